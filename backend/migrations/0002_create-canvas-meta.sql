@@ -0,0 +1,6 @@
+CREATE TABLE canvas_meta (
+  id integer PRIMARY KEY DEFAULT 1 CHECK (id = 1),
+  width integer NOT NULL,
+  height integer NOT NULL,
+  background integer NOT NULL
+);
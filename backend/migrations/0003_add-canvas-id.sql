@@ -0,0 +1,4 @@
+ALTER TABLE canvas
+  ADD COLUMN canvas_id text NOT NULL DEFAULT 'default',
+  DROP CONSTRAINT canvas_pkey,
+  ADD PRIMARY KEY (canvas_id, x, y);
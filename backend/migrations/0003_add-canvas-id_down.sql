@@ -0,0 +1,5 @@
+DELETE FROM canvas WHERE canvas_id <> 'default';
+ALTER TABLE canvas
+  DROP CONSTRAINT canvas_pkey,
+  DROP COLUMN canvas_id,
+  ADD PRIMARY KEY (x, y);
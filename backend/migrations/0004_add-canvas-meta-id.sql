@@ -0,0 +1,3 @@
+ALTER TABLE canvas_meta
+  DROP COLUMN id,
+  ADD COLUMN canvas_id text NOT NULL DEFAULT 'default' PRIMARY KEY;
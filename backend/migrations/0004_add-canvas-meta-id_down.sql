@@ -0,0 +1,4 @@
+DELETE FROM canvas_meta WHERE canvas_id <> 'default';
+ALTER TABLE canvas_meta
+  DROP COLUMN canvas_id,
+  ADD COLUMN id integer PRIMARY KEY DEFAULT 1 CHECK (id = 1);
@@ -0,0 +1,6 @@
+CREATE TABLE canvas_palette (
+  canvas_id text NOT NULL,
+  idx smallint NOT NULL,
+  colour integer NOT NULL,
+  PRIMARY KEY(canvas_id, idx)
+);
@@ -0,0 +1 @@
+ALTER TABLE canvas_meta ADD COLUMN indexed boolean NOT NULL DEFAULT false;
@@ -0,0 +1 @@
+ALTER TABLE canvas_meta DROP COLUMN indexed;
@@ -0,0 +1,3 @@
+ALTER TABLE canvas
+  ADD COLUMN palette_index smallint,
+  ALTER COLUMN colour DROP NOT NULL;
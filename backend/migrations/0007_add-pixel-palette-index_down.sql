@@ -0,0 +1,6 @@
+UPDATE canvas c SET colour = p.colour FROM canvas_palette p
+  WHERE c.colour IS NULL AND p.canvas_id = c.canvas_id AND p.idx = c.palette_index;
+DELETE FROM canvas WHERE colour IS NULL;
+ALTER TABLE canvas
+  DROP COLUMN palette_index,
+  ALTER COLUMN colour SET NOT NULL;
@@ -0,0 +1,8 @@
+CREATE TABLE canvas_history (
+  id bigserial PRIMARY KEY,
+  canvas_id text NOT NULL,
+  x integer NOT NULL,
+  y integer NOT NULL,
+  colour integer NOT NULL,
+  updated integer NOT NULL
+);
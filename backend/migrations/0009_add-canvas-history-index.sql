@@ -0,0 +1 @@
+CREATE INDEX canvas_history_updated ON canvas_history (canvas_id, updated);
@@ -0,0 +1 @@
+DROP INDEX canvas_history_updated;
@@ -0,0 +1,6 @@
+CREATE TABLE canvas_snapshot (
+  id bigserial PRIMARY KEY,
+  canvas_id text NOT NULL,
+  taken integer NOT NULL,
+  history_id bigint NOT NULL
+);
@@ -0,0 +1,8 @@
+CREATE TABLE canvas_snapshot_pixel (
+  snapshot_id bigint NOT NULL REFERENCES canvas_snapshot (id) ON DELETE CASCADE,
+  x integer NOT NULL,
+  y integer NOT NULL,
+  colour integer NOT NULL,
+  updated integer NOT NULL,
+  PRIMARY KEY(snapshot_id, x, y)
+);
@@ -0,0 +1,5 @@
+CREATE TABLE users (
+  name text PRIMARY KEY,
+  password_hash text NOT NULL,
+  created integer NOT NULL
+);
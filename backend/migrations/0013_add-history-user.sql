@@ -0,0 +1 @@
+ALTER TABLE canvas_history ADD COLUMN user_name text;
@@ -0,0 +1 @@
+ALTER TABLE canvas_history DROP COLUMN user_name;
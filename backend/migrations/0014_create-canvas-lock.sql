@@ -0,0 +1,10 @@
+CREATE TABLE canvas_lock (
+  id bigint PRIMARY KEY,
+  canvas_id text NOT NULL,
+  x integer NOT NULL,
+  y integer NOT NULL,
+  width integer NOT NULL,
+  height integer NOT NULL,
+  reason text,
+  created integer NOT NULL
+);
@@ -0,0 +1,5 @@
+CREATE TABLE ban (
+  identity text PRIMARY KEY,
+  reason text,
+  created integer NOT NULL
+);
@@ -0,0 +1,10 @@
+CREATE TABLE audit_log (
+  id bigserial PRIMARY KEY,
+  at integer NOT NULL,
+  actor text NOT NULL,
+  address text NOT NULL,
+  method text NOT NULL,
+  path text NOT NULL,
+  status smallint NOT NULL
+);
+CREATE INDEX audit_log_at ON audit_log (at);
@@ -0,0 +1,484 @@
+//! Admin routes for moving canvas contents in and out of the cluster and for moderation,
+//! served under the `/admin` scope. Every request has to be made with the admin token or the
+//! token of an admin account, see `auth`, and every action is recorded, see `audit`.
+
+use crate::audit::{AuditEntry, AuditQuery};
+use crate::auth::Auth;
+use crate::canvas::{CanvasRegistry, Region};
+use crate::cooldown;
+use crate::dump::{self, Format};
+use crate::moderation::{self, Action, Ban, Lock, RollbackFilter};
+use crate::pixel::{self, Pixel};
+use crate::replica_manager::WriteOutcome;
+use crate::snapshot;
+use crate::store::CanvasStore;
+use crate::store::Store;
+use crate::{auth_error_response, not_primary, unknown_canvas, ReplicaHandle};
+use actix_web::body::{BoxBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::Method;
+use actix_web::middleware::Next;
+use actix_web::{delete, get, post, web, Error, HttpResponse};
+use futures::future::join_all;
+use rand::Rng as _;
+use serde_json::json;
+
+/// Largest file accepted by an import
+pub const MAX_IMPORT_SIZE: usize = 64 * 1024 * 1024;
+
+/// Refuse requests without admin access, and record every request that isn't a `GET` in the
+/// audit log once it's answered
+pub async fn authorize(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, Error> {
+    // unwrap: both are registered on the app
+    let auth = req.app_data::<web::Data<Auth>>().unwrap().clone();
+    let store = req
+        .app_data::<web::Data<dyn CanvasStore>>()
+        .unwrap()
+        .clone();
+    let method = req.method().clone();
+    let path = req.path().to_string();
+    let address = cooldown::client_address(req.request());
+
+    let (actor, res) = match auth.admin(req.request()) {
+        Ok(actor) => (
+            actor,
+            next.call(req).await.map(|res| res.map_into_boxed_body()),
+        ),
+        Err(err) => {
+            log::info!("Refused admin request {} {}: {}", method, path, err);
+            let actor = auth.author(req.request()).map_or_else(
+                |_| cooldown::identity(None, &address),
+                |author| author.identity,
+            );
+            (actor, Ok(req.into_response(auth_error_response(err))))
+        }
+    };
+
+    if method != Method::GET {
+        let status = match &res {
+            Ok(res) => res.status(),
+            Err(err) => err.as_response_error().status_code(),
+        };
+        let entry = AuditEntry {
+            id: 0,
+            at: pixel::now(),
+            actor,
+            address,
+            method: method.to_string(),
+            path,
+            status: status.as_u16() as i16,
+        };
+        if let Err(e) = store.record_audit(&entry).await {
+            log::error!("Couldn't record admin action {:?}: {}", entry, e);
+        }
+    }
+    res
+}
+
+/// The audit log, newest first. Query parameters: `actor`, `since`, `until` (unix seconds)
+/// and `limit` (default 100)
+#[get("/audit")]
+pub async fn audit_log(
+    store: web::Data<dyn CanvasStore>,
+    query: web::Query<AuditQuery>,
+) -> HttpResponse {
+    match store.audit_log(&query).await {
+        Ok(entries) => HttpResponse::Ok().json(entries),
+        Err(err) => {
+            log::debug!("unable to fetch audit log: {:?}", err);
+            HttpResponse::InternalServerError().json("unable to fetch audit log")
+        }
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct ExportQuery {
+    #[serde(default)]
+    format: Format,
+    x: Option<i32>,
+    y: Option<i32>,
+    width: Option<i32>,
+    height: Option<i32>,
+    /// Export every write from the history instead of the current pixels
+    #[serde(default)]
+    history: bool,
+    since: Option<i32>,
+    until: Option<i32>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct ImportQuery {
+    #[serde(default)]
+    format: Format,
+}
+
+/// Export the pixels of a canvas, optionally limited to a region and to writes between
+/// `since` and `until`
+#[get("/canvases/{id}/export")]
+pub async fn export_canvas(
+    store: web::Data<dyn CanvasStore>,
+    canvases: web::Data<CanvasRegistry>,
+    path: web::Path<String>,
+    query: web::Query<ExportQuery>,
+) -> HttpResponse {
+    let meta = match canvases.get(&path) {
+        Some(meta) => meta,
+        None => return unknown_canvas(&path),
+    };
+    let query = query.into_inner();
+
+    let pixels = if query.history {
+        store.history(&meta.id, query.since, query.until).await
+    } else {
+        store.canvas_pixels(&meta.id).await.map(|mut pixels| {
+            pixels.retain(|pixel| pixel.updated_between(query.since, query.until));
+            pixels.sort_by_key(|pixel| (pixel.y, pixel.x));
+            pixels
+        })
+    };
+    let mut pixels: Vec<Pixel> = match pixels {
+        Ok(pixels) => pixels,
+        Err(err) => {
+            log::debug!("unable to fetch pixels: {:?}", err);
+            return HttpResponse::InternalServerError().json("unable to fetch pixels");
+        }
+    };
+
+    if query.x.is_some() || query.y.is_some() || query.width.is_some() || query.height.is_some() {
+        let x = query.x.unwrap_or(0);
+        let y = query.y.unwrap_or(0);
+        let region = Region {
+            x,
+            y,
+            width: query.width.unwrap_or(meta.width.saturating_sub(x)),
+            height: query.height.unwrap_or(meta.height.saturating_sub(y)),
+        };
+        if !region.inside(&meta) {
+            return HttpResponse::BadRequest().json(json!({
+                "command": "error",
+                "payload": {
+                    "code": "invalid_region",
+                    "message": "region must be inside the canvas",
+                },
+            }));
+        }
+        pixels.retain(|pixel| region.contains(pixel));
+    }
+
+    HttpResponse::Ok()
+        .content_type(query.format.content_type())
+        .body(dump::write_pixels(query.format, &pixels))
+}
+
+/// Import pixels into a canvas. Nothing is written unless every row is valid, then each pixel
+/// is written through the replica manager like any other write. Registered with a payload
+/// limit of `MAX_IMPORT_SIZE`, see `main`
+pub async fn import_canvas(
+    replica_handle: web::Data<ReplicaHandle>,
+    canvases: web::Data<CanvasRegistry>,
+    path: web::Path<String>,
+    query: web::Query<ImportQuery>,
+    body: String,
+) -> HttpResponse {
+    let meta = match canvases.get(&path) {
+        Some(meta) => meta,
+        None => return unknown_canvas(&path),
+    };
+
+    let pixels = match dump::read_pixels(query.format, &body, &meta, pixel::now()) {
+        Ok(pixels) => pixels,
+        Err(errors) => {
+            log::debug!("rejected import of {} bad rows", errors.len());
+            return HttpResponse::BadRequest().json(json!({
+                "command": "error",
+                "payload": {
+                    "code": "invalid_import",
+                    "rows": errors,
+                },
+            }));
+        }
+    };
+
+    let mut imported = 0;
+    let mut rejected = 0;
+    for pixel in pixels.iter() {
+        // The palette could have changed since the rows were checked
+        match replica_handle
+            .send_message(
+                meta.id.clone(),
+                serde_json::to_string(pixel).unwrap(),
+                None,
+                None,
+            )
+            .await
+        {
+            Ok(()) => imported += 1,
+            Err(err) => {
+                log::debug!("rejected imported pixel: {}", err);
+                rejected += 1;
+            }
+        }
+    }
+    log::info!("Imported {} pixels into canvas {}", imported, meta.id);
+
+    HttpResponse::Accepted().json(json!({
+        "canvas": meta.id,
+        "imported": imported,
+        "rejected": rejected,
+    }))
+}
+
+/// Checkpoint a canvas now instead of waiting for the next scheduled snapshot
+#[post("/canvases/{id}/snapshots")]
+pub async fn take_snapshot(
+    store: web::Data<dyn CanvasStore>,
+    canvases: web::Data<CanvasRegistry>,
+    path: web::Path<String>,
+) -> HttpResponse {
+    if canvases.get(&path).is_none() {
+        return unknown_canvas(&path);
+    }
+    let store: Store = store.into_inner();
+    match snapshot::snapshot_canvas(&store, &path).await {
+        Some(info) => HttpResponse::Created().json(info),
+        None => HttpResponse::InternalServerError().json("unable to take snapshot"),
+    }
+}
+
+fn moderation_error(code: &str, message: &str) -> HttpResponse {
+    HttpResponse::BadRequest().json(json!({
+        "command": "error",
+        "payload": { "code": code, "message": message },
+    }))
+}
+
+fn moderation_response(
+    stored: Result<(), crate::store::StoreError>,
+    created: impl serde::Serialize,
+) -> HttpResponse {
+    match stored {
+        Ok(()) => HttpResponse::Created().json(created),
+        Err(err) => {
+            log::error!("unable to store moderation action: {}", err);
+            HttpResponse::InternalServerError().json("unable to store moderation action")
+        }
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct LockRequest {
+    #[serde(flatten)]
+    region: Region,
+    reason: Option<String>,
+}
+
+/// Lock a region of a canvas against writes from clients on every replica
+#[post("/canvases/{id}/locks")]
+pub async fn lock_region(
+    replica_handle: web::Data<ReplicaHandle>,
+    canvases: web::Data<CanvasRegistry>,
+    path: web::Path<String>,
+    body: web::Json<LockRequest>,
+) -> HttpResponse {
+    let meta = match canvases.get(&path) {
+        Some(meta) => meta,
+        None => return unknown_canvas(&path),
+    };
+    let LockRequest { region, reason } = body.into_inner();
+    if region.width <= 0 || region.height <= 0 {
+        return moderation_error("invalid_region", "width and height must be positive");
+    }
+    if !region.inside(&meta) {
+        return moderation_error("invalid_region", "region must be inside the canvas");
+    }
+
+    let lock = Lock {
+        // Random so any replica can make one, and small enough to survive a JavaScript number
+        id: rand::thread_rng().gen_range(1..1 << 53),
+        canvas_id: meta.id,
+        region,
+        reason,
+        created: pixel::now(),
+    };
+    let stored = replica_handle
+        .moderate(Action::Lock { lock: lock.clone() })
+        .await;
+    moderation_response(stored, lock)
+}
+
+/// WebSocket sessions of this replica and how many messages are waiting to be sent to each
+#[get("/sessions")]
+pub async fn list_sessions(replica_handle: web::Data<ReplicaHandle>) -> HttpResponse {
+    HttpResponse::Ok().json(replica_handle.sessions().await)
+}
+
+#[get("/locks")]
+pub async fn list_locks(store: web::Data<dyn CanvasStore>) -> HttpResponse {
+    match store.locks().await {
+        Ok(locks) => HttpResponse::Ok().json(locks),
+        Err(err) => {
+            log::debug!("unable to fetch locks: {:?}", err);
+            HttpResponse::InternalServerError().json("unable to fetch locks")
+        }
+    }
+}
+
+#[delete("/locks/{lock}")]
+pub async fn unlock_region(
+    replica_handle: web::Data<ReplicaHandle>,
+    store: web::Data<dyn CanvasStore>,
+    path: web::Path<i64>,
+) -> HttpResponse {
+    let id = path.into_inner();
+    match store.locks().await {
+        Ok(locks) if locks.iter().any(|lock| lock.id == id) => {}
+        Ok(_) => return HttpResponse::NotFound().json("no such lock"),
+        Err(err) => {
+            log::debug!("unable to fetch locks: {:?}", err);
+            return HttpResponse::InternalServerError().json("unable to fetch locks");
+        }
+    }
+    match replica_handle.moderate(Action::Unlock { id }).await {
+        Ok(()) => HttpResponse::NoContent().finish(),
+        Err(err) => {
+            log::error!("unable to remove lock {}: {}", id, err);
+            HttpResponse::InternalServerError().json("unable to remove lock")
+        }
+    }
+}
+
+/// Exactly one of `user` and `ip` is given
+#[derive(Debug, serde::Deserialize)]
+pub struct BanRequest {
+    user: Option<String>,
+    ip: Option<String>,
+    reason: Option<String>,
+}
+
+/// Ban a user or an IP address from writing on every replica
+#[post("/bans")]
+pub async fn add_ban(
+    replica_handle: web::Data<ReplicaHandle>,
+    body: web::Json<BanRequest>,
+) -> HttpResponse {
+    let BanRequest { user, ip, reason } = body.into_inner();
+    let identity = match (user, ip) {
+        (Some(user), None) if !user.is_empty() => cooldown::identity(Some(&user), ""),
+        (None, Some(ip)) if !ip.is_empty() => cooldown::identity(None, &ip),
+        _ => return moderation_error("invalid_ban", "give either a user or an ip"),
+    };
+    let ban = Ban {
+        identity,
+        reason,
+        created: pixel::now(),
+    };
+    let stored = replica_handle
+        .moderate(Action::Ban { ban: ban.clone() })
+        .await;
+    moderation_response(stored, ban)
+}
+
+#[get("/bans")]
+pub async fn list_bans(store: web::Data<dyn CanvasStore>) -> HttpResponse {
+    match store.bans().await {
+        Ok(bans) => HttpResponse::Ok().json(bans),
+        Err(err) => {
+            log::debug!("unable to fetch bans: {:?}", err);
+            HttpResponse::InternalServerError().json("unable to fetch bans")
+        }
+    }
+}
+
+/// Lift a ban, `identity` is `user:<name>` or `ip:<address>` as listed by `/admin/bans`
+#[delete("/bans/{identity}")]
+pub async fn unban(
+    replica_handle: web::Data<ReplicaHandle>,
+    store: web::Data<dyn CanvasStore>,
+    path: web::Path<String>,
+) -> HttpResponse {
+    let identity = path.into_inner();
+    match store.bans().await {
+        Ok(bans) if bans.iter().any(|ban| ban.identity == identity) => {}
+        Ok(_) => return HttpResponse::NotFound().json("no such ban"),
+        Err(err) => {
+            log::debug!("unable to fetch bans: {:?}", err);
+            return HttpResponse::InternalServerError().json("unable to fetch bans");
+        }
+    }
+    match replica_handle.moderate(Action::Unban { identity }).await {
+        Ok(()) => HttpResponse::NoContent().finish(),
+        Err(err) => {
+            log::error!("unable to lift ban: {}", err);
+            HttpResponse::InternalServerError().json("unable to lift ban")
+        }
+    }
+}
+
+/// Undo the writes to a canvas made by `user`, between `since` and `until`, or both. The
+/// colours from before are written through the ring, so this has to be sent to the primary
+#[post("/canvases/{id}/rollback")]
+pub async fn rollback(
+    replica_handle: web::Data<ReplicaHandle>,
+    store: web::Data<dyn CanvasStore>,
+    canvases: web::Data<CanvasRegistry>,
+    path: web::Path<String>,
+    body: web::Json<RollbackFilter>,
+) -> HttpResponse {
+    let meta = match canvases.get(&path) {
+        Some(meta) => meta,
+        None => return unknown_canvas(&path),
+    };
+    let filter = body.into_inner();
+    if filter.is_empty() {
+        return moderation_error("invalid_rollback", "give a user, since or until");
+    }
+
+    let history = store.history(&meta.id, None, None).await;
+    let current = store.canvas_pixels(&meta.id).await;
+    let (history, current) = match (history, current) {
+        (Ok(history), Ok(current)) => (history, current),
+        (Err(err), _) | (_, Err(err)) => {
+            log::debug!("unable to fetch pixels: {:?}", err);
+            return HttpResponse::InternalServerError().json("unable to fetch pixels");
+        }
+    };
+    let restored = moderation::rollback(&meta, &history, &current, &filter, pixel::now());
+
+    let outcomes = join_all(restored.iter().map(|pixel| {
+        replica_handle.write(meta.id.clone(), serde_json::to_string(pixel).unwrap(), None)
+    }))
+    .await;
+
+    let mut replicated = 0;
+    let mut unreplicated = 0;
+    let mut rejected = 0;
+    for outcome in outcomes {
+        match outcome {
+            Ok(WriteOutcome::Replicated) => replicated += 1,
+            Ok(WriteOutcome::Unreplicated) => unreplicated += 1,
+            Ok(WriteOutcome::NotPrimary { primary, address }) => {
+                return not_primary(primary, address)
+            }
+            // The palette could have changed since the colour was written
+            Err(err) => {
+                log::debug!("rejected restored pixel: {}", err);
+                rejected += 1;
+            }
+        }
+    }
+    log::info!(
+        "Rolled back {} pixels of canvas {}",
+        replicated + unreplicated,
+        meta.id
+    );
+
+    HttpResponse::Ok().json(json!({
+        "canvas": meta.id,
+        "replicated": replicated,
+        "unreplicated": unreplicated,
+        "rejected": rejected,
+    }))
+}
@@ -0,0 +1,109 @@
+//! Record of the admin actions taken on a replica.
+//!
+//! Every request to `/admin` that isn't a `GET` is recorded once it's answered, with who made
+//! it and the status it got, including those refused for a missing or bad token. Each replica
+//! keeps the record of the requests it answered, so look on the replica an action was sent to.
+
+use deadpool_postgres::GenericClient;
+use tokio_postgres::{Error, Row};
+
+/// Entries returned when a query has no `limit`
+pub const DEFAULT_LIMIT: i64 = 100;
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct AuditEntry {
+    pub id: i64,
+    /// Unix seconds
+    pub at: i32,
+    /// `token` for the admin token or `user:<name>` for an admin account. Refused requests are
+    /// recorded with the identity of the client, as for cooldowns
+    pub actor: String,
+    pub address: String,
+    pub method: String,
+    pub path: String,
+    pub status: i16,
+}
+
+impl From<Row> for AuditEntry {
+    fn from(row: Row) -> Self {
+        Self {
+            id: row.get(0),
+            at: row.get(1),
+            actor: row.get(2),
+            address: row.get(3),
+            method: row.get(4),
+            path: row.get(5),
+            status: row.get(6),
+        }
+    }
+}
+
+/// Which entries to return, newest first
+#[derive(Debug, Clone, Default, serde::Deserialize)]
+pub struct AuditQuery {
+    pub actor: Option<String>,
+    pub since: Option<i32>,
+    pub until: Option<i32>,
+    pub limit: Option<i64>,
+}
+
+impl AuditQuery {
+    pub fn limit(&self) -> i64 {
+        self.limit.unwrap_or(DEFAULT_LIMIT).max(0)
+    }
+
+    pub fn matches(&self, entry: &AuditEntry) -> bool {
+        self.actor
+            .as_ref()
+            .is_none_or(|actor| &entry.actor == actor)
+            && self.since.is_none_or(|since| entry.at >= since)
+            && self.until.is_none_or(|until| entry.at <= until)
+    }
+}
+
+impl AuditEntry {
+    /// Store an entry, its `id` is ignored and assigned by the database
+    pub async fn record<C: GenericClient>(client: &C, entry: &AuditEntry) -> Result<u64, Error> {
+        let stmt = client
+            .prepare_cached(
+                "INSERT INTO audit_log (at, actor, address, method, path, status)
+            VALUES ($1, $2, $3, $4, $5, $6)",
+            )
+            .await?;
+        client
+            .execute(
+                &stmt,
+                &[
+                    &entry.at,
+                    &entry.actor,
+                    &entry.address,
+                    &entry.method,
+                    &entry.path,
+                    &entry.status,
+                ],
+            )
+            .await
+    }
+
+    pub async fn query<C: GenericClient>(
+        client: &C,
+        query: &AuditQuery,
+    ) -> Result<Vec<AuditEntry>, Error> {
+        let stmt = client
+            .prepare_cached(
+                "SELECT id, at, actor, address, method, path, status FROM audit_log
+            WHERE ($1::text IS NULL OR actor = $1)
+            AND ($2::integer IS NULL OR at >= $2)
+            AND ($3::integer IS NULL OR at <= $3)
+            ORDER BY id DESC LIMIT $4",
+            )
+            .await?;
+        let rows = client
+            .query(
+                &stmt,
+                &[&query.actor, &query.since, &query.until, &query.limit()],
+            )
+            .await?;
+        Ok(rows.into_iter().map(AuditEntry::from).collect())
+    }
+}
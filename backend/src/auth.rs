@@ -0,0 +1,408 @@
+//! User accounts and the tokens that prove who made a write.
+//!
+//! Accounts are created on the primary and passed around the ring like canvases, so a user can
+//! log in on any replica. Tokens are `name.expires.signature`, signed with HMAC-SHA256 and
+//! `AUTH_SECRET`, so every replica can check them without asking the others. Replicas have to
+//! share the secret for tokens from one to be accepted by another.
+//!
+//! | Variable | |
+//! | --- | --- |
+//! | `AUTH_SECRET` | Key tokens are signed with, random when unset so tokens only work on the replica that issued them |
+//! | `AUTH_TOKEN_TTL` | Seconds a token is valid for, default 7 days |
+//! | `AUTH_REQUIRED` | `true` rejects writes without a token, by default they're made anonymously |
+//! | `ADMIN_TOKEN` | Token that may use the `/admin` routes |
+//! | `ADMIN_USERS` | Comma separated accounts whose tokens may use the `/admin` routes |
+//!
+//! The `/admin` routes are refused to everyone when neither `ADMIN_TOKEN` nor `ADMIN_USERS` is
+//! set.
+
+use crate::cooldown;
+use actix_web::HttpRequest;
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use deadpool_postgres::GenericClient;
+use hmac::{Hmac, KeyInit, Mac};
+use sha2::Sha256;
+use std::fmt;
+use tokio_postgres::{Error, Row};
+
+const DEFAULT_TOKEN_TTL: i64 = 7 * 24 * 60 * 60;
+const MIN_PASSWORD_LENGTH: usize = 8;
+const MAX_NAME_LENGTH: usize = 32;
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct User {
+    pub name: String,
+    /// Argon2 hash in PHC format
+    pub password_hash: String,
+    pub created: i32,
+}
+
+impl From<Row> for User {
+    fn from(row: Row) -> Self {
+        Self {
+            name: row.get(0),
+            password_hash: row.get(1),
+            created: row.get(2),
+        }
+    }
+}
+
+impl User {
+    pub async fn all<C: GenericClient>(client: &C) -> Result<Vec<User>, Error> {
+        let rows = client
+            .query("SELECT name, password_hash, created FROM users", &[])
+            .await?;
+        Ok(rows.into_iter().map(User::from).collect())
+    }
+
+    pub async fn get<C: GenericClient>(client: &C, name: &str) -> Result<Option<User>, Error> {
+        let stmt = client
+            .prepare_cached("SELECT name, password_hash, created FROM users WHERE name = $1")
+            .await?;
+        Ok(client.query_opt(&stmt, &[&name]).await?.map(User::from))
+    }
+
+    /// Store a user unless one with the same name exists. Returns whether it was stored
+    pub async fn add<C: GenericClient>(client: &C, user: &User) -> Result<bool, Error> {
+        let stmt = client
+            .prepare_cached(
+                "INSERT INTO users (name, password_hash, created) VALUES ($1, $2, $3)
+            ON CONFLICT (name) DO NOTHING",
+            )
+            .await?;
+        let added = client
+            .execute(&stmt, &[&user.name, &user.password_hash, &user.created])
+            .await?;
+        Ok(added == 1)
+    }
+}
+
+/// Why a registration, login or token was refused. Serialized as the payload of an `error`
+/// message
+#[derive(Debug, Clone, serde::Serialize)]
+#[serde(tag = "code", rename_all = "snake_case")]
+pub enum AuthError {
+    InvalidName {
+        message: String,
+    },
+    WeakPassword {
+        message: String,
+    },
+    NameTaken {
+        name: String,
+    },
+    BadCredentials,
+    /// `AUTH_REQUIRED` is set and no token was sent
+    Unauthenticated,
+    InvalidToken,
+    TokenExpired,
+    /// The token is valid but doesn't grant access to the `/admin` routes
+    Forbidden,
+    /// Accounts are only created on the primary
+    NotPrimary {
+        primary: u16,
+        address: Option<String>,
+    },
+    /// The store couldn't be reached
+    Unavailable,
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthError::InvalidName { message } => write!(f, "invalid name: {}", message),
+            AuthError::WeakPassword { message } => write!(f, "weak password: {}", message),
+            AuthError::NameTaken { name } => write!(f, "{} is already taken", name),
+            AuthError::BadCredentials => write!(f, "wrong name or password"),
+            AuthError::Unauthenticated => write!(f, "a token is required"),
+            AuthError::InvalidToken => write!(f, "invalid token"),
+            AuthError::TokenExpired => write!(f, "token expired"),
+            AuthError::Forbidden => write!(f, "admin access is required"),
+            AuthError::NotPrimary { primary, .. } => {
+                write!(f, "accounts are created on the primary, {}", primary)
+            }
+            AuthError::Unavailable => write!(f, "accounts are unavailable"),
+        }
+    }
+}
+
+impl std::error::Error for AuthError {}
+
+/// A name and password sent to `/register` or `/login`
+#[derive(Debug, serde::Deserialize)]
+pub struct Credentials {
+    pub name: String,
+    pub password: String,
+}
+
+impl Credentials {
+    pub fn check(&self) -> Result<(), AuthError> {
+        if self.name.is_empty() || self.name.len() > MAX_NAME_LENGTH {
+            return Err(AuthError::InvalidName {
+                message: format!("must be 1 to {} characters", MAX_NAME_LENGTH),
+            });
+        }
+        let allowed = |c: char| c.is_ascii_alphanumeric() || c == '_' || c == '-';
+        if !self.name.chars().all(allowed) {
+            return Err(AuthError::InvalidName {
+                message: "may only contain letters, digits, _ and -".to_string(),
+            });
+        }
+        if self.password.chars().count() < MIN_PASSWORD_LENGTH {
+            return Err(AuthError::WeakPassword {
+                message: format!("must be at least {} characters", MIN_PASSWORD_LENGTH),
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Argon2 hash of a password. Slow on purpose, run it off the async runtime
+pub fn hash_password(password: &str) -> String {
+    let salt = SaltString::generate(&mut OsRng);
+    // Hashing only fails for parameters out of range, the defaults aren't
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .unwrap()
+        .to_string()
+}
+
+pub fn verify_password(password_hash: &str, password: &str) -> bool {
+    PasswordHash::new(password_hash).is_ok_and(|hash| {
+        Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok()
+    })
+}
+
+/// A token given out by `/login`
+#[derive(Debug, serde::Serialize)]
+pub struct Token {
+    pub token: String,
+    pub name: String,
+    /// Unix seconds
+    pub expires: i64,
+}
+
+/// Who made a write: the user it's attributed to, if any, the address it came from and the
+/// identity its cooldown is kept under
+#[derive(Debug, Clone)]
+pub struct Author {
+    pub user: Option<String>,
+    pub address: String,
+    pub identity: String,
+}
+
+/// Issues and checks tokens
+#[derive(Debug, Clone)]
+pub struct Auth {
+    secret: Vec<u8>,
+    ttl: i64,
+    required: bool,
+    admin_token: Option<String>,
+    admin_users: Vec<String>,
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn unhex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+/// The token a request was made with, from `Authorization: Bearer <token>` or the `token`
+/// query parameter, since browsers can't set headers on WebSockets
+pub fn request_token(req: &HttpRequest) -> Option<String> {
+    let bearer = req
+        .headers()
+        .get("Authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    if let Some(token) = bearer.filter(|token| !token.is_empty()) {
+        return Some(token.to_string());
+    }
+    req.query_string()
+        .split('&')
+        .find_map(|pair| pair.strip_prefix("token="))
+        .filter(|token| !token.is_empty())
+        .map(str::to_string)
+}
+
+impl Auth {
+    pub fn from_env() -> Self {
+        let secret = match std::env::var("AUTH_SECRET") {
+            Ok(secret) if !secret.is_empty() => secret.into_bytes(),
+            _ => {
+                log::warn!("AUTH_SECRET isn't set, tokens will only be accepted by this replica");
+                let mut secret = vec![0; 32];
+                OsRng.fill_bytes(&mut secret);
+                secret
+            }
+        };
+        Self {
+            secret,
+            ttl: std::env::var("AUTH_TOKEN_TTL")
+                .ok()
+                .and_then(|ttl| ttl.parse().ok())
+                .unwrap_or(DEFAULT_TOKEN_TTL),
+            required: std::env::var("AUTH_REQUIRED").is_ok_and(|required| required == "true"),
+            admin_token: std::env::var("ADMIN_TOKEN")
+                .ok()
+                .filter(|token| !token.is_empty()),
+            admin_users: std::env::var("ADMIN_USERS")
+                .unwrap_or_default()
+                .split(',')
+                .map(str::trim)
+                .filter(|name| !name.is_empty())
+                .map(str::to_string)
+                .collect(),
+        }
+    }
+
+    /// Whether anyone can use the `/admin` routes
+    pub fn admin_enabled(&self) -> bool {
+        self.admin_token.is_some() || !self.admin_users.is_empty()
+    }
+
+    fn sign(&self, payload: &str) -> Hmac<Sha256> {
+        // HMAC takes keys of any length
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.secret).unwrap();
+        mac.update(payload.as_bytes());
+        mac
+    }
+
+    pub fn issue(&self, name: &str) -> Token {
+        let expires = crate::pixel::now() as i64 + self.ttl;
+        let payload = format!("{}.{}", name, expires);
+        let signature = hex(&self.sign(&payload).finalize().into_bytes());
+        Token {
+            token: format!("{}.{}", payload, signature),
+            name: name.to_string(),
+            expires,
+        }
+    }
+
+    /// Name of the user a token was issued to
+    pub fn verify(&self, token: &str) -> Result<String, AuthError> {
+        let (payload, signature) = token.rsplit_once('.').ok_or(AuthError::InvalidToken)?;
+        let signature = unhex(signature).ok_or(AuthError::InvalidToken)?;
+        self.sign(payload)
+            .verify_slice(&signature)
+            .map_err(|_| AuthError::InvalidToken)?;
+
+        let (name, expires) = payload.rsplit_once('.').ok_or(AuthError::InvalidToken)?;
+        let expires: i64 = expires.parse().map_err(|_| AuthError::InvalidToken)?;
+        if expires < crate::pixel::now() as i64 {
+            return Err(AuthError::TokenExpired);
+        }
+        Ok(name.to_string())
+    }
+
+    /// Who is making a request to the `/admin` routes, `token` for the admin token and
+    /// `user:<name>` for an admin account. Fails unless the request has either
+    pub fn admin(&self, req: &HttpRequest) -> Result<String, AuthError> {
+        let token = request_token(req).ok_or(AuthError::Unauthenticated)?;
+        if let Some(admin_token) = &self.admin_token {
+            // Compared as signatures so the comparison takes the same time wherever they differ
+            let expected = self.sign(admin_token).finalize().into_bytes();
+            if self.sign(&token).verify_slice(&expected).is_ok() {
+                return Ok("token".to_string());
+            }
+        }
+        let name = self.verify(&token)?;
+        if self.admin_users.contains(&name) {
+            Ok(cooldown::identity(Some(&name), ""))
+        } else {
+            Err(AuthError::Forbidden)
+        }
+    }
+
+    /// Who is making a request. Fails if its token is invalid, or it has none and one is
+    /// required
+    pub fn author(&self, req: &HttpRequest) -> Result<Author, AuthError> {
+        let user = match request_token(req) {
+            Some(token) => Some(self.verify(&token)?),
+            None if self.required => return Err(AuthError::Unauthenticated),
+            None => None,
+        };
+        let address = cooldown::client_address(req);
+        let identity = cooldown::identity(user.as_deref(), &address);
+        Ok(Author {
+            user,
+            address,
+            identity,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn auth(ttl: i64) -> Auth {
+        Auth {
+            secret: b"secret".to_vec(),
+            ttl,
+            required: false,
+            admin_token: None,
+            admin_users: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn verifies_issued_tokens() {
+        let auth = auth(DEFAULT_TOKEN_TTL);
+        let token = auth.issue("alice");
+        assert_eq!(auth.verify(&token.token).unwrap(), "alice");
+    }
+
+    #[test]
+    fn tampered_tokens() {
+        let auth = auth(DEFAULT_TOKEN_TTL);
+        let token = auth.issue("alice").token;
+
+        // Someone else's name on alice's signature
+        let forged = token.replacen("alice", "mallory", 1);
+        assert!(matches!(auth.verify(&forged), Err(AuthError::InvalidToken)));
+
+        // A later expiry on the same signature
+        let (payload, signature) = token.rsplit_once('.').unwrap();
+        let (name, expires) = payload.rsplit_once('.').unwrap();
+        let expires: i64 = expires.parse().unwrap();
+        let extended = format!("{}.{}.{}", name, expires + 1, signature);
+        assert!(matches!(
+            auth.verify(&extended),
+            Err(AuthError::InvalidToken)
+        ));
+
+        // Signed with another secret
+        let other = Auth {
+            secret: b"other".to_vec(),
+            ..auth.clone()
+        };
+        assert!(matches!(other.verify(&token), Err(AuthError::InvalidToken)));
+
+        for garbage in ["", "alice", "alice.123", "alice.123.zz", "alice.123.abc"] {
+            assert!(matches!(auth.verify(garbage), Err(AuthError::InvalidToken)));
+        }
+    }
+
+    #[test]
+    fn expired_tokens() {
+        let auth = auth(-10);
+        let token = auth.issue("alice");
+        assert!(matches!(
+            auth.verify(&token.token),
+            Err(AuthError::TokenExpired)
+        ));
+    }
+}
@@ -0,0 +1,162 @@
+//! Coalescing of the pixel updates sent to one WebSocket connection.
+//!
+//! A connection opened with `?batch=json` or `?batch=binary` gets the writes of other
+//! connections in batches instead of a frame each. A write to a pixel already waiting in the
+//! batch replaces it, and the batch is sent `WS_BATCH_WINDOW_MS` after its first write or once
+//! it holds `WS_BATCH_MAX` pixels, whichever comes first. Anything else sent to the connection
+//! sends the batch ahead of it, so frames keep their order.
+
+use crate::pixel::Pixel;
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::time::Instant;
+
+const DEFAULT_WINDOW: Duration = Duration::from_millis(50);
+const DEFAULT_MAX: usize = 256;
+
+/// How batches are sent
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BatchFormat {
+    /// A text frame, `pixel_batch` in the connection's protocol version
+    Json,
+    /// A binary frame of fixed size records, see `protocol::encode_batch`
+    Binary,
+}
+
+/// `WS_BATCH_WINDOW_MS`, how long a batch waits for more writes
+fn batch_window() -> Duration {
+    std::env::var("WS_BATCH_WINDOW_MS")
+        .ok()
+        .and_then(|window| window.parse().ok())
+        .map(Duration::from_millis)
+        .unwrap_or(DEFAULT_WINDOW)
+}
+
+/// `WS_BATCH_MAX`, most pixels in one batch
+fn batch_max() -> usize {
+    std::env::var("WS_BATCH_MAX")
+        .ok()
+        .and_then(|max| max.parse().ok())
+        .filter(|max| *max > 0)
+        .unwrap_or(DEFAULT_MAX)
+}
+
+/// Writes waiting to be sent to one connection
+#[derive(Debug)]
+pub struct Batch {
+    pub format: BatchFormat,
+    window: Duration,
+    max: usize,
+    pixels: Vec<Pixel>,
+    /// Where each pixel waiting is in `pixels`
+    index: HashMap<(i32, i32), usize>,
+    deadline: Option<Instant>,
+}
+
+impl Batch {
+    pub fn new(format: BatchFormat) -> Batch {
+        Batch {
+            format,
+            window: batch_window(),
+            max: batch_max(),
+            pixels: Vec::new(),
+            index: HashMap::new(),
+            deadline: None,
+        }
+    }
+
+    /// Add a write, replacing any waiting for the same pixel. True once the batch is full
+    pub fn push(&mut self, pixel: Pixel) -> bool {
+        match self.index.get(&(pixel.x, pixel.y)) {
+            Some(&idx) => self.pixels[idx] = pixel,
+            None => {
+                self.index.insert((pixel.x, pixel.y), self.pixels.len());
+                self.pixels.push(pixel);
+            }
+        }
+        let window = self.window;
+        self.deadline.get_or_insert_with(|| Instant::now() + window);
+        self.pixels.len() >= self.max
+    }
+
+    /// When the batch has to be sent, None while it's empty
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    /// The writes waiting, in the order their pixels were first written
+    pub fn take(&mut self) -> Vec<Pixel> {
+        self.index.clear();
+        self.deadline = None;
+        std::mem::take(&mut self.pixels)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn batch(max: usize) -> Batch {
+        Batch {
+            window: DEFAULT_WINDOW,
+            max,
+            ..Batch::new(BatchFormat::Json)
+        }
+    }
+
+    fn taken(batch: &mut Batch) -> Vec<(i32, i32, i32)> {
+        batch
+            .take()
+            .iter()
+            .map(|pixel| (pixel.x, pixel.y, pixel.colour))
+            .collect()
+    }
+
+    #[test]
+    fn writes_to_the_same_pixel_are_coalesced() {
+        let mut batch = batch(10);
+        batch.push(Pixel::test(1, 1, 1));
+        batch.push(Pixel::test(2, 2, 2));
+        batch.push(Pixel::test(1, 1, 3));
+        // The newest write, where the pixel was first written
+        assert_eq!(taken(&mut batch), vec![(1, 1, 3), (2, 2, 2)]);
+    }
+
+    #[test]
+    fn coalesced_writes_dont_fill_the_batch() {
+        let mut batch = batch(2);
+        assert!(!batch.push(Pixel::test(1, 1, 1)));
+        assert!(!batch.push(Pixel::test(1, 1, 2)));
+        assert!(!batch.push(Pixel::test(1, 1, 3)));
+        assert!(batch.push(Pixel::test(1, 2, 4)));
+        assert_eq!(taken(&mut batch), vec![(1, 1, 3), (1, 2, 4)]);
+    }
+
+    #[test]
+    fn deadline_is_from_the_first_write() {
+        let mut batch = batch(10);
+        assert_eq!(batch.deadline(), None);
+        let before = Instant::now();
+        batch.push(Pixel::test(1, 1, 1));
+        let deadline = batch.deadline().unwrap();
+        assert!(deadline >= before + DEFAULT_WINDOW);
+        batch.push(Pixel::test(1, 1, 2));
+        batch.push(Pixel::test(2, 2, 2));
+        assert_eq!(batch.deadline(), Some(deadline));
+    }
+
+    #[test]
+    fn taking_starts_a_new_window() {
+        let mut batch = batch(10);
+        batch.push(Pixel::test(1, 1, 1));
+        assert_eq!(taken(&mut batch), vec![(1, 1, 1)]);
+        assert_eq!(batch.deadline(), None);
+        assert!(taken(&mut batch).is_empty());
+
+        // The pixel is no longer waiting, so it isn't replaced in the old position
+        batch.push(Pixel::test(2, 2, 2));
+        batch.push(Pixel::test(1, 1, 3));
+        assert_eq!(taken(&mut batch), vec![(2, 2, 2), (1, 1, 3)]);
+    }
+}
@@ -0,0 +1,107 @@
+//! Write throughput of the store under the `limit_test.py` workload: `workers` clients, each
+//! owning one pixel of a square in the top left of the canvas, all writing their pixel once a
+//! round and switching between two colours every round. The writes go straight to the store
+//! selected by `STORE`, so run it against a scratch database.
+
+use crate::canvas::{self, CanvasRegistry};
+use crate::pixel::{self, Pixel};
+use crate::store::{self, Store, StoreError};
+use futures::future::join_all;
+use std::time::{Duration, Instant};
+
+pub struct BenchOptions {
+    pub canvas: String,
+    pub workers: i32,
+    pub rounds: i32,
+    /// Writes per statement, 0 writes each pixel on its own like the websocket handler does
+    pub batch: usize,
+}
+
+pub struct BenchReport {
+    pub writes: usize,
+    pub elapsed: Duration,
+    pub slowest_round: Duration,
+}
+
+impl BenchReport {
+    pub fn writes_per_second(&self) -> f64 {
+        self.writes as f64 / self.elapsed.as_secs_f64()
+    }
+}
+
+/// Colour of a worker in a round, the same as `limit_test.py` picks
+fn colour(worker: i32, workers: i32, round: i32) -> i32 {
+    if round % 2 == 1 {
+        let green = worker * 255 / workers;
+        255 * 256 * 256 + green * 256
+    } else {
+        let red = (workers - worker) * 255 / workers;
+        let blue = worker * 255 / workers;
+        red * 256 * 256 + blue
+    }
+}
+
+fn round_writes(options: &BenchOptions, round: i32, updated: i32) -> Vec<Pixel> {
+    let side = ((options.workers as f64).sqrt() as i32).max(1);
+    (0..options.workers)
+        .map(|worker| Pixel {
+            x: worker % side,
+            y: worker / side,
+            colour: colour(worker, options.workers, round),
+            updated,
+            canvas_id: options.canvas.clone(),
+            user: None,
+            seq: None,
+        })
+        .collect()
+}
+
+async fn write_round(
+    store: &Store,
+    canvases: &CanvasRegistry,
+    writes: &[Pixel],
+    batch: usize,
+) -> Result<(), StoreError> {
+    let results = if batch == 0 {
+        join_all(
+            writes
+                .iter()
+                .map(|pixel| store.insert_pixel(pixel, canvases.palette_index(pixel))),
+        )
+        .await
+    } else {
+        join_all(
+            writes
+                .chunks(batch)
+                .map(|chunk| store.insert_pixels(chunk, canvases)),
+        )
+        .await
+    };
+    results
+        .into_iter()
+        .try_for_each(|result| result.map(|_| ()))
+}
+
+pub async fn run(
+    options: BenchOptions,
+) -> Result<BenchReport, Box<dyn std::error::Error + Send + Sync>> {
+    let store = store::create_store().await?;
+    let canvases = canvas::load_canvases(&*store).await?;
+
+    // Every round is a second later so each write replaces the one before it
+    let start = pixel::now();
+    let mut slowest_round = Duration::ZERO;
+    let began = Instant::now();
+    for round in 0..options.rounds {
+        let writes = round_writes(&options, round, start + round);
+        let round_began = Instant::now();
+        write_round(&store, &canvases, &writes, options.batch).await?;
+        slowest_round = slowest_round.max(round_began.elapsed());
+    }
+
+    Ok(BenchReport {
+        writes: (options.workers * options.rounds) as usize,
+        elapsed: began.elapsed(),
+        slowest_round,
+    })
+}
@@ -0,0 +1,668 @@
+use crate::pixel::Pixel;
+use crate::store::{CanvasStore, StoreError};
+use crate::CanvasId;
+use serde_json::json;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, RwLock};
+use tokio_postgres::{Error, GenericClient, Row};
+
+/// Board used by clients that don't ask for one, and by the old single-canvas routes.
+pub const DEFAULT_CANVAS: &str = "default";
+
+const MAX_CANVAS_ID_LEN: usize = 64;
+
+/// Palette indices are stored as `smallint`, this keeps them well inside that.
+pub const MAX_PALETTE_SIZE: usize = 256;
+
+/// Largest colour a pixel can hold (24-bit RGB).
+pub const MAX_COLOUR: i32 = 0xFFFFFF;
+
+const DEFAULT_WIDTH: i32 = 501;
+const DEFAULT_HEIGHT: i32 = 501;
+const DEFAULT_BACKGROUND: i32 = 0xFFFFFF;
+
+pub fn default_canvas_id() -> CanvasId {
+    DEFAULT_CANVAS.to_string()
+}
+
+/// Size, background and palette of a canvas, stored in the `canvas_meta` and `canvas_palette` tables.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct CanvasMeta {
+    #[serde(default = "default_canvas_id")]
+    pub id: CanvasId,
+    pub width: i32,
+    pub height: i32,
+    pub background: i32,
+    /// Colours pixels may be set to. Any colour is allowed when empty
+    #[serde(default)]
+    pub palette: Vec<i32>,
+    /// Store pixels as indices into the palette instead of as colours
+    #[serde(default)]
+    pub indexed: bool,
+}
+
+impl Default for CanvasMeta {
+    fn default() -> Self {
+        Self {
+            id: default_canvas_id(),
+            width: DEFAULT_WIDTH,
+            height: DEFAULT_HEIGHT,
+            background: DEFAULT_BACKGROUND,
+            palette: Vec::new(),
+            indexed: false,
+        }
+    }
+}
+
+impl From<Row> for CanvasMeta {
+    fn from(row: Row) -> Self {
+        Self {
+            id: row.get(0),
+            width: row.get(1),
+            height: row.get(2),
+            background: row.get(3),
+            palette: Vec::new(),
+            indexed: row.get(4),
+        }
+    }
+}
+
+/// Rectangle of a canvas starting at `x`, `y`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Region {
+    pub x: i32,
+    pub y: i32,
+    pub width: i32,
+    pub height: i32,
+}
+
+impl Region {
+    /// Widened so regions reaching past `i32::MAX` don't overflow
+    pub fn contains(&self, pixel: &Pixel) -> bool {
+        let (x, y) = (i64::from(pixel.x), i64::from(pixel.y));
+        x >= i64::from(self.x)
+            && x < i64::from(self.x) + i64::from(self.width)
+            && y >= i64::from(self.y)
+            && y < i64::from(self.y) + i64::from(self.height)
+    }
+
+    /// Whether the region is at least a pixel and lies inside the canvas
+    pub fn inside(&self, meta: &CanvasMeta) -> bool {
+        let right = self.x.checked_add(self.width);
+        let bottom = self.y.checked_add(self.height);
+        self.x >= 0
+            && self.y >= 0
+            && self.width > 0
+            && self.height > 0
+            && right.is_some_and(|right| right <= meta.width)
+            && bottom.is_some_and(|bottom| bottom <= meta.height)
+    }
+}
+
+/// Reason a pixel write was rejected. Serialized as the payload of an `error` message.
+#[derive(Debug, Clone, serde::Serialize)]
+#[serde(tag = "code", rename_all = "snake_case")]
+pub enum WriteError {
+    Malformed {
+        message: String,
+    },
+    OutOfBounds {
+        x: i32,
+        y: i32,
+        width: i32,
+        height: i32,
+    },
+    InvalidColour {
+        colour: i32,
+    },
+    NotInPalette {
+        colour: i32,
+    },
+    UnknownCanvas {
+        canvas: CanvasId,
+    },
+    InvalidCanvas {
+        message: String,
+    },
+    /// The client placed a pixel too recently
+    Cooldown {
+        remaining_ms: u64,
+    },
+    /// The pixel is in a region locked by a moderator
+    Locked {
+        lock: i64,
+    },
+    /// The client was banned by a moderator
+    Banned,
+}
+
+impl fmt::Display for WriteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WriteError::Malformed { message } => write!(f, "malformed pixel: {}", message),
+            WriteError::OutOfBounds {
+                x,
+                y,
+                width,
+                height,
+            } => write!(
+                f,
+                "pixel ({}, {}) is outside the {}x{} canvas",
+                x, y, width, height
+            ),
+            WriteError::InvalidColour { colour } => {
+                write!(f, "colour {} is outside 0..={}", colour, MAX_COLOUR)
+            }
+            WriteError::NotInPalette { colour } => {
+                write!(f, "colour {} is not in the palette", colour)
+            }
+            WriteError::UnknownCanvas { canvas } => write!(f, "no canvas named {}", canvas),
+            WriteError::InvalidCanvas { message } => write!(f, "invalid canvas: {}", message),
+            WriteError::Cooldown { remaining_ms } => {
+                write!(f, "next pixel can be placed in {}ms", remaining_ms)
+            }
+            WriteError::Locked { lock } => write!(f, "pixel is in locked region {}", lock),
+            WriteError::Banned => write!(f, "banned from placing pixels"),
+        }
+    }
+}
+
+impl WriteError {
+    /// Message sent back to the WebSocket that made the write. A write made during the
+    /// cooldown gets a `cooldown` message instead of an error
+    pub fn to_ws_message(&self) -> String {
+        let command = match self {
+            WriteError::Cooldown { .. } => "cooldown",
+            _ => "error",
+        };
+        json!({
+            "command": command,
+            "payload": self,
+        })
+        .to_string()
+    }
+}
+
+impl CanvasMeta {
+    pub async fn all<C: GenericClient>(client: &C) -> Result<Vec<CanvasMeta>, Error> {
+        let stmt = client
+            .prepare("SELECT canvas_id, width, height, background, indexed FROM canvas_meta")
+            .await?;
+        let rows = client.query(&stmt, &[]).await?;
+        let mut canvases: Vec<CanvasMeta> = rows.into_iter().map(CanvasMeta::from).collect();
+
+        let stmt = client
+            .prepare("SELECT canvas_id, colour FROM canvas_palette ORDER BY canvas_id, idx")
+            .await?;
+        for row in client.query(&stmt, &[]).await? {
+            let canvas_id: String = row.get(0);
+            if let Some(meta) = canvases.iter_mut().find(|m| m.id == canvas_id) {
+                meta.palette.push(row.get(1));
+            }
+        }
+
+        Ok(canvases)
+    }
+
+    /// Store the canvas and its palette in one transaction, a savepoint if `client` is already
+    /// in one
+    pub async fn set<C: GenericClient>(client: &mut C, meta: &CanvasMeta) -> Result<u64, Error> {
+        let tx = client.transaction().await?;
+        let stmt = tx
+            .prepare(
+                "INSERT INTO canvas_meta (canvas_id, width, height, background, indexed)
+            VALUES ($1, $2, $3, $4, $5)
+            ON CONFLICT (canvas_id) DO UPDATE SET
+            width = $2, height = $3, background = $4, indexed = $5",
+            )
+            .await?;
+        let result = tx
+            .execute(
+                &stmt,
+                &[
+                    &meta.id,
+                    &meta.width,
+                    &meta.height,
+                    &meta.background,
+                    &meta.indexed,
+                ],
+            )
+            .await?;
+
+        let stmt = tx
+            .prepare("DELETE FROM canvas_palette WHERE canvas_id = $1")
+            .await?;
+        tx.execute(&stmt, &[&meta.id]).await?;
+        let stmt = tx
+            .prepare("INSERT INTO canvas_palette (canvas_id, idx, colour) VALUES ($1, $2, $3)")
+            .await?;
+        for (idx, colour) in meta.palette.iter().enumerate() {
+            tx.execute(&stmt, &[&meta.id, &(idx as i16), colour])
+                .await?;
+        }
+        tx.commit().await?;
+
+        Ok(result)
+    }
+
+    /// Index of a colour in the palette, if pixels of this canvas are stored as indices
+    pub fn palette_index(&self, colour: i32) -> Option<i16> {
+        if !self.indexed {
+            return None;
+        }
+        self.palette
+            .iter()
+            .position(|c| *c == colour)
+            .map(|idx| idx as i16)
+    }
+
+    /// Check this canvas can replace `current`. Indexed pixels would point at the wrong
+    /// colours if entries were removed from the palette
+    pub fn check_change(&self, current: &CanvasMeta) -> Result<(), WriteError> {
+        if current.indexed && self.indexed && self.palette.len() < current.palette.len() {
+            return Err(WriteError::InvalidCanvas {
+                message: "colours can't be removed from an indexed palette".to_string(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Check a canvas is fit to be created
+    pub fn check(&self) -> Result<(), WriteError> {
+        let invalid = |message: &str| {
+            Err(WriteError::InvalidCanvas {
+                message: message.to_string(),
+            })
+        };
+        if self.id.is_empty() || self.id.len() > MAX_CANVAS_ID_LEN {
+            return invalid("id must be 1 to 64 characters");
+        }
+        if !self
+            .id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            return invalid("id may only contain letters, digits, '-' and '_'");
+        }
+        if self.width <= 0 || self.height <= 0 {
+            return invalid("dimensions must be positive");
+        }
+        if !(0..=MAX_COLOUR).contains(&self.background) {
+            return invalid("background is not a valid colour");
+        }
+        if self.palette.len() > MAX_PALETTE_SIZE {
+            return invalid("palette can have at most 256 colours");
+        }
+        for (idx, colour) in self.palette.iter().enumerate() {
+            if !(0..=MAX_COLOUR).contains(colour) {
+                return invalid("palette contains an invalid colour");
+            }
+            if self.palette[..idx].contains(colour) {
+                return invalid("palette contains a colour twice");
+            }
+        }
+        if self.indexed && self.palette.is_empty() {
+            return invalid("indexed storage needs a palette");
+        }
+        Ok(())
+    }
+
+    pub fn validate(&self, pixel: &Pixel) -> Result<(), WriteError> {
+        if pixel.x < 0 || pixel.y < 0 || pixel.x >= self.width || pixel.y >= self.height {
+            return Err(WriteError::OutOfBounds {
+                x: pixel.x,
+                y: pixel.y,
+                width: self.width,
+                height: self.height,
+            });
+        }
+        if !(0..=MAX_COLOUR).contains(&pixel.colour) {
+            return Err(WriteError::InvalidColour {
+                colour: pixel.colour,
+            });
+        }
+        if !self.palette.is_empty() && !self.palette.contains(&pixel.colour) {
+            return Err(WriteError::NotInPalette {
+                colour: pixel.colour,
+            });
+        }
+        Ok(())
+    }
+}
+
+fn env_i32(name: &str) -> Option<i32> {
+    let value = std::env::var(name).ok()?;
+    match value.parse::<i32>() {
+        Ok(value) => Some(value),
+        Err(e) => {
+            log::error!("Ignoring {}={}: {}", name, value, e);
+            None
+        }
+    }
+}
+
+/// Canvases known to this replica, shared between the HTTP handlers and the replica manager.
+#[derive(Debug, Clone, Default)]
+pub struct CanvasRegistry {
+    canvases: Arc<RwLock<HashMap<CanvasId, CanvasMeta>>>,
+}
+
+impl CanvasRegistry {
+    pub fn get(&self, id: &str) -> Option<CanvasMeta> {
+        self.canvases.read().unwrap().get(id).cloned()
+    }
+
+    pub fn all(&self) -> Vec<CanvasMeta> {
+        let mut list: Vec<CanvasMeta> = self.canvases.read().unwrap().values().cloned().collect();
+        list.sort_by(|a, b| a.id.cmp(&b.id));
+        list
+    }
+
+    pub fn insert(&self, meta: CanvasMeta) {
+        self.canvases.write().unwrap().insert(meta.id.clone(), meta);
+    }
+
+    /// Parse a pixel sent by a client to `canvas` and check it can be written there
+    pub fn parse_pixel(&self, canvas: &str, data: &str) -> Result<Pixel, WriteError> {
+        let mut pixel = serde_json::from_str::<Pixel>(data).map_err(|e| WriteError::Malformed {
+            message: e.to_string(),
+        })?;
+        pixel.canvas_id = canvas.to_string();
+        self.validate(&pixel)?;
+        Ok(pixel)
+    }
+
+    /// Parse a pixel received from another replica, which carries its own canvas id
+    pub fn parse_replicated_pixel(&self, data: &str) -> Result<Pixel, WriteError> {
+        let pixel = serde_json::from_str::<Pixel>(data).map_err(|e| WriteError::Malformed {
+            message: e.to_string(),
+        })?;
+        self.validate(&pixel)?;
+        Ok(pixel)
+    }
+
+    /// Palette index to store for a pixel, if its canvas uses indexed storage
+    pub fn palette_index(&self, pixel: &Pixel) -> Option<i16> {
+        self.get(&pixel.canvas_id)
+            .and_then(|meta| meta.palette_index(pixel.colour))
+    }
+
+    pub fn validate(&self, pixel: &Pixel) -> Result<(), WriteError> {
+        match self.get(&pixel.canvas_id) {
+            Some(meta) => meta.validate(pixel),
+            None => Err(WriteError::UnknownCanvas {
+                canvas: pixel.canvas_id.clone(),
+            }),
+        }
+    }
+}
+
+/// Why the canvases couldn't be loaded on startup
+#[derive(Debug)]
+pub enum LoadError {
+    Store(StoreError),
+    /// `CANVAS_WIDTH`, `CANVAS_HEIGHT` or `CANVAS_BACKGROUND` make an unusable default canvas
+    Invalid(WriteError),
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::Store(e) => write!(f, "couldn't load canvas metadata: {}", e),
+            LoadError::Invalid(e) => write!(f, "invalid default canvas: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for LoadError {}
+
+impl From<StoreError> for LoadError {
+    fn from(e: StoreError) -> Self {
+        LoadError::Store(e)
+    }
+}
+
+/// Load every canvas, creating the default one on first start. `CANVAS_WIDTH`,
+/// `CANVAS_HEIGHT` and `CANVAS_BACKGROUND` override the default canvas.
+pub async fn load_canvases(store: &dyn CanvasStore) -> Result<CanvasRegistry, LoadError> {
+    let stored = store.canvases().await?;
+    let registry = CanvasRegistry::default();
+    for meta in stored.iter() {
+        registry.insert(meta.clone());
+    }
+
+    let default = registry.get(DEFAULT_CANVAS);
+    let mut meta = default.clone().unwrap_or_default();
+    if let Some(width) = env_i32("CANVAS_WIDTH") {
+        meta.width = width;
+    }
+    if let Some(height) = env_i32("CANVAS_HEIGHT") {
+        meta.height = height;
+    }
+    if let Some(background) = env_i32("CANVAS_BACKGROUND") {
+        meta.background = background;
+    }
+    meta.check().map_err(LoadError::Invalid)?;
+
+    let changed = match &default {
+        Some(stored) => {
+            stored.width != meta.width
+                || stored.height != meta.height
+                || stored.background != meta.background
+        }
+        None => true,
+    };
+    if changed {
+        store.set_canvas(&meta).await?;
+    }
+    registry.insert(meta);
+
+    for meta in registry.all() {
+        log::info!(
+            "Canvas {} is {}x{} with background {:06x}",
+            meta.id,
+            meta.width,
+            meta.height,
+            meta.background
+        );
+    }
+    Ok(registry)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn meta() -> CanvasMeta {
+        CanvasMeta {
+            width: 10,
+            height: 20,
+            background: 0,
+            ..CanvasMeta::default()
+        }
+    }
+
+    #[test]
+    fn pixels_inside_the_canvas() {
+        let meta = meta();
+        for (x, y) in [(0, 0), (9, 0), (0, 19), (9, 19)] {
+            assert!(
+                meta.validate(&Pixel::test(x, y, 0)).is_ok(),
+                "({}, {})",
+                x,
+                y
+            );
+        }
+    }
+
+    #[test]
+    fn pixels_outside_the_canvas() {
+        let meta = meta();
+        for (x, y) in [(-1, 0), (0, -1), (10, 0), (0, 20), (i32::MIN, i32::MAX)] {
+            assert!(
+                matches!(
+                    meta.validate(&Pixel::test(x, y, 0)),
+                    Err(WriteError::OutOfBounds {
+                        width: 10,
+                        height: 20,
+                        ..
+                    })
+                ),
+                "({}, {})",
+                x,
+                y
+            );
+        }
+    }
+
+    fn region(x: i32, y: i32, width: i32, height: i32) -> Region {
+        Region {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    #[test]
+    fn regions_contain_pixels() {
+        let region = region(2, 3, 4, 5);
+        assert!(region.contains(&Pixel::test(2, 3, 0)));
+        assert!(region.contains(&Pixel::test(5, 7, 0)));
+        assert!(!region.contains(&Pixel::test(6, 3, 0)));
+        assert!(!region.contains(&Pixel::test(2, 8, 0)));
+        assert!(!region.contains(&Pixel::test(1, 3, 0)));
+    }
+
+    #[test]
+    fn regions_past_the_largest_coordinate() {
+        let region = region(i32::MAX - 1, i32::MAX - 1, i32::MAX, i32::MAX);
+        assert!(region.contains(&Pixel::test(i32::MAX, i32::MAX, 0)));
+        assert!(!region.contains(&Pixel::test(0, 0, 0)));
+    }
+
+    #[test]
+    fn regions_inside_the_canvas() {
+        let meta = meta();
+        assert!(region(0, 0, 10, 20).inside(&meta));
+        assert!(region(9, 19, 1, 1).inside(&meta));
+        for outside in [
+            region(0, 0, 11, 20),
+            region(0, 0, 10, 21),
+            region(-1, 0, 2, 2),
+            region(0, 0, 0, 1),
+            region(1, 1, i32::MAX, 1),
+            region(1, 1, 1, i32::MAX),
+        ] {
+            assert!(!outside.inside(&meta), "{:?}", outside);
+        }
+    }
+
+    #[test]
+    fn colours() {
+        let meta = meta();
+        assert!(meta.validate(&Pixel::test(0, 0, MAX_COLOUR)).is_ok());
+        for colour in [-1, MAX_COLOUR + 1] {
+            assert!(matches!(
+                meta.validate(&Pixel::test(0, 0, colour)),
+                Err(WriteError::InvalidColour { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn parses_pixels() {
+        let canvases = CanvasRegistry::default();
+        canvases.insert(meta());
+        let pixel = canvases
+            .parse_pixel(DEFAULT_CANVAS, r#"{"x":1,"y":2,"colour":3,"updated":4}"#)
+            .unwrap();
+        assert_eq!(
+            (pixel.x, pixel.y, pixel.colour, pixel.updated),
+            (1, 2, 3, 4)
+        );
+        assert!(matches!(
+            canvases.parse_pixel(DEFAULT_CANVAS, r#"{"x":1,"y":2}"#),
+            Err(WriteError::Malformed { .. })
+        ));
+        assert!(matches!(
+            canvases.parse_pixel(DEFAULT_CANVAS, r#"{"x":10,"y":2,"colour":3,"updated":4}"#),
+            Err(WriteError::OutOfBounds { .. })
+        ));
+        assert!(matches!(
+            canvases.parse_pixel("other", r#"{"x":1,"y":2,"colour":3,"updated":4}"#),
+            Err(WriteError::UnknownCanvas { .. })
+        ));
+    }
+
+    fn palette(colours: &[i32], indexed: bool) -> CanvasMeta {
+        CanvasMeta {
+            palette: colours.to_vec(),
+            indexed,
+            ..meta()
+        }
+    }
+
+    #[test]
+    fn colours_not_in_the_palette() {
+        let meta = palette(&[0xff0000, 0x00ff00], false);
+        assert!(meta.validate(&Pixel::test(0, 0, 0x00ff00)).is_ok());
+        assert!(matches!(
+            meta.validate(&Pixel::test(0, 0, 0x0000ff)),
+            Err(WriteError::NotInPalette { colour: 0x0000ff })
+        ));
+        // Any colour goes without a palette
+        assert!(palette(&[], false)
+            .validate(&Pixel::test(0, 0, 0x0000ff))
+            .is_ok());
+    }
+
+    #[test]
+    fn palette_indices() {
+        let meta = palette(&[0xff0000, 0x00ff00], true);
+        assert_eq!(meta.palette_index(0x00ff00), Some(1));
+        assert_eq!(meta.palette_index(0x0000ff), None);
+        assert_eq!(palette(&[0xff0000], false).palette_index(0xff0000), None);
+    }
+
+    #[test]
+    fn checks_canvases() {
+        assert!(palette(&[0, MAX_COLOUR], true).check().is_ok());
+        let invalid = [
+            CanvasMeta {
+                id: String::new(),
+                ..meta()
+            },
+            CanvasMeta {
+                id: "a b".to_string(),
+                ..meta()
+            },
+            CanvasMeta { width: 0, ..meta() },
+            CanvasMeta {
+                background: -1,
+                ..meta()
+            },
+            palette(&[MAX_COLOUR + 1], false),
+            palette(&[1, 2, 1], false),
+            palette(&(0..=MAX_PALETTE_SIZE as i32).collect::<Vec<_>>(), false),
+            palette(&[], true),
+        ];
+        for meta in invalid.iter() {
+            assert!(
+                matches!(meta.check(), Err(WriteError::InvalidCanvas { .. })),
+                "{:?}",
+                meta
+            );
+        }
+    }
+
+    #[test]
+    fn indexed_palettes_only_grow() {
+        let current = palette(&[1, 2], true);
+        assert!(palette(&[1, 2, 3], true).check_change(&current).is_ok());
+        assert!(palette(&[1], true).check_change(&current).is_err());
+        // Pixels are turned back into colours first
+        assert!(palette(&[1], false).check_change(&current).is_ok());
+    }
+}
@@ -0,0 +1,274 @@
+//! Command line tools, run as `cargo run -- <command>`. `export` and `import` talk to the admin
+//! routes of a running replica, so imports are replicated like any other write. `migrate` works
+//! on the Postgres database configured with the `PG_*` variables directly, and `bench` on the
+//! store selected by `STORE`.
+//!
+//! ```text
+//! export [--canvas ID] [--format csv|jsonl] [--output FILE]
+//!        [--x X --y Y --width W --height H] [--history] [--since T] [--until T]
+//! import FILE [--canvas ID] [--format csv|jsonl]
+//! migrate up|down [STEPS]|status
+//! bench [--canvas ID] [--workers N] [--rounds N] [--batch N]
+//! ```
+//!
+//! `export` and `import` take `--server URL`, which defaults to the replica at `ADDRESS`, and
+//! `--token TOKEN`, which defaults to `ADMIN_TOKEN`.
+
+use crate::bench::{self, BenchOptions};
+use crate::canvas::DEFAULT_CANVAS;
+use crate::dump::Format;
+use crate::migrate::{self, MigrationError};
+use crate::postgres;
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::time::Duration;
+
+/// Imports are written one pixel at a time, leave them plenty of time
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(600);
+
+const MAX_EXPORT_SIZE: usize = 256 * 1024 * 1024;
+
+/// Options that are sent on to the replica as query parameters when given
+const EXPORT_QUERY: [&str; 6] = ["x", "y", "width", "height", "since", "until"];
+
+struct Args {
+    positional: Vec<String>,
+    options: HashMap<String, String>,
+    flags: Vec<String>,
+}
+
+impl Args {
+    fn parse(args: impl Iterator<Item = String>) -> io::Result<Args> {
+        let mut parsed = Args {
+            positional: Vec::new(),
+            options: HashMap::new(),
+            flags: Vec::new(),
+        };
+        let mut args = args.peekable();
+        while let Some(arg) = args.next() {
+            match arg.strip_prefix("--") {
+                Some("history") => parsed.flags.push("history".into()),
+                Some(name) => match args.next() {
+                    Some(value) => {
+                        parsed.options.insert(name.to_string(), value);
+                    }
+                    None => return Err(usage(&format!("--{} needs a value", name))),
+                },
+                None => parsed.positional.push(arg),
+            }
+        }
+        Ok(parsed)
+    }
+
+    fn option(&self, name: &str) -> Option<&str> {
+        self.options.get(name).map(String::as_str)
+    }
+
+    fn number<T: std::str::FromStr>(&self, name: &str, default: T) -> io::Result<T> {
+        match self.option(name) {
+            Some(value) => value
+                .parse()
+                .map_err(|_| usage(&format!("--{} must be a number, got {}", name, value))),
+            None => Ok(default),
+        }
+    }
+
+    fn format(&self, path: Option<&str>) -> io::Result<Format> {
+        match self.option("format") {
+            Some(format) => format.parse().map_err(|e: String| usage(&e)),
+            None => Ok(path.map(Format::from_path).unwrap_or_default()),
+        }
+    }
+
+    fn canvas_url(&self, route: &str) -> String {
+        format!(
+            "{}/admin/canvases/{}/{}",
+            self.option("server")
+                .map(str::to_string)
+                .unwrap_or_else(default_server),
+            self.option("canvas").unwrap_or(DEFAULT_CANVAS),
+            route
+        )
+    }
+}
+
+fn usage(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message.to_string())
+}
+
+fn default_server() -> String {
+    let address = std::env::var("ADDRESS").unwrap_or_else(|_| "127.0.0.1:8000".into());
+    format!("http://{}", address.replace("0.0.0.0", "127.0.0.1"))
+}
+
+/// Client for the admin routes, sending the admin token if there is one
+fn client(args: &Args) -> awc::Client {
+    let token = args
+        .option("token")
+        .map(str::to_string)
+        .or_else(|| std::env::var("ADMIN_TOKEN").ok())
+        .filter(|token| !token.is_empty());
+    let builder = awc::Client::builder().timeout(REQUEST_TIMEOUT);
+    match token {
+        Some(token) => builder.bearer_auth(token).finish(),
+        None => builder.finish(),
+    }
+}
+
+fn request_error(e: impl std::fmt::Display) -> io::Error {
+    io::Error::other(e.to_string())
+}
+
+/// Fail with the body of an unsuccessful response, which describes what went wrong
+fn check_status(status: awc::http::StatusCode, body: &[u8]) -> io::Result<()> {
+    if status.is_success() {
+        Ok(())
+    } else {
+        Err(request_error(format!(
+            "{}: {}",
+            status,
+            String::from_utf8_lossy(body)
+        )))
+    }
+}
+
+async fn export(args: Args) -> io::Result<()> {
+    let output = args.option("output");
+    let format = args.format(output)?;
+
+    let mut query = vec![("format".to_string(), format.to_string())];
+    for name in EXPORT_QUERY {
+        if let Some(value) = args.option(name) {
+            query.push((name.to_string(), value.to_string()));
+        }
+    }
+    if args.flags.iter().any(|flag| flag == "history") {
+        query.push(("history".into(), "true".into()));
+    }
+
+    let mut res = client(&args)
+        .get(args.canvas_url("export"))
+        .query(&query)
+        .map_err(request_error)?
+        .send()
+        .await
+        .map_err(request_error)?;
+    let body = res
+        .body()
+        .limit(MAX_EXPORT_SIZE)
+        .await
+        .map_err(request_error)?;
+    check_status(res.status(), &body)?;
+
+    match output {
+        Some(path) => tokio::fs::write(path, &body).await,
+        None => io::stdout().write_all(&body),
+    }
+}
+
+async fn import(args: Args) -> io::Result<()> {
+    let path = match args.positional.first() {
+        Some(path) => path.as_str(),
+        None => return Err(usage("import needs a file")),
+    };
+    let format = args.format(Some(path))?;
+    let data = tokio::fs::read(path).await?;
+
+    let mut res = client(&args)
+        .post(args.canvas_url("import"))
+        .query(&[("format", format.to_string())])
+        .map_err(request_error)?
+        .content_type(format.content_type())
+        .send_body(data)
+        .await
+        .map_err(request_error)?;
+    let body = res.body().await.map_err(request_error)?;
+    check_status(res.status(), &body)?;
+
+    println!("{}", String::from_utf8_lossy(&body));
+    Ok(())
+}
+
+fn migration_error(e: MigrationError) -> io::Error {
+    io::Error::other(e)
+}
+
+async fn migrate(args: Args) -> io::Result<()> {
+    let pool = postgres::create_pool().map_err(io::Error::other)?;
+    let mut client = pool.get().await.map_err(|e| migration_error(e.into()))?;
+    let mut positional = args.positional.iter().map(String::as_str);
+    match positional.next() {
+        Some("up") => {
+            let migrated = migrate::up(&mut client).await.map_err(migration_error)?;
+            for name in migrated.iter() {
+                println!("applied {}", name);
+            }
+            println!("schema version {}", migrate::SCHEMA_VERSION);
+        }
+        Some("down") => {
+            let steps = match positional.next() {
+                Some(steps) => steps
+                    .parse()
+                    .map_err(|_| usage(&format!("invalid number of steps {}", steps)))?,
+                None => 1,
+            };
+            let reverted = migrate::down(&mut client, steps)
+                .await
+                .map_err(migration_error)?;
+            for name in reverted.iter() {
+                println!("reverted {}", name);
+            }
+        }
+        Some("status") => {
+            for status in migrate::status(&**client).await.map_err(migration_error)? {
+                println!(
+                    "{:<40} {:<20} {}",
+                    status.name,
+                    status.state,
+                    status.executed_at.unwrap_or_default()
+                );
+            }
+        }
+        _ => return Err(usage("expected migrate up, down [STEPS] or status")),
+    }
+    Ok(())
+}
+
+async fn bench(args: Args) -> io::Result<()> {
+    let options = BenchOptions {
+        canvas: args.option("canvas").unwrap_or(DEFAULT_CANVAS).to_string(),
+        workers: args.number("workers", 100)?,
+        rounds: args.number("rounds", 50)?,
+        batch: args.number("batch", 0)?,
+    };
+    if options.workers < 1 || options.rounds < 1 {
+        return Err(usage("--workers and --rounds must be at least 1"));
+    }
+    let batch = options.batch;
+    let report = bench::run(options).await.map_err(io::Error::other)?;
+    println!(
+        "{} writes in {:.2?}, {:.0} writes/s, slowest round {:.2?}, batch {}",
+        report.writes,
+        report.elapsed,
+        report.writes_per_second(),
+        report.slowest_round,
+        batch
+    );
+    Ok(())
+}
+
+/// Run the command in `args`, which start after the program name
+pub async fn run(mut args: impl Iterator<Item = String>) -> io::Result<()> {
+    let command = args.next().unwrap_or_default();
+    let args = Args::parse(args)?;
+    match command.as_str() {
+        "export" => export(args).await,
+        "import" => import(args).await,
+        "migrate" => migrate(args).await,
+        "bench" => bench(args).await,
+        other => Err(usage(&format!(
+            "unknown command {}, expected export, import, migrate or bench",
+            other
+        ))),
+    }
+}
@@ -0,0 +1,287 @@
+//! The wait between two pixels placed by the same client. A client is identified by the user
+//! its token was issued to, see `auth`, and by its IP address when it has none. The proxy writes
+//! for all of its clients over one connection, so it names the client of each write itself, see
+//! [`proxied_write`].
+//!
+//! The replica a client writes to holds it to its cooldown right away, and sends the placement
+//! around the ring once the write is accepted, so every replica knows when each client last
+//! placed a pixel and switching replicas doesn't reset it. A write that fails gives the client
+//! its cooldown back.
+
+use actix_web::HttpRequest;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::OnceLock;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Placements are forgotten once their cooldown is over, checked every this many placements
+const PRUNE_EVERY: usize = 1024;
+
+/// A client placed a pixel at `placed_at`, in unix milliseconds
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Placement {
+    pub identity: String,
+    pub placed_at: u64,
+}
+
+/// A placement made before its write was accepted, undone if the write fails
+#[derive(Debug, Clone)]
+pub struct Reservation {
+    pub placement: Placement,
+    /// When the client placed a pixel before this one
+    previous: Option<u64>,
+}
+
+/// When each client last placed a pixel
+#[derive(Debug)]
+pub struct Cooldowns {
+    cooldown: Duration,
+    placed: HashMap<String, u64>,
+    recorded: usize,
+}
+
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+/// `PIXEL_COOLDOWN` in seconds, 0 (the default) lets clients write as often as they like
+pub fn pixel_cooldown() -> Duration {
+    std::env::var("PIXEL_COOLDOWN")
+        .ok()
+        .and_then(|cooldown| cooldown.parse().ok())
+        .map(Duration::from_secs_f64)
+        .unwrap_or(Duration::ZERO)
+}
+
+/// `TRUSTED_PROXIES`, comma separated addresses of the proxies allowed to say who their
+/// clients are. Read once, the first time it's needed
+pub fn trusted_proxies() -> &'static [IpAddr] {
+    static TRUSTED_PROXIES: OnceLock<Vec<IpAddr>> = OnceLock::new();
+    TRUSTED_PROXIES.get_or_init(|| {
+        std::env::var("TRUSTED_PROXIES")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|proxy| !proxy.is_empty())
+            .filter_map(|proxy| match proxy.parse() {
+                Ok(address) => Some(address),
+                Err(err) => {
+                    log::error!("Ignoring {:?} in TRUSTED_PROXIES: {}", proxy, err);
+                    None
+                }
+            })
+            .collect()
+    })
+}
+
+/// Whether a request comes straight from one of the trusted proxies
+pub fn from_trusted_proxy(req: &HttpRequest) -> bool {
+    req.peer_addr()
+        .is_some_and(|peer| trusted_proxies().contains(&peer.ip()))
+}
+
+/// Address a request came from. `Forwarded` and `X-Forwarded-For` are only believed from a
+/// trusted proxy, anyone else could make up a new address for every write
+pub fn client_address(req: &HttpRequest) -> String {
+    let Some(peer) = req.peer_addr() else {
+        return "unknown".to_string();
+    };
+    if !trusted_proxies().contains(&peer.ip()) {
+        return peer.ip().to_string();
+    }
+    req.connection_info()
+        .realip_remote_addr()
+        .unwrap_or("unknown")
+        .to_string()
+}
+
+/// Who made a write, see the module docs
+pub fn identity(user: Option<&str>, address: &str) -> String {
+    match user {
+        Some(user) => format!("user:{}", user),
+        None => format!("ip:{}", address),
+    }
+}
+
+/// A write sent by a trusted proxy on behalf of one of its clients, `{"client": "<address>",
+/// ...}`. Gives back the write without `client`, and the client's address
+pub fn proxied_write(text: &str) -> Option<(String, String)> {
+    let mut write: serde_json::Map<String, serde_json::Value> = serde_json::from_str(text).ok()?;
+    let client = match write.remove("client")? {
+        serde_json::Value::String(client) => client,
+        _ => return None,
+    };
+    Some((serde_json::Value::Object(write).to_string(), client))
+}
+
+/// Tell a proxy which of its clients a reply is for, by adding `client` to it
+pub fn to_client(reply: &str, client: &str) -> String {
+    match serde_json::from_str(reply) {
+        Ok(serde_json::Value::Object(mut reply)) => {
+            reply.insert("client".to_string(), client.into());
+            serde_json::Value::Object(reply).to_string()
+        }
+        _ => reply.to_string(),
+    }
+}
+
+impl Cooldowns {
+    pub fn from_env() -> Self {
+        Self {
+            cooldown: pixel_cooldown(),
+            placed: HashMap::new(),
+            recorded: 0,
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        !self.cooldown.is_zero()
+    }
+
+    /// How long `identity` still has to wait before placing a pixel, if at all
+    pub fn remaining(&self, identity: &str) -> Option<Duration> {
+        let placed_at = *self.placed.get(identity)?;
+        let ready_at = placed_at + self.cooldown.as_millis() as u64;
+        let now = now_millis();
+        (ready_at > now).then(|| Duration::from_millis(ready_at - now))
+    }
+
+    /// Remember a placement, keeping the newest one of each client
+    pub fn record(&mut self, placement: Placement) {
+        if !self.enabled() {
+            return;
+        }
+        let placed_at = self.placed.entry(placement.identity).or_default();
+        *placed_at = (*placed_at).max(placement.placed_at);
+
+        self.recorded += 1;
+        if self.recorded.is_multiple_of(PRUNE_EVERY) {
+            let cutoff = now_millis().saturating_sub(self.cooldown.as_millis() as u64);
+            self.placed.retain(|_, placed_at| *placed_at > cutoff);
+        }
+    }
+
+    /// Hold `identity` to its cooldown from now on, or fail with how long it still has to wait.
+    /// Nothing is reserved without a cooldown
+    pub fn reserve(&mut self, identity: String) -> Result<Option<Reservation>, Duration> {
+        if !self.enabled() {
+            return Ok(None);
+        }
+        if let Some(remaining) = self.remaining(&identity) {
+            return Err(remaining);
+        }
+        let previous = self.placed.get(&identity).copied();
+        let placement = Placement {
+            identity,
+            placed_at: now_millis(),
+        };
+        self.record(placement.clone());
+        Ok(Some(Reservation {
+            placement,
+            previous,
+        }))
+    }
+
+    /// Give back the cooldown of a write that failed, unless the client has placed a pixel
+    /// since
+    pub fn refund(&mut self, reservation: &Reservation) {
+        let identity = &reservation.placement.identity;
+        if self.placed.get(identity) != Some(&reservation.placement.placed_at) {
+            return;
+        }
+        match reservation.previous {
+            Some(previous) => {
+                self.placed.insert(identity.clone(), previous);
+            }
+            None => {
+                self.placed.remove(identity);
+            }
+        }
+    }
+
+    /// Placements still cooling down, sent to replicas joining the ring
+    pub fn placements(&self) -> Vec<Placement> {
+        self.placed
+            .iter()
+            .filter(|(identity, _)| self.remaining(identity).is_some())
+            .map(|(identity, placed_at)| Placement {
+                identity: identity.clone(),
+                placed_at: *placed_at,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn proxied_writes() {
+        let (write, client) = proxied_write(r#"{"x":1,"y":2,"client":"10.0.0.7"}"#).unwrap();
+        assert_eq!(write, r#"{"x":1,"y":2}"#);
+        assert_eq!(client, "10.0.0.7");
+
+        assert!(proxied_write(r#"{"x":1,"y":2}"#).is_none());
+        assert!(proxied_write(r#"{"x":1,"client":7}"#).is_none());
+        assert!(proxied_write("not json").is_none());
+    }
+
+    fn with_cooldown(seconds: u64) -> Cooldowns {
+        Cooldowns {
+            cooldown: Duration::from_secs(seconds),
+            placed: HashMap::new(),
+            recorded: 0,
+        }
+    }
+
+    #[test]
+    fn reservations_hold_the_client() {
+        let mut cooldowns = with_cooldown(60);
+        assert!(cooldowns.reserve("ip:1".to_string()).unwrap().is_some());
+        assert!(cooldowns.reserve("ip:1".to_string()).is_err());
+        assert!(cooldowns.reserve("ip:2".to_string()).is_ok());
+
+        assert!(with_cooldown(0)
+            .reserve("ip:1".to_string())
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn refunds_give_the_cooldown_back() {
+        let mut cooldowns = with_cooldown(60);
+        let reservation = cooldowns.reserve("ip:1".to_string()).unwrap().unwrap();
+        cooldowns.refund(&reservation);
+        assert!(cooldowns.remaining("ip:1").is_none());
+
+        // Back to the placement before it
+        let earlier = now_millis() - 90_000;
+        cooldowns.placed.insert("ip:1".to_string(), earlier);
+        let reservation = cooldowns.reserve("ip:1".to_string()).unwrap().unwrap();
+        cooldowns.refund(&reservation);
+        assert_eq!(cooldowns.placed["ip:1"], earlier);
+    }
+
+    #[test]
+    fn refunds_keep_newer_placements() {
+        let mut cooldowns = with_cooldown(60);
+        let reservation = cooldowns.reserve("ip:1".to_string()).unwrap().unwrap();
+        // Placed again on another replica
+        cooldowns.record(Placement {
+            identity: "ip:1".to_string(),
+            placed_at: reservation.placement.placed_at + 1,
+        });
+        cooldowns.refund(&reservation);
+        assert!(cooldowns.remaining("ip:1").is_some());
+    }
+
+    #[test]
+    fn replies_to_clients() {
+        let reply = to_client(r#"{"command":"cooldown"}"#, "10.0.0.7");
+        assert_eq!(reply, r#"{"client":"10.0.0.7","command":"cooldown"}"#);
+    }
+}
@@ -0,0 +1,753 @@
+//! permessage-deflate (RFC 7692) for `/ws`.
+//!
+//! actix-ws doesn't know about WebSocket extensions, and its codec turns away frames with the
+//! RSV1 bit compressed messages are marked with. So when a client offers the extension, frames
+//! are deflated on their way out of actix-ws and inflated before it reads them, leaving the
+//! sessions themselves as they were.
+//!
+//! Configured with:
+//!
+//! - `WS_DEFLATE`: `off` turns compression down for every connection
+//! - `WS_DEFLATE_LEVEL`: 0 (fastest) to 9 (smallest), default 6
+//! - `WS_DEFLATE_MIN_SIZE`: messages shorter than this many bytes are sent as they are, default
+//!   128. Compressing a single pixel update costs more than it saves
+//!
+//! Messages the client sends inflate to at most `MAX_INFLATED` bytes, the largest frame actix-ws
+//! takes anyway, counting every frame of a fragmented one. A client sending a larger message is
+//! closed with 1009 (message too big).
+
+use actix_web::body::{BodySize, BoxBody, MessageBody};
+use actix_web::dev::Payload;
+use actix_web::error::PayloadError;
+use actix_web::http::header::{HeaderValue, SEC_WEBSOCKET_EXTENSIONS};
+use actix_web::web::{self, Bytes, BytesMut};
+use actix_web::{FromRequest, HttpRequest, HttpResponse};
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress, Status};
+use futures::Stream;
+use std::error::Error as StdError;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+const DEFAULT_LEVEL: u32 = 6;
+const DEFAULT_MIN_SIZE: usize = 128;
+
+/// Largest message accepted from a client, before and after inflating it
+const MAX_INFLATED: usize = 65_536;
+
+/// Ends every deflated message, left off on the wire (RFC 7692 §7.2.1)
+const TAIL: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+
+const FIN: u8 = 0x80;
+const RSV1: u8 = 0x40;
+const MASKED: u8 = 0x80;
+const CONTINUATION: u8 = 0x0;
+/// Opcodes from this one up are control frames, which are never compressed
+const CLOSE: u8 = 0x8;
+/// Close code for a message too big to process (RFC 6455 §7.4.1)
+const MESSAGE_TOO_BIG: u16 = 1009;
+
+/// `WS_DEFLATE`
+fn deflate_enabled() -> bool {
+    std::env::var("WS_DEFLATE").map_or(true, |enabled| enabled != "off")
+}
+
+/// `WS_DEFLATE_LEVEL`
+fn deflate_level() -> Compression {
+    let level = std::env::var("WS_DEFLATE_LEVEL")
+        .ok()
+        .and_then(|level| level.parse().ok())
+        .filter(|level| *level <= 9)
+        .unwrap_or(DEFAULT_LEVEL);
+    Compression::new(level)
+}
+
+/// `WS_DEFLATE_MIN_SIZE`
+fn deflate_min_size() -> usize {
+    std::env::var("WS_DEFLATE_MIN_SIZE")
+        .ok()
+        .and_then(|size| size.parse().ok())
+        .unwrap_or(DEFAULT_MIN_SIZE)
+}
+
+/// Parameters agreed on with a client
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct Params {
+    /// Compress every message on its own
+    server_no_context_takeover: bool,
+    /// The client compresses every message on its own
+    client_no_context_takeover: bool,
+    /// The client asked for the window we use anyway
+    server_max_window_bits: bool,
+}
+
+impl Params {
+    /// Parameters of an offer we can take, None for another extension or one we can't
+    fn from_offer(offer: &str) -> Option<Params> {
+        let mut parts = offer.split(';').map(str::trim);
+        if parts.next()? != "permessage-deflate" {
+            return None;
+        }
+        let mut params = Params::default();
+        let mut seen = Vec::new();
+        for param in parts {
+            let (name, value) = match param.split_once('=') {
+                Some((name, value)) => (name.trim(), Some(value.trim().trim_matches('"'))),
+                None => (param, None),
+            };
+            // A parameter given twice makes the offer invalid
+            if seen.contains(&name) {
+                return None;
+            }
+            seen.push(name);
+            let window_bits =
+                value.map(|bits| bits.parse::<u8>().ok().filter(|b| (8..=15).contains(b)));
+            match (name, window_bits) {
+                ("server_no_context_takeover", None) => params.server_no_context_takeover = true,
+                ("client_no_context_takeover", None) => params.client_no_context_takeover = true,
+                // The compressor always uses the largest window, it can't be made smaller
+                ("server_max_window_bits", Some(Some(15))) => params.server_max_window_bits = true,
+                // Inflating takes any window, so the client can keep whichever it likes
+                ("client_max_window_bits", None | Some(Some(_))) => {}
+                _ => return None,
+            }
+        }
+        Some(params)
+    }
+
+    /// `Sec-WebSocket-Extensions` accepting the offer
+    fn response_header(&self) -> HeaderValue {
+        let mut header = String::from("permessage-deflate");
+        if self.server_no_context_takeover {
+            header.push_str("; server_no_context_takeover");
+        }
+        if self.client_no_context_takeover {
+            header.push_str("; client_no_context_takeover");
+        }
+        if self.server_max_window_bits {
+            header.push_str("; server_max_window_bits=15");
+        }
+        HeaderValue::from_str(&header).expect("extension parameters are ASCII")
+    }
+}
+
+/// First offer of permessage-deflate we can take, in the client's order of preference
+fn negotiate(req: &HttpRequest) -> Option<Params> {
+    if !deflate_enabled() {
+        return None;
+    }
+    req.headers()
+        .get_all(SEC_WEBSOCKET_EXTENSIONS)
+        .filter_map(|header| header.to_str().ok())
+        .flat_map(|header| header.split(','))
+        .find_map(Params::from_offer)
+}
+
+/// `actix_ws::handle`, compressing the connection if the client offers to
+pub async fn handle(
+    req: &HttpRequest,
+    payload: web::Payload,
+) -> Result<(HttpResponse, actix_ws::Session, actix_ws::MessageStream), actix_web::Error> {
+    let Some(params) = negotiate(req) else {
+        return actix_ws::handle(req, payload);
+    };
+
+    let inflating = Inflating {
+        payload: payload.into_inner(),
+        buf: BytesMut::new(),
+        inflater: Inflater::new(params.client_no_context_takeover),
+        closed: false,
+    };
+    let inflating: Pin<Box<dyn Stream<Item = _>>> = Box::pin(inflating);
+    let mut inflated = Payload::from(inflating);
+    let payload = web::Payload::from_request(req, &mut inflated).await?;
+
+    let (res, session, msg_stream) = actix_ws::handle(req, payload)?;
+    let mut res = res.map_body(|_, body| {
+        Deflating {
+            body,
+            buf: BytesMut::new(),
+            deflater: Deflater::new(params.server_no_context_takeover),
+        }
+        .boxed()
+    });
+    res.headers_mut()
+        .insert(SEC_WEBSOCKET_EXTENSIONS, params.response_header());
+    Ok((res, session, msg_stream))
+}
+
+/// A frame with its payload unmasked
+#[derive(Debug)]
+struct Frame {
+    /// The first byte, FIN, RSV bits and opcode
+    head: u8,
+    payload: BytesMut,
+}
+
+impl Frame {
+    fn opcode(&self) -> u8 {
+        self.head & 0x0f
+    }
+
+    fn fin(&self) -> bool {
+        self.head & FIN != 0
+    }
+
+    fn compressed(&self) -> bool {
+        self.head & RSV1 != 0
+    }
+
+    fn is_control(&self) -> bool {
+        self.opcode() >= CLOSE
+    }
+}
+
+/// Take the next whole frame off `buf`, None until one has been buffered
+fn read_frame(buf: &mut BytesMut, max_size: usize) -> Result<Option<Frame>, PayloadError> {
+    if buf.len() < 2 {
+        return Ok(None);
+    }
+    let masked = buf[1] & MASKED != 0;
+    let (len, mut idx) = match buf[1] & 0x7f {
+        126 if buf.len() >= 4 => (u16::from_be_bytes([buf[2], buf[3]]) as u64, 4),
+        127 if buf.len() >= 10 => {
+            let mut len = [0; 8];
+            len.copy_from_slice(&buf[2..10]);
+            (u64::from_be_bytes(len), 10)
+        }
+        126 | 127 => return Ok(None),
+        len => (len as u64, 2),
+    };
+    if len > max_size as u64 {
+        return Err(PayloadError::Overflow);
+    }
+    let mask = if masked {
+        idx += 4;
+        buf.get(idx - 4..idx)
+            .map(|key| [key[0], key[1], key[2], key[3]])
+    } else {
+        Some([0; 4])
+    };
+    let Some(mask) = mask.filter(|_| buf.len() >= idx + len as usize) else {
+        return Ok(None);
+    };
+
+    let mut frame = buf.split_to(idx + len as usize);
+    let head = frame[0];
+    let mut payload = frame.split_off(idx);
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+    Ok(Some(Frame { head, payload }))
+}
+
+/// Append a frame to `dst`. Frames for actix-ws are masked with a key of zeros, which leaves the
+/// payload as it is
+fn write_frame(dst: &mut BytesMut, head: u8, payload: &[u8], masked: bool) {
+    let mask = if masked { MASKED } else { 0 };
+    dst.reserve(payload.len() + 14);
+    dst.extend_from_slice(&[head]);
+    match payload.len() {
+        len if len < 126 => dst.extend_from_slice(&[mask | len as u8]),
+        len if len <= u16::MAX as usize => {
+            dst.extend_from_slice(&[mask | 126]);
+            dst.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            dst.extend_from_slice(&[mask | 127]);
+            dst.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    if masked {
+        dst.extend_from_slice(&[0; 4]);
+    }
+    dst.extend_from_slice(payload);
+}
+
+/// Inflates the messages of one client
+struct Inflater {
+    decompress: Decompress,
+    no_context_takeover: bool,
+    /// The message being received is compressed
+    compressed: bool,
+    /// Bytes of the message being received so far, inflated
+    size: usize,
+}
+
+impl Inflater {
+    fn new(no_context_takeover: bool) -> Inflater {
+        Inflater {
+            decompress: Decompress::new(false),
+            no_context_takeover,
+            compressed: false,
+            size: 0,
+        }
+    }
+
+    /// The frame as actix-ws can read it
+    fn inflate(&mut self, frame: Frame) -> Result<Frame, PayloadError> {
+        if frame.is_control() {
+            return Ok(frame);
+        }
+        if frame.opcode() != CONTINUATION {
+            self.compressed = frame.compressed();
+            self.size = 0;
+        }
+        if !self.compressed {
+            self.size += frame.payload.len();
+            if self.size > MAX_INFLATED {
+                return Err(PayloadError::Overflow);
+            }
+            return Ok(frame);
+        }
+
+        let mut out = Vec::new();
+        self.decompress_into(&frame.payload, &mut out)?;
+        if frame.fin() {
+            self.decompress_into(&TAIL, &mut out)?;
+            if self.no_context_takeover {
+                self.decompress.reset(false);
+            }
+        }
+        self.size += out.len();
+        Ok(Frame {
+            head: frame.head & !RSV1,
+            payload: BytesMut::from(&out[..]),
+        })
+    }
+
+    fn decompress_into(&mut self, mut input: &[u8], out: &mut Vec<u8>) -> Result<(), PayloadError> {
+        loop {
+            if out.capacity() - out.len() < 1024 {
+                out.reserve(input.len().max(1024) * 2);
+            }
+            let before_in = self.decompress.total_in();
+            let before_out = self.decompress.total_out();
+            let status = self
+                .decompress
+                .decompress_vec(input, out, FlushDecompress::Sync)
+                .map_err(|_| PayloadError::EncodingCorrupted)?;
+            input = &input[(self.decompress.total_in() - before_in) as usize..];
+            if self.size + out.len() > MAX_INFLATED {
+                return Err(PayloadError::Overflow);
+            }
+            if status == Status::StreamEnd {
+                // The client ended the stream with a final block, the next message starts a new one
+                self.decompress.reset(false);
+            }
+            if input.is_empty() && out.len() < out.capacity() {
+                return Ok(());
+            }
+            if self.decompress.total_in() == before_in && self.decompress.total_out() == before_out
+            {
+                // Input left over that can't be inflated
+                return Err(PayloadError::EncodingCorrupted);
+            }
+        }
+    }
+}
+
+/// Deflates the messages sent to one client
+struct Deflater {
+    compress: Compress,
+    min_size: usize,
+    no_context_takeover: bool,
+    /// The message being sent is compressed
+    compressed: bool,
+}
+
+impl Deflater {
+    fn new(no_context_takeover: bool) -> Deflater {
+        Deflater {
+            compress: Compress::new(deflate_level(), false),
+            min_size: deflate_min_size(),
+            no_context_takeover,
+            compressed: false,
+        }
+    }
+
+    /// Append the frame to `dst`, compressed if its message is worth it
+    fn deflate(&mut self, frame: Frame, dst: &mut BytesMut) -> Result<(), flate2::CompressError> {
+        if frame.is_control() {
+            write_frame(dst, frame.head, &frame.payload, false);
+            return Ok(());
+        }
+        let first = frame.opcode() != CONTINUATION;
+        if first {
+            // Only whole messages are known to be short
+            self.compressed = !frame.fin() || frame.payload.len() >= self.min_size;
+        }
+        if !self.compressed {
+            write_frame(dst, frame.head, &frame.payload, false);
+            return Ok(());
+        }
+
+        let flush = if frame.fin() {
+            FlushCompress::Sync
+        } else {
+            FlushCompress::None
+        };
+        let mut out = self.compress_into(&frame.payload, flush)?;
+        if frame.fin() {
+            if out.ends_with(&TAIL) {
+                out.truncate(out.len() - TAIL.len());
+            }
+            if self.no_context_takeover {
+                self.compress.reset();
+            }
+        }
+        let head = if first { frame.head | RSV1 } else { frame.head };
+        write_frame(dst, head, &out, false);
+        Ok(())
+    }
+
+    fn compress_into(
+        &mut self,
+        mut input: &[u8],
+        flush: FlushCompress,
+    ) -> Result<Vec<u8>, flate2::CompressError> {
+        let mut out = Vec::with_capacity(input.len() / 2 + 64);
+        loop {
+            if out.len() == out.capacity() {
+                out.reserve(out.capacity().max(64));
+            }
+            let before = self.compress.total_in();
+            self.compress.compress_vec(input, &mut out, flush)?;
+            input = &input[(self.compress.total_in() - before) as usize..];
+            if input.is_empty() && out.len() < out.capacity() {
+                return Ok(out);
+            }
+        }
+    }
+}
+
+/// Request payload of a compressed connection, with its frames inflated
+struct Inflating {
+    payload: Payload,
+    buf: BytesMut,
+    inflater: Inflater,
+    /// Passed actix-ws a close frame in place of a message too big, nothing is read after it
+    closed: bool,
+}
+
+impl Stream for Inflating {
+    type Item = Result<Bytes, PayloadError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        loop {
+            if this.closed {
+                return Poll::Ready(None);
+            }
+            let mut out = BytesMut::new();
+            if let Err(err) = this.inflate_into(&mut out) {
+                if !matches!(err, PayloadError::Overflow) {
+                    return Poll::Ready(Some(Err(err)));
+                }
+                // The session closes the connection with the code of the close frame it reads
+                write_frame(&mut out, FIN | CLOSE, &MESSAGE_TOO_BIG.to_be_bytes(), true);
+                this.closed = true;
+            }
+            if !out.is_empty() {
+                return Poll::Ready(Some(Ok(out.freeze())));
+            }
+            match Pin::new(&mut this.payload).poll_next(cx) {
+                Poll::Ready(Some(Ok(bytes))) => this.buf.extend_from_slice(&bytes),
+                other => return other,
+            }
+        }
+    }
+}
+
+impl Inflating {
+    /// Append every whole frame buffered to `out`, inflated
+    fn inflate_into(&mut self, out: &mut BytesMut) -> Result<(), PayloadError> {
+        while let Some(frame) = read_frame(&mut self.buf, MAX_INFLATED)? {
+            let frame = self.inflater.inflate(frame)?;
+            write_frame(out, frame.head, &frame.payload, true);
+        }
+        Ok(())
+    }
+}
+
+/// Response body of a compressed connection, with its frames deflated
+struct Deflating {
+    body: BoxBody,
+    buf: BytesMut,
+    deflater: Deflater,
+}
+
+impl MessageBody for Deflating {
+    type Error = Box<dyn StdError>;
+
+    fn size(&self) -> BodySize {
+        BodySize::Stream
+    }
+
+    fn poll_next(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Bytes, Self::Error>>> {
+        let this = self.get_mut();
+        loop {
+            match Pin::new(&mut this.body).poll_next(cx) {
+                Poll::Ready(Some(Ok(bytes))) => this.buf.extend_from_slice(&bytes),
+                other => return other,
+            }
+            let mut out = BytesMut::new();
+            while let Some(frame) = read_frame(&mut this.buf, usize::MAX)? {
+                this.deflater.deflate(frame, &mut out)?;
+            }
+            if !out.is_empty() {
+                return Poll::Ready(Some(Ok(out.freeze())));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+
+    const TEXT: u8 = 0x1;
+
+    fn deflater(no_context_takeover: bool) -> Deflater {
+        Deflater {
+            min_size: 0,
+            ..Deflater::new(no_context_takeover)
+        }
+    }
+
+    /// The frames of one message, deflated and read back off the wire
+    fn deflate(deflater: &mut Deflater, frames: &[(u8, &[u8])]) -> Vec<Frame> {
+        let mut wire = BytesMut::new();
+        for (head, payload) in frames {
+            let frame = Frame {
+                head: *head,
+                payload: BytesMut::from(*payload),
+            };
+            deflater.deflate(frame, &mut wire).unwrap();
+        }
+        let mut out = Vec::new();
+        while let Some(frame) = read_frame(&mut wire, usize::MAX).unwrap() {
+            out.push(frame);
+        }
+        assert!(wire.is_empty());
+        out
+    }
+
+    fn inflate(inflater: &mut Inflater, frames: Vec<Frame>) -> Result<Vec<u8>, PayloadError> {
+        let mut message = Vec::new();
+        for frame in frames {
+            let frame = inflater.inflate(frame)?;
+            assert!(!frame.compressed());
+            message.extend_from_slice(&frame.payload);
+        }
+        Ok(message)
+    }
+
+    #[test]
+    fn frames_round_trip() {
+        for len in [0, 125, 126, 65_535, 65_536] {
+            for masked in [false, true] {
+                let payload = vec![7; len];
+                let mut buf = BytesMut::new();
+                write_frame(&mut buf, FIN | TEXT, &payload, masked);
+                let frame = read_frame(&mut buf, usize::MAX).unwrap().unwrap();
+                assert_eq!(frame.head, FIN | TEXT);
+                assert_eq!(&frame.payload[..], &payload[..]);
+                assert!(buf.is_empty());
+            }
+        }
+    }
+
+    #[test]
+    fn frames_wait_until_whole() {
+        let mut whole = BytesMut::new();
+        write_frame(&mut whole, FIN | TEXT, &[1; 300], true);
+        for len in 0..whole.len() {
+            let mut buf = BytesMut::from(&whole[..len]);
+            assert!(read_frame(&mut buf, usize::MAX).unwrap().is_none());
+            assert_eq!(buf.len(), len);
+        }
+    }
+
+    #[test]
+    fn masked_frames_are_unmasked() {
+        let key = [0x12, 0x34, 0x56, 0x78];
+        let mut buf = BytesMut::from(&[FIN | TEXT, MASKED | 5][..]);
+        buf.extend_from_slice(&key);
+        buf.extend(b"hello".iter().zip(key.iter().cycle()).map(|(b, k)| b ^ k));
+        let frame = read_frame(&mut buf, usize::MAX).unwrap().unwrap();
+        assert_eq!(&frame.payload[..], b"hello");
+    }
+
+    #[test]
+    fn frames_over_the_limit_are_refused() {
+        let mut buf = BytesMut::new();
+        write_frame(&mut buf, FIN | TEXT, &[0; 11], true);
+        assert!(matches!(
+            read_frame(&mut buf, 10),
+            Err(PayloadError::Overflow)
+        ));
+    }
+
+    #[test]
+    fn messages_round_trip() {
+        let mut deflater = deflater(false);
+        let mut inflater = Inflater::new(false);
+        let message = br#"{"type":"pixel_update","x":1,"y":2}"#.repeat(10);
+        let frames = deflate(&mut deflater, &[(FIN | TEXT, &message)]);
+        assert!(frames[0].compressed());
+        assert!(frames[0].payload.len() < message.len());
+        assert_eq!(inflate(&mut inflater, frames).unwrap(), message);
+    }
+
+    #[test]
+    fn fragmented_messages_round_trip() {
+        let mut deflater = deflater(false);
+        let mut inflater = Inflater::new(false);
+        let frames = deflate(
+            &mut deflater,
+            &[
+                (TEXT, b"first half, "),
+                (CONTINUATION, b"middle, "),
+                (FIN | CONTINUATION, b"second half"),
+            ],
+        );
+        assert_eq!(frames.len(), 3);
+        // Only the first frame of a message is marked
+        assert!(frames[0].compressed());
+        assert!(!frames[1].compressed() && !frames[2].compressed());
+        assert_eq!(
+            inflate(&mut inflater, frames).unwrap(),
+            b"first half, middle, second half"
+        );
+    }
+
+    #[test]
+    fn short_messages_are_sent_as_they_are() {
+        let mut deflater = Deflater {
+            min_size: 128,
+            ..Deflater::new(false)
+        };
+        let frames = deflate(&mut deflater, &[(FIN | TEXT, b"short")]);
+        assert!(!frames[0].compressed());
+        assert_eq!(&frames[0].payload[..], b"short");
+    }
+
+    #[test]
+    fn context_takeover() {
+        let message = br#"{"type":"pixel_update","x":1,"y":2,"colour":3}"#;
+        let mut deflater = deflater(false);
+        let mut inflater = Inflater::new(false);
+        let first = deflate(&mut deflater, &[(FIN | TEXT, message)]);
+        let second = deflate(&mut deflater, &[(FIN | TEXT, message)]);
+        // The second message refers back to the first
+        assert!(second[0].payload.len() < first[0].payload.len());
+        assert_eq!(inflate(&mut inflater, first).unwrap(), message);
+        assert_eq!(inflate(&mut inflater, second).unwrap(), message);
+    }
+
+    #[test]
+    fn no_context_takeover() {
+        let message = br#"{"type":"pixel_update","x":1,"y":2,"colour":3}"#;
+        let mut deflater = deflater(true);
+        let first = deflate(&mut deflater, &[(FIN | TEXT, message)]);
+        let second = deflate(&mut deflater, &[(FIN | TEXT, message)]);
+        assert_eq!(first[0].payload, second[0].payload);
+        // Each message inflates on its own
+        assert_eq!(inflate(&mut Inflater::new(true), second).unwrap(), message);
+
+        let mut inflater = Inflater::new(true);
+        assert_eq!(inflate(&mut inflater, first).unwrap(), message);
+        let third = deflate(&mut deflater, &[(FIN | TEXT, message)]);
+        assert_eq!(inflate(&mut inflater, third).unwrap(), message);
+    }
+
+    #[test]
+    fn oversized_messages_are_refused() {
+        let mut deflater = deflater(false);
+        let mut inflater = Inflater::new(false);
+        let frames = deflate(&mut deflater, &[(FIN | TEXT, &[0; MAX_INFLATED + 1])]);
+        assert!(matches!(
+            inflate(&mut inflater, frames),
+            Err(PayloadError::Overflow)
+        ));
+    }
+
+    #[test]
+    fn oversized_fragmented_messages_are_refused() {
+        let chunk = [0; MAX_INFLATED / 4];
+        let mut frames = vec![(TEXT, &chunk[..])];
+        frames.extend([(CONTINUATION, &chunk[..]); 3]);
+        frames.push((FIN | CONTINUATION, b"."));
+
+        let mut deflater = deflater(false);
+        let mut inflater = Inflater::new(false);
+        let deflated = deflate(&mut deflater, &frames);
+        // Every frame is well under the limit on its own
+        assert!(deflated.iter().all(|frame| frame.payload.len() < 1024));
+        assert!(matches!(
+            inflate(&mut inflater, deflated),
+            Err(PayloadError::Overflow)
+        ));
+
+        let mut inflater = Inflater::new(false);
+        let plain = frames
+            .iter()
+            .map(|(head, payload)| Frame {
+                head: *head,
+                payload: BytesMut::from(*payload),
+            })
+            .collect();
+        assert!(matches!(
+            inflate(&mut inflater, plain),
+            Err(PayloadError::Overflow)
+        ));
+    }
+
+    #[test]
+    fn oversized_messages_close_the_connection() {
+        let mut wire = BytesMut::new();
+        let mut deflater = deflater(false);
+        let chunk = [0; MAX_INFLATED / 2];
+        for (head, payload) in [
+            (FIN | TEXT, &b"fits"[..]),
+            (TEXT, &chunk[..]),
+            (CONTINUATION, &chunk[..]),
+            (FIN | CONTINUATION, &b"."[..]),
+            (FIN | TEXT, &b"after"[..]),
+        ] {
+            let frame = Frame {
+                head,
+                payload: BytesMut::from(payload),
+            };
+            deflater.deflate(frame, &mut wire).unwrap();
+        }
+        let chunks: Vec<Result<Bytes, PayloadError>> = vec![Ok(wire.freeze())];
+        let payload: Pin<Box<dyn Stream<Item = _>>> = Box::pin(futures::stream::iter(chunks));
+        let inflating = Inflating {
+            payload: Payload::from(payload),
+            buf: BytesMut::new(),
+            inflater: Inflater::new(false),
+            closed: false,
+        };
+
+        let mut out: BytesMut = futures::executor::block_on(inflating.collect::<Vec<_>>())
+            .into_iter()
+            .flat_map(Result::unwrap)
+            .collect();
+        let mut frames = Vec::new();
+        while let Some(frame) = read_frame(&mut out, usize::MAX).unwrap() {
+            frames.push(frame);
+        }
+        let last = frames.pop().unwrap();
+        assert_eq!(last.head, FIN | CLOSE);
+        assert_eq!(&last.payload[..], &MESSAGE_TOO_BIG.to_be_bytes());
+        assert_eq!(frames[0].head, FIN | TEXT);
+        assert_eq!(&frames[0].payload[..], b"fits");
+        // Frames after the close aren't read
+        assert!(frames.iter().all(|frame| &frame.payload[..] != b"after"));
+    }
+}
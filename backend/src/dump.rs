@@ -0,0 +1,166 @@
+//! Canvas import and export as CSV or JSON lines.
+//!
+//! Both formats hold one pixel per row with the columns `x`, `y`, `colour` and `updated`,
+//! the same layout as `canvas.csv` plus the time each pixel was written.
+
+use crate::canvas::{CanvasMeta, WriteError};
+use crate::pixel::Pixel;
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    #[default]
+    Csv,
+    Jsonl,
+}
+
+impl Format {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Format::Csv => "text/csv",
+            Format::Jsonl => "application/jsonl",
+        }
+    }
+
+    /// Guess the format from a file name, CSV unless it ends in `.jsonl`
+    pub fn from_path(path: &str) -> Format {
+        if path.ends_with(".jsonl") {
+            Format::Jsonl
+        } else {
+            Format::Csv
+        }
+    }
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(Format::Csv),
+            "jsonl" => Ok(Format::Jsonl),
+            other => Err(format!("unknown format {}, expected csv or jsonl", other)),
+        }
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Format::Csv => write!(f, "csv"),
+            Format::Jsonl => write!(f, "jsonl"),
+        }
+    }
+}
+
+/// One row of an import or export. Rows without `updated` are given the time of the import.
+/// `user` is only set in history exports and is ignored on import, imports aren't attributed
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+struct Record {
+    x: i32,
+    y: i32,
+    colour: i32,
+    #[serde(default)]
+    updated: Option<i32>,
+    #[serde(default)]
+    user: Option<String>,
+}
+
+/// A row of an import that couldn't be used, numbered from 1 including any header
+#[derive(Debug, serde::Serialize)]
+pub struct RowError {
+    pub line: usize,
+    #[serde(flatten)]
+    pub error: WriteError,
+}
+
+impl fmt::Display for RowError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.error)
+    }
+}
+
+pub fn write_pixels(format: Format, pixels: &[Pixel]) -> String {
+    let records = pixels.iter().map(|pixel| Record {
+        x: pixel.x,
+        y: pixel.y,
+        colour: pixel.colour,
+        updated: Some(pixel.updated),
+        user: pixel.user.clone(),
+    });
+    match format {
+        Format::Csv => {
+            let mut writer = csv::Writer::from_writer(Vec::new());
+            for record in records {
+                // Writing to a Vec can't fail
+                writer.serialize(record).unwrap();
+            }
+            String::from_utf8(writer.into_inner().unwrap()).unwrap()
+        }
+        Format::Jsonl => {
+            let mut out = String::new();
+            for record in records {
+                out.push_str(&serde_json::to_string(&record).unwrap());
+                out.push('\n');
+            }
+            out
+        }
+    }
+}
+
+/// Read the pixels of an import into `canvas`, checking each against its bounds and palette.
+/// Every bad row is reported rather than just the first
+pub fn read_pixels(
+    format: Format,
+    data: &str,
+    canvas: &CanvasMeta,
+    now: i32,
+) -> Result<Vec<Pixel>, Vec<RowError>> {
+    let records: Vec<(usize, Result<Record, String>)> = match format {
+        Format::Csv => csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_reader(data.as_bytes())
+            .deserialize::<Record>()
+            .enumerate()
+            // Line 1 is the header
+            .map(|(n, record)| (n + 2, record.map_err(|e| e.to_string())))
+            .collect(),
+        Format::Jsonl => data
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty())
+            .map(|(n, line)| (n + 1, serde_json::from_str(line).map_err(|e| e.to_string())))
+            .collect(),
+    };
+
+    let mut pixels = Vec::with_capacity(records.len());
+    let mut errors = Vec::new();
+    for (line, record) in records {
+        let result = record
+            .map_err(|message| WriteError::Malformed { message })
+            .and_then(|record| {
+                let pixel = Pixel {
+                    x: record.x,
+                    y: record.y,
+                    colour: record.colour,
+                    updated: record.updated.unwrap_or(now),
+                    canvas_id: canvas.id.clone(),
+                    user: None,
+                    seq: None,
+                };
+                canvas.validate(&pixel).map(|_| pixel)
+            });
+        match result {
+            Ok(pixel) => pixels.push(pixel),
+            Err(error) => errors.push(RowError { line, error }),
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(pixels)
+    } else {
+        Err(errors)
+    }
+}
@@ -0,0 +1,241 @@
+//! `GET /events`, a Server-Sent Events stream of a canvas for clients that only watch it, like
+//! dashboards or `curl -N`, and don't need a WebSocket.
+//!
+//! The stream is a session of the replica manager like any WebSocket connection, and gets the
+//! same messages as version 2 of `protocol`: each event is named after the `type` of the message
+//! and carries it as its data. `pixel_update`s have their `seq` as their event id and
+//! `snapshot`s the `seq` of the newest write they hold. `resumed` has none: it comes ahead of
+//! the writes replayed, and a stream dropped partway through has to resume from the last of
+//! those it got.
+//!
+//! A client reconnecting with `Last-Event-ID`, as `EventSource` does on its own, or with
+//! `?since=<seq>` is sent the writes it missed first, see `replay`. A stream that falls too far
+//! behind gets `resync_required` and is ended, see `session_queue`.
+//!
+//! Only the primary sends writes to its sessions, so watchers should follow `leader` events to
+//! the new primary, as WebSocket clients do.
+
+use crate::protocol::{self, ServerMessage};
+use crate::session_queue::{self, SessionReceiver};
+use crate::{canvas, unknown_canvas, CanvasId, ReplicaHandle};
+use actix_web::http::header::{CacheControl, CacheDirective};
+use actix_web::web::Bytes;
+use actix_web::{get, web, Error, HttpRequest, HttpResponse};
+use std::time::Duration;
+use tokio::time::{interval_at, Instant, Interval};
+
+/// How often a comment is sent to keep idle streams open through proxies
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+#[derive(Debug, serde::Deserialize)]
+pub struct EventsQuery {
+    canvas: Option<CanvasId>,
+    /// Resume after this `seq`, for clients that can't set `Last-Event-ID`
+    since: Option<u64>,
+}
+
+#[get("/events")]
+pub async fn events(
+    req: HttpRequest,
+    replica_handle: web::Data<ReplicaHandle>,
+    canvases: web::Data<canvas::CanvasRegistry>,
+    query: web::Query<EventsQuery>,
+) -> HttpResponse {
+    let query = query.into_inner();
+    let canvas = query.canvas.unwrap_or_else(canvas::default_canvas_id);
+    if canvases.get(&canvas).is_none() {
+        return unknown_canvas(&canvas);
+    }
+    let since = req
+        .headers()
+        .get("Last-Event-ID")
+        .and_then(|id| id.to_str().ok())
+        .and_then(|id| id.trim().parse().ok())
+        .or(query.since);
+
+    let (conn_tx, conn_rx) = session_queue::channel();
+    let conn = replica_handle.connect(canvas.clone(), conn_tx).await;
+    log::info!("Event stream {} opened on canvas {}", conn, canvas);
+    if let Some(since) = since {
+        replica_handle.resume(canvas.clone(), conn, since, None);
+    }
+
+    let hello = ServerMessage::Hello {
+        version: protocol::VERSION,
+        canvas: canvas.clone(),
+    };
+    let stream = EventStream {
+        replica_handle: (**replica_handle).clone(),
+        canvas,
+        conn,
+        conn_rx,
+        keepalive: interval_at(Instant::now() + KEEPALIVE_INTERVAL, KEEPALIVE_INTERVAL),
+        pending: Some(event(&hello)),
+        ended: false,
+    };
+
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(CacheControl(vec![CacheDirective::NoCache]))
+        .streaming(futures::stream::unfold(stream, EventStream::next))
+}
+
+/// An open stream, leaves the replica manager once the client is gone
+struct EventStream {
+    replica_handle: ReplicaHandle,
+    canvas: CanvasId,
+    conn: usize,
+    conn_rx: SessionReceiver,
+    keepalive: Interval,
+    /// Sent before anything else
+    pending: Option<Bytes>,
+    /// Sent `resync_required`
+    ended: bool,
+}
+
+impl EventStream {
+    async fn next(mut self) -> Option<(Result<Bytes, Error>, EventStream)> {
+        if let Some(pending) = self.pending.take() {
+            return Some((Ok(pending), self));
+        }
+        if self.ended {
+            return None;
+        }
+        loop {
+            tokio::select! {
+                msg = self.conn_rx.recv() => {
+                    let msg = msg?;
+                    if msg == session_queue::RESYNC_REQUIRED {
+                        self.ended = true;
+                        return Some((Ok(event(&ServerMessage::ResyncRequired)), self));
+                    }
+                    if let Some(msg) = ServerMessage::from_manager(&msg) {
+                        return Some((Ok(event(&msg)), self));
+                    }
+                }
+                _ = self.keepalive.tick() => {
+                    return Some((Ok(Bytes::from_static(b":\n\n")), self));
+                }
+            }
+        }
+    }
+}
+
+impl Drop for EventStream {
+    fn drop(&mut self) {
+        log::info!("Event stream {} closed", self.conn);
+        self.replica_handle
+            .disconnect(self.canvas.clone(), self.conn);
+    }
+}
+
+/// The `type` of a message
+#[derive(serde::Deserialize)]
+struct Tagged<'a> {
+    #[serde(rename = "type")]
+    name: &'a str,
+}
+
+/// The event for a message, named after its `type`
+fn event(msg: &ServerMessage) -> Bytes {
+    let data = msg.to_text();
+    let name = serde_json::from_str::<Tagged>(&data).map_or("message", |tagged| tagged.name);
+    let id = match msg {
+        ServerMessage::PixelUpdate { pixel } => pixel.seq,
+        ServerMessage::Snapshot(resync) => Some(resync.seq),
+        _ => None,
+    };
+    let mut event = String::new();
+    if let Some(id) = id {
+        event.push_str(&format!("id: {}\n", id));
+    }
+    event.push_str(&format!("event: {}\ndata: {}\n\n", name, data));
+    Bytes::from(event)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::canvas::CanvasMeta;
+    use crate::pixel::Pixel;
+    use crate::protocol::{Resumed, Resync};
+
+    /// The fields of an event, in order
+    fn fields(event: &Bytes) -> Vec<(String, String)> {
+        let event = std::str::from_utf8(event).unwrap();
+        let body = event
+            .strip_suffix("\n\n")
+            .expect("events end with a blank line");
+        body.lines()
+            .map(|line| {
+                let (field, value) = line.split_once(": ").unwrap();
+                (field.to_string(), value.to_string())
+            })
+            .collect()
+    }
+
+    fn field_names(event: &Bytes) -> Vec<String> {
+        fields(event).into_iter().map(|(field, _)| field).collect()
+    }
+
+    #[test]
+    fn pixel_updates_have_their_seq_as_id() {
+        let msg = ServerMessage::PixelUpdate {
+            pixel: Pixel {
+                seq: Some(42),
+                ..Pixel::test(1, 2, 3)
+            },
+        };
+        let fields = fields(&event(&msg));
+        assert_eq!(fields[0], ("id".to_string(), "42".to_string()));
+        assert_eq!(fields[1], ("event".to_string(), "pixel_update".to_string()));
+        assert_eq!(fields[2], ("data".to_string(), msg.to_text()));
+        assert_eq!(fields.len(), 3);
+    }
+
+    #[test]
+    fn unnumbered_messages_have_no_id() {
+        let hello = ServerMessage::Hello {
+            version: protocol::VERSION,
+            canvas: "default".to_string(),
+        };
+        assert_eq!(field_names(&event(&hello)), ["event", "data"]);
+        assert_eq!(fields(&event(&hello))[0].1, "hello");
+
+        let update = ServerMessage::PixelUpdate {
+            pixel: Pixel::test(1, 2, 3),
+        };
+        assert_eq!(field_names(&event(&update)), ["event", "data"]);
+
+        assert_eq!(
+            fields(&event(&ServerMessage::ResyncRequired))[0],
+            ("event".to_string(), "resync_required".to_string())
+        );
+    }
+
+    #[test]
+    fn snapshots_have_the_newest_seq_as_id() {
+        let snapshot = ServerMessage::Snapshot(Resync {
+            id: None,
+            seq: 7,
+            canvas: CanvasMeta::default(),
+            pixels: vec![Pixel::test(1, 2, 3)],
+        });
+        let fields = fields(&event(&snapshot));
+        assert_eq!(fields[0], ("id".to_string(), "7".to_string()));
+        assert_eq!(fields[1], ("event".to_string(), "snapshot".to_string()));
+    }
+
+    #[test]
+    fn resumed_has_no_id() {
+        let resumed = ServerMessage::Resumed(Resumed {
+            id: None,
+            since: 3,
+            seq: 9,
+            replayed: 6,
+        });
+        // A stream dropped during the replay resumes from the last write it got, not from 9
+        assert_eq!(field_names(&event(&resumed)), ["event", "data"]);
+        assert_eq!(fields(&event(&resumed))[0].1, "resumed");
+    }
+}
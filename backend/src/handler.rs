@@ -1,14 +1,22 @@
-use crate::Msg;
-use crate::ReplicaHandle;
-use actix_web::web;
-use actix_ws::Message;
-use deadpool_postgres::Pool;
+use crate::auth::Author;
+use crate::batch::{Batch, BatchFormat};
+use crate::canvas::WriteError;
+use crate::protocol::{
+    self, ClientMessage, Encoding, FailedWrite, Origin, Protocol, ReplicatedWrite, RequestId,
+    Resumed, Resync, ServerMessage,
+};
+use crate::session_queue;
+use crate::{cooldown, CanvasId, ConnId, ReplicaHandle};
+use actix_ws::{CloseCode, CloseReason, Message};
 use futures_util::{
     future::{select, Either},
     StreamExt as _,
 };
 use std::time::{Duration, Instant};
-use tokio::{pin, sync::mpsc, time::interval};
+use tokio::{
+    pin,
+    time::{interval, sleep_until},
+};
 
 /// How often heartbeat pings are sent.
 ///
@@ -18,23 +26,56 @@ const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(2);
 /// How long before lack of client response causes a timeout.
 const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
 
+/// How to talk to a connection, from its upgrade request
+#[derive(Debug, Clone, Copy)]
+pub struct WsOptions {
+    pub protocol: Protocol,
+    pub encoding: Encoding,
+    pub batch: Option<BatchFormat>,
+    /// The connection comes from a trusted proxy, whose writes each name the client they were
+    /// made for
+    pub proxy: bool,
+}
+
 /// Echo text & binary messages received from the client, respond to ping messages, and monitor
 /// connection health to detect network issues and free up resources.
 pub async fn canvas_ws(
     replica_handle: ReplicaHandle,
     mut session: actix_ws::Session,
     mut msg_stream: actix_ws::MessageStream,
-    pool: web::Data<Pool>,
+    canvas: CanvasId,
+    author: Author,
+    options: WsOptions,
 ) {
-    log::info!("WS connected");
+    let WsOptions {
+        protocol,
+        encoding,
+        batch,
+        proxy,
+    } = options;
+    log::info!(
+        "WS connected to canvas {} with {:?} and {:?} encoding",
+        canvas,
+        protocol,
+        encoding
+    );
+    let mut batch = batch.map(Batch::new);
 
     let mut last_heartbeat = Instant::now();
     let mut interval = interval(HEARTBEAT_INTERVAL);
 
-    let (conn_tx, mut conn_rx) = mpsc::unbounded_channel::<Msg>();
+    let (conn_tx, mut conn_rx) = session_queue::channel();
 
     // unwrap: manager is not dropped before the HTTP server
-    let conn_id = replica_handle.connect(conn_tx).await;
+    let conn_id = replica_handle.connect(canvas.clone(), conn_tx).await;
+
+    if protocol == Protocol::V2 {
+        let hello = ServerMessage::Hello {
+            version: protocol::VERSION,
+            canvas: canvas.clone(),
+        };
+        let _ = session.text(hello.to_text()).await;
+    }
 
     let close_reason = loop {
         // most of the futures we process need to be stack-pinned to work with select()
@@ -42,6 +83,17 @@ pub async fn canvas_ws(
         let tick = interval.tick();
         pin!(tick);
 
+        // Send the waiting batch once its window is up
+        let deadline = batch.as_ref().and_then(Batch::deadline);
+        let flush = async move {
+            match deadline {
+                Some(deadline) => sleep_until(deadline).await,
+                None => std::future::pending().await,
+            }
+        };
+        pin!(flush);
+        let timers = select(tick, flush);
+
         let msg_rx = conn_rx.recv();
         pin!(msg_rx);
 
@@ -49,36 +101,111 @@ pub async fn canvas_ws(
         let messages = select(msg_stream.next(), msg_rx);
         pin!(messages);
 
-        match select(messages, tick).await {
+        match select(messages, timers).await {
             // commands & messages received from client
-            Either::Left((Either::Left((Some(Ok(msg)), _)), _)) => {
-                match msg {
-                    Message::Ping(bytes) => {
-                        last_heartbeat = Instant::now();
-                        // unwrap:
-                        session.pong(&bytes).await.unwrap();
+            Either::Left((Either::Left((Some(Ok(msg)), _)), _)) => match msg {
+                Message::Ping(bytes) => {
+                    last_heartbeat = Instant::now();
+                    if session.pong(&bytes).await.is_err() {
+                        break None;
                     }
+                }
 
-                    Message::Pong(_) => {
-                        last_heartbeat = Instant::now();
-                    }
+                Message::Pong(_) => {
+                    last_heartbeat = Instant::now();
+                }
 
-                    Message::Text(text) => {
-                        log::debug!("msg: {text:?}");
-                        replica_handle.send_message(text).await
+                Message::Text(text) if protocol == Protocol::Legacy => {
+                    log::debug!("msg: {text:?}");
+                    match protocol::parse_legacy_resume(&text) {
+                        Some(Ok(since)) => {
+                            replica_handle.resume(canvas.clone(), conn_id, since, None);
+                            continue;
+                        }
+                        Some(Err(e)) => {
+                            let err = WriteError::Malformed {
+                                message: format!("resume: {}", e),
+                            };
+                            let _ = session.text(err.to_ws_message()).await;
+                            continue;
+                        }
+                        None => {}
                     }
+                    let proxied = proxy.then(|| cooldown::proxied_write(&text)).flatten();
+                    let (write, writer, client) = match proxied {
+                        Some((write, client)) => {
+                            let writer = Author {
+                                user: author.user.clone(),
+                                identity: cooldown::identity(author.user.as_deref(), &client),
+                                address: client.clone(),
+                            };
+                            (write.into(), writer, Some(client))
+                        }
+                        None => (text, author.clone(), None),
+                    };
+                    if let Err(err) = replica_handle
+                        .send_message(
+                            canvas.clone(),
+                            write,
+                            Some(writer),
+                            Some(Origin {
+                                conn: conn_id,
+                                id: None,
+                            }),
+                        )
+                        .await
+                    {
+                        log::info!("Write rejected: {}", err);
+                        let reply = err.to_ws_message();
+                        let reply = match &client {
+                            Some(client) => cooldown::to_client(&reply, client),
+                            None => reply,
+                        };
+                        let _ = session.text(reply).await;
+                    }
+                }
 
-                    Message::Binary(_bin) => {
-                        log::warn!("unexpected binary message");
+                Message::Text(text) => {
+                    log::debug!("msg: {text:?}");
+                    let reply =
+                        handle_request(&replica_handle, &canvas, &author, conn_id, &text).await;
+                    if let Some(reply) = reply {
+                        let _ = session.text(reply.to_text()).await;
                     }
+                }
 
-                    Message::Close(reason) => break reason,
+                Message::Binary(bin) if encoding == Encoding::Binary => {
+                    handle_binary_writes(
+                        &replica_handle,
+                        &mut session,
+                        &canvas,
+                        &author,
+                        conn_id,
+                        protocol,
+                        &bin,
+                    )
+                    .await;
+                }
 
-                    _ => {
-                        break None;
-                    }
+                Message::Binary(_bin) if protocol == Protocol::V2 => {
+                    let reply = ServerMessage::Error {
+                        id: None,
+                        code: "unsupported",
+                        message: "binary frames need ?encoding=binary".to_string(),
+                    };
+                    let _ = session.text(reply.to_text()).await;
+                }
+
+                Message::Binary(_bin) => {
+                    log::warn!("unexpected binary message");
                 }
-            }
+
+                Message::Close(reason) => break reason,
+
+                _ => {
+                    break None;
+                }
+            },
 
             // client WebSocket stream error
             Either::Left((Either::Left((Some(Err(err)), _)), _)) => {
@@ -89,19 +216,113 @@ pub async fn canvas_ws(
             // client WebSocket stream ended
             Either::Left((Either::Left((None, _)), _)) => break None,
 
+            // Fell too far behind, see `session_queue`
+            Either::Left((Either::Right((Some(msg), _)), _))
+                if msg == session_queue::RESYNC_REQUIRED =>
+            {
+                log::info!("WS session {} fell behind, closing it", conn_id);
+                let frame = match protocol {
+                    Protocol::V2 => ServerMessage::ResyncRequired.to_text(),
+                    Protocol::Legacy => protocol::legacy_resync_required(),
+                };
+                let _ = session.text(frame).await;
+                break Some(CloseReason {
+                    code: CloseCode::Again,
+                    description: Some(session_queue::RESYNC_REQUIRED.to_string()),
+                });
+            }
+
+            // Writes of other connections wait in the batch
+            Either::Left((Either::Right((Some(msg), _)), _))
+                if batch.is_some() && msg.starts_with("replicated: ") =>
+            {
+                let pixel = msg.trim_start_matches("replicated: ");
+                match serde_json::from_str(pixel) {
+                    Ok(pixel) => {
+                        if let Some(batch) = batch.as_mut() {
+                            if batch.push(pixel) {
+                                send_batch(&mut session, protocol, batch).await;
+                            }
+                        }
+                    }
+                    Err(e) => log::error!("Unreadable replicated pixel {}: {}", pixel, e),
+                }
+            }
+
+            // Writes are sent as binary frames with `?encoding=binary`
+            Either::Left((Either::Right((Some(msg), _)), _))
+                if encoding == Encoding::Binary && protocol::has_binary_form(&msg) =>
+            {
+                if let Some(batch) = batch.as_mut() {
+                    send_batch(&mut session, protocol, batch).await;
+                }
+                match protocol::binary_from_manager(&msg) {
+                    Some(frame) => {
+                        let _ = session.binary(frame).await;
+                    }
+                    None => log::error!("Unreadable msg from replica manager {}", msg),
+                }
+            }
+
             // Got a message from the replica manager. Are we the new primary?
+            Either::Left((Either::Right((Some(msg), _)), _)) if protocol == Protocol::V2 => {
+                log::info!("Message received from replica manager {}", msg);
+                if let Some(batch) = batch.as_mut() {
+                    send_batch(&mut session, protocol, batch).await;
+                }
+                if let Some(frame) = ServerMessage::from_manager(&msg) {
+                    let _ = session.text(frame.to_text()).await;
+                }
+            }
+
             Either::Left((Either::Right((Some(msg), _)), _)) => {
                 log::info!("Message received from replica manager {}", msg);
+                if let Some(batch) = batch.as_mut() {
+                    send_batch(&mut session, protocol, batch).await;
+                }
                 if msg == "primary" {
                     log::info!("Sending primary message to ws connection");
-                    session.text("primary").await.unwrap();
+                    if session.text("primary").await.is_err() {
+                        break None;
+                    }
                 } else if msg.starts_with("replicated") {
                     let payload = msg.trim_start_matches("replicated: ");
                     log::info!("Sending replicated message to ws connection");
-                    session.text(payload).await.unwrap();
-                } else if msg.starts_with("unreplicated") {
-                    log::info!("Received unreplicated message from replica");
-                    // session.text(payload).await.unwrap();
+                    if session.text(payload).await.is_err() {
+                        break None;
+                    }
+                } else if msg.starts_with("leader: ") {
+                    // Version 1 is only told when this replica becomes the primary
+                } else if let Some(resumed) = msg.strip_prefix("resumed: ") {
+                    match serde_json::from_str::<Resumed>(resumed) {
+                        Ok(resumed) => {
+                            let _ = session.text(resumed.to_legacy_message()).await;
+                        }
+                        Err(e) => log::error!("Unreadable resume {}: {}", resumed, e),
+                    }
+                } else if let Some(resync) = msg.strip_prefix("snapshot: ") {
+                    match serde_json::from_str::<Resync>(resync) {
+                        Ok(resync) => {
+                            let _ = session.text(resync.to_legacy_message()).await;
+                        }
+                        Err(e) => log::error!("Unreadable snapshot {}: {}", resync, e),
+                    }
+                } else if let Some(written) = msg.strip_prefix("written: ") {
+                    log::info!("Sending replicated write to ws connection");
+                    match serde_json::from_str::<ReplicatedWrite>(written) {
+                        Ok(written) => {
+                            let _ = session.text(written.to_legacy_message()).await;
+                        }
+                        Err(e) => log::error!("Unreadable replicated write {}: {}", written, e),
+                    }
+                } else if let Some(failed) = msg.strip_prefix("unreplicated: ") {
+                    log::info!("Sending unreplicated message to ws connection");
+                    match serde_json::from_str::<FailedWrite>(failed) {
+                        Ok(failed) => {
+                            let _ = session.text(failed.to_legacy_message()).await;
+                        }
+                        Err(e) => log::error!("Unreadable unreplicated write {}: {}", failed, e),
+                    }
                 } else {
                     log::error!("Unrecognized msg from replica manager {}", msg);
                 }
@@ -113,8 +334,15 @@ pub async fn canvas_ws(
                 break None; // Deal with this better
             }
 
+            // batch window is up
+            Either::Right((Either::Right(_), _)) => {
+                if let Some(batch) = batch.as_mut() {
+                    send_batch(&mut session, protocol, batch).await;
+                }
+            }
+
             // heartbeat internal tick
-            Either::Right((_inst, _)) => {
+            Either::Right((Either::Left(_), _)) => {
                 // if no heartbeat ping/pong received recently, close the connection
                 if Instant::now().duration_since(last_heartbeat) > CLIENT_TIMEOUT {
                     log::info!(
@@ -129,8 +357,159 @@ pub async fn canvas_ws(
         };
     };
 
-    replica_handle.disconnect(conn_id);
+    replica_handle.disconnect(canvas, conn_id);
 
     // attempt to close connection gracefully
     let _ = session.close(close_reason).await;
 }
+
+/// Write the pixels of a binary frame, see `protocol`
+async fn handle_binary_writes(
+    replica_handle: &ReplicaHandle,
+    session: &mut actix_ws::Session,
+    canvas: &str,
+    author: &Author,
+    conn_id: ConnId,
+    protocol: Protocol,
+    frame: &[u8],
+) {
+    let records = match protocol::decode_set_pixels(frame) {
+        Ok(records) => records,
+        Err(message) => {
+            log::info!("Unreadable binary frame: {}", message);
+            let reply = match protocol {
+                Protocol::V2 => ServerMessage::Error {
+                    id: None,
+                    code: "malformed",
+                    message,
+                }
+                .to_text(),
+                Protocol::Legacy => WriteError::Malformed { message }.to_ws_message(),
+            };
+            let _ = session.text(reply).await;
+            return;
+        }
+    };
+
+    for (index, (x, y, colour)) in records.into_iter().enumerate() {
+        let msg = protocol::pixel_write(x, y, colour, None, canvas);
+        let origin = Origin {
+            conn: conn_id,
+            id: None,
+        };
+        if let Err(error) = replica_handle
+            .send_message(canvas.to_string(), msg, Some(author.clone()), Some(origin))
+            .await
+        {
+            log::info!("Write rejected: {}", error);
+            let reply = match protocol {
+                Protocol::V2 => ServerMessage::Nack {
+                    id: Some(RequestId::Number(index as u64)),
+                    error,
+                }
+                .to_text(),
+                Protocol::Legacy => error.to_ws_message(),
+            };
+            let _ = session.text(reply).await;
+        }
+    }
+}
+
+/// Send the writes waiting in the batch, if any
+async fn send_batch(session: &mut actix_ws::Session, protocol: Protocol, batch: &mut Batch) {
+    let pixels = batch.take();
+    if pixels.is_empty() {
+        return;
+    }
+    log::info!("Sending batch of {} pixels to ws connection", pixels.len());
+    let _ = match batch.format {
+        BatchFormat::Binary => session.binary(protocol::encode_batch(&pixels)).await,
+        BatchFormat::Json if protocol == Protocol::V2 => {
+            let frame = ServerMessage::PixelBatch {
+                seq: protocol::newest_seq(&pixels),
+                pixels,
+            };
+            session.text(frame.to_text()).await
+        }
+        BatchFormat::Json => session.text(protocol::legacy_batch(&pixels)).await,
+    };
+}
+
+/// Answer a version 2 request. None when the replica manager answers through the connection's
+/// channel instead
+async fn handle_request(
+    replica_handle: &ReplicaHandle,
+    canvas: &str,
+    author: &Author,
+    conn_id: ConnId,
+    text: &str,
+) -> Option<ServerMessage> {
+    let reply = match ClientMessage::parse(text) {
+        Ok(ClientMessage::SetPixel {
+            id,
+            x,
+            y,
+            colour,
+            updated,
+        }) => {
+            let msg = protocol::pixel_write(x, y, colour, updated, canvas);
+            let origin = Origin {
+                conn: conn_id,
+                id: id.clone(),
+            };
+            match replica_handle
+                .send_message(canvas.to_string(), msg, Some(author.clone()), Some(origin))
+                .await
+            {
+                Ok(()) => ServerMessage::Ack { id },
+                Err(error) => {
+                    log::info!("Write rejected: {}", error);
+                    ServerMessage::Nack { id, error }
+                }
+            }
+        }
+        Ok(ClientMessage::Ping { id }) => ServerMessage::Pong { id },
+        Ok(ClientMessage::Subscribe { id, area }) => {
+            let region = area.region();
+            match replica_handle
+                .subscribe(canvas.to_string(), conn_id, region)
+                .await
+            {
+                Ok(()) => ServerMessage::Subscribed { id, region },
+                Err(err) => ServerMessage::Error {
+                    id,
+                    code: "invalid_region",
+                    message: err.to_string(),
+                },
+            }
+        }
+        Ok(ClientMessage::Unsubscribe { id, area }) => {
+            let region = area.region();
+            if replica_handle
+                .unsubscribe(canvas.to_string(), conn_id, region)
+                .await
+            {
+                ServerMessage::Unsubscribed { id, region }
+            } else {
+                ServerMessage::Error {
+                    id,
+                    code: "not_subscribed",
+                    message: "not subscribed to the region".to_string(),
+                }
+            }
+        }
+        Ok(ClientMessage::Resume { id, since }) => {
+            replica_handle.resume(canvas.to_string(), conn_id, since, id);
+            return None;
+        }
+        Err(err) => {
+            log::info!("Unreadable frame {}: {}", text, err.message);
+            ServerMessage::Error {
+                id: err.id,
+                code: "malformed",
+                message: err.message,
+            }
+        }
+    };
+    Some(reply)
+}
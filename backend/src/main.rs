@@ -1,7 +1,6 @@
 use actix_web::{
-    web::Json, get, post, middleware::Logger, web, App, Error, HttpRequest, HttpResponse, HttpServer, rt,
+    web::Json, get, post, put, delete, middleware::{from_fn, Logger}, web, App, Error, HttpRequest, HttpResponse, HttpServer, rt,
 };
-use deadpool_postgres::Pool;
 use actix_cors::Cors;
 use tokio::{
     task::spawn,
@@ -9,13 +8,33 @@ use tokio::{
     sync::mpsc
 };
 mod postgres;
+mod migrate;
 mod pixel;
+mod canvas;
 mod handler;
+mod store;
+mod dump;
+mod admin;
+mod cli;
+mod bench;
+mod snapshot;
+mod cooldown;
+mod auth;
+mod moderation;
+mod audit;
+mod protocol;
+mod subscriptions;
+mod replay;
+mod batch;
+mod session_queue;
+mod deflate;
+mod events;
 use serde_json::json;
 
 mod replica_manager;
 
-pub use self::replica_manager::{ReplicaHandle, ReplicaManager};
+pub use self::replica_manager::{ReplicaHandle, ReplicaManager, WriteOutcome};
+use self::store::CanvasStore;
 
 /// Connection ID.
 pub type ConnId = usize;
@@ -23,55 +42,443 @@ pub type ConnId = usize;
 /// Message sent to a replica.
 pub type Msg = String;
 
+/// Name of a canvas.
+pub type CanvasId = String;
+
+/// Pixels of a canvas in the message format the proxy forwards to clients
+async fn canvas_pixels(store: &dyn CanvasStore, meta: canvas::CanvasMeta) -> HttpResponse {
+    match store.canvas_pixels(&meta.id).await {
+        Ok(list) => HttpResponse::Ok().json(json!({
+            "command": "get_pixels",
+            "payload": list,
+            "canvas": meta,
+        })),
+        Err(err) => {
+            log::debug!("unable to fetch pixels: {:?}", err);
+            HttpResponse::InternalServerError().json("unable to fetch pixels")
+        }
+    }
+}
+
+fn error_response(err: canvas::WriteError) -> HttpResponse {
+    let body = json!({
+        "command": "error",
+        "payload": err,
+    });
+    match err {
+        canvas::WriteError::UnknownCanvas { .. } => HttpResponse::NotFound().json(body),
+        canvas::WriteError::Cooldown { remaining_ms } => HttpResponse::TooManyRequests()
+            .insert_header(("Retry-After", remaining_ms.div_ceil(1000).to_string()))
+            .json(json!({ "command": "cooldown", "payload": err })),
+        canvas::WriteError::Locked { .. } | canvas::WriteError::Banned => HttpResponse::Forbidden().json(body),
+        _ => HttpResponse::BadRequest().json(body),
+    }
+}
+
+fn auth_error_response(err: auth::AuthError) -> HttpResponse {
+    let body = json!({
+        "command": "error",
+        "payload": err,
+    });
+    match err {
+        auth::AuthError::InvalidName { .. } | auth::AuthError::WeakPassword { .. } => HttpResponse::BadRequest().json(body),
+        auth::AuthError::NameTaken { .. } => HttpResponse::Conflict().json(body),
+        auth::AuthError::NotPrimary { .. } | auth::AuthError::Unavailable => HttpResponse::ServiceUnavailable().json(body),
+        auth::AuthError::Forbidden => HttpResponse::Forbidden().json(body),
+        _ => HttpResponse::Unauthorized().json(body),
+    }
+}
+
+fn unknown_canvas(id: &str) -> HttpResponse {
+    error_response(canvas::WriteError::UnknownCanvas {
+        canvas: id.to_string(),
+    })
+}
+
 #[get("/canvas")]
-async fn get_pixels(pool: web::Data<Pool>) -> HttpResponse {
-    let client = match pool.get().await {
-        Ok(client) => client,
+async fn get_pixels(store: web::Data<dyn CanvasStore>, canvases: web::Data<canvas::CanvasRegistry>) -> HttpResponse {
+    match canvases.get(canvas::DEFAULT_CANVAS) {
+        Some(meta) => canvas_pixels(&**store, meta).await,
+        None => unknown_canvas(canvas::DEFAULT_CANVAS),
+    }
+}
+
+#[get("/canvases")]
+async fn list_canvases(canvases: web::Data<canvas::CanvasRegistry>) -> HttpResponse {
+    HttpResponse::Ok().json(canvases.all())
+}
+
+#[post("/canvases", wrap = "from_fn(admin::authorize)")]
+async fn create_canvas(replica_handle: web::Data<ReplicaHandle>, data: Json<canvas::CanvasMeta>) -> HttpResponse {
+    let meta = data.into_inner();
+    match replica_handle.update_canvas(meta.clone()).await {
+        Ok(()) => HttpResponse::Created().json(meta),
+        Err(err) => error_response(err),
+    }
+}
+
+#[get("/canvases/{id}")]
+async fn get_canvas(canvases: web::Data<canvas::CanvasRegistry>, path: web::Path<String>) -> HttpResponse {
+    match canvases.get(&path) {
+        Some(meta) => HttpResponse::Ok().json(meta),
+        None => unknown_canvas(&path),
+    }
+}
+
+#[get("/canvases/{id}/pixels")]
+async fn get_canvas_pixels(store: web::Data<dyn CanvasStore>, canvases: web::Data<canvas::CanvasRegistry>, path: web::Path<String>) -> HttpResponse {
+    match canvases.get(&path) {
+        Some(meta) => canvas_pixels(&**store, meta).await,
+        None => unknown_canvas(&path),
+    }
+}
+
+#[get("/canvases/{id}/snapshots")]
+async fn list_snapshots(store: web::Data<dyn CanvasStore>, canvases: web::Data<canvas::CanvasRegistry>, path: web::Path<String>) -> HttpResponse {
+    if canvases.get(&path).is_none() {
+        return unknown_canvas(&path);
+    }
+    match store.snapshots(&path).await {
+        Ok(list) => HttpResponse::Ok().json(list),
         Err(err) => {
-            log::debug!("unable to get postgres client: {:?}", err);
-            return HttpResponse::InternalServerError().json("unable to get postgres client");
+            log::debug!("unable to fetch snapshots: {:?}", err);
+            HttpResponse::InternalServerError().json("unable to fetch snapshots")
         }
+    }
+}
+
+/// Pixels of a checkpoint in the same format as the current pixels of a canvas
+#[get("/canvases/{id}/snapshots/{snapshot}")]
+async fn get_snapshot(store: web::Data<dyn CanvasStore>, canvases: web::Data<canvas::CanvasRegistry>, path: web::Path<(String, i64)>) -> HttpResponse {
+    let (id, snapshot_id) = path.into_inner();
+    let meta = match canvases.get(&id) {
+        Some(meta) => meta,
+        None => return unknown_canvas(&id),
     };
-    match pixel::Pixel::all(&**client).await {
-        Ok(list) => HttpResponse::Ok().json(json!({
+    match store.snapshot(&id, snapshot_id).await {
+        Ok(Some(snapshot)) => HttpResponse::Ok().json(json!({
             "command": "get_pixels",
-            "payload": list,
+            "snapshot": snapshot.info(),
+            "payload": snapshot.pixels,
+            "canvas": meta,
+        })),
+        Ok(None) => HttpResponse::NotFound().json(json!({
+            "command": "error",
+            "payload": {"code": "unknown_snapshot", "snapshot": snapshot_id},
         })),
         Err(err) => {
-            log::debug!("unable to fetch pixels: {:?}", err);
-            return HttpResponse::InternalServerError().json("unable to fetch pixels");
+            log::debug!("unable to fetch snapshot: {:?}", err);
+            HttpResponse::InternalServerError().json("unable to fetch snapshot")
         }
     }
 }
 
+fn palette_response(meta: &canvas::CanvasMeta) -> serde_json::Value {
+    json!({
+        "colours": meta.palette,
+        "indexed": meta.indexed,
+    })
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct PaletteUpdate {
+    colours: Vec<i32>,
+    indexed: Option<bool>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct PaletteColour {
+    colour: i32,
+}
+
+/// Apply a change to a canvas palette on every replica
+async fn change_palette(
+    replica_handle: &ReplicaHandle, canvases: &canvas::CanvasRegistry, id: &str,
+    created: bool, change: impl FnOnce(&mut canvas::CanvasMeta) -> Result<(), canvas::WriteError>) -> HttpResponse {
+    let mut meta = match canvases.get(id) {
+        Some(meta) => meta,
+        None => return unknown_canvas(id),
+    };
+    if let Err(err) = change(&mut meta) {
+        return error_response(err);
+    }
+    match replica_handle.update_canvas(meta.clone()).await {
+        Ok(()) if created => HttpResponse::Created().json(palette_response(&meta)),
+        Ok(()) => HttpResponse::Ok().json(palette_response(&meta)),
+        Err(err) => error_response(err),
+    }
+}
+
+fn no_palette_entry(index: usize) -> canvas::WriteError {
+    canvas::WriteError::InvalidCanvas {
+        message: format!("palette has no colour at index {}", index),
+    }
+}
+
+#[get("/canvases/{id}/palette")]
+async fn get_palette(canvases: web::Data<canvas::CanvasRegistry>, path: web::Path<String>) -> HttpResponse {
+    match canvases.get(&path) {
+        Some(meta) => HttpResponse::Ok().json(palette_response(&meta)),
+        None => unknown_canvas(&path),
+    }
+}
+
+#[put("/canvases/{id}/palette", wrap = "from_fn(admin::authorize)")]
+async fn set_palette(replica_handle: web::Data<ReplicaHandle>, canvases: web::Data<canvas::CanvasRegistry>, path: web::Path<String>, data: Json<PaletteUpdate>) -> HttpResponse {
+    let update = data.into_inner();
+    change_palette(&replica_handle, &canvases, &path, false, |meta| {
+        meta.palette = update.colours;
+        meta.indexed = update.indexed.unwrap_or(meta.indexed);
+        Ok(())
+    }).await
+}
+
+#[post("/canvases/{id}/palette", wrap = "from_fn(admin::authorize)")]
+async fn add_palette_colour(replica_handle: web::Data<ReplicaHandle>, canvases: web::Data<canvas::CanvasRegistry>, path: web::Path<String>, data: Json<PaletteColour>) -> HttpResponse {
+    change_palette(&replica_handle, &canvases, &path, true, |meta| {
+        meta.palette.push(data.colour);
+        Ok(())
+    }).await
+}
+
+#[put("/canvases/{id}/palette/{index}", wrap = "from_fn(admin::authorize)")]
+async fn set_palette_colour(replica_handle: web::Data<ReplicaHandle>, canvases: web::Data<canvas::CanvasRegistry>, path: web::Path<(String, usize)>, data: Json<PaletteColour>) -> HttpResponse {
+    let (id, index) = path.into_inner();
+    change_palette(&replica_handle, &canvases, &id, false, |meta| {
+        match meta.palette.get_mut(index) {
+            Some(colour) => {
+                *colour = data.colour;
+                Ok(())
+            }
+            None => Err(no_palette_entry(index)),
+        }
+    }).await
+}
+
+#[delete("/canvases/{id}/palette/{index}", wrap = "from_fn(admin::authorize)")]
+async fn delete_palette_colour(replica_handle: web::Data<ReplicaHandle>, canvases: web::Data<canvas::CanvasRegistry>, path: web::Path<(String, usize)>) -> HttpResponse {
+    let (id, index) = path.into_inner();
+    change_palette(&replica_handle, &canvases, &id, false, |meta| {
+        if index >= meta.palette.len() {
+            return Err(no_palette_entry(index));
+        }
+        meta.palette.remove(index);
+        Ok(())
+    }).await
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct WsQuery {
+    canvas: Option<CanvasId>,
+    /// Version of `protocol` to speak, see there
+    protocol: Option<u32>,
+    /// Send the writes of other connections in batches, see `batch`
+    batch: Option<batch::BatchFormat>,
+    /// Take and send writes as binary frames, see `protocol`
+    #[serde(default)]
+    encoding: protocol::Encoding,
+}
+
 // Entry point for our websocket route
 async fn canvas_route(
-    req: HttpRequest, stream: web::Payload, pool: web::Data<Pool>, replica_handle: web::Data<ReplicaHandle>) -> Result<HttpResponse, Error> {
-        let (res, session, msg_stream) = actix_ws::handle(&req, stream)?;
+    req: HttpRequest, stream: web::Payload, replica_handle: web::Data<ReplicaHandle>,
+    canvases: web::Data<canvas::CanvasRegistry>, auth: web::Data<auth::Auth>, query: web::Query<WsQuery>) -> Result<HttpResponse, Error> {
+        let query = query.into_inner();
+        let canvas = query.canvas.unwrap_or_else(canvas::default_canvas_id);
+        let protocol = match protocol::Protocol::negotiate(&req, query.protocol) {
+            Ok(protocol) => protocol,
+            Err(version) => return Ok(HttpResponse::BadRequest().json(json!({
+                "command": "error",
+                "payload": { "code": "unsupported_protocol", "version": version, "latest": protocol::VERSION },
+            }))),
+        };
+        if canvases.get(&canvas).is_none() {
+            return Ok(unknown_canvas(&canvas));
+        }
+        // Checked before upgrading so a bad token gets a proper status
+        let author = match auth.author(&req) {
+            Ok(author) => author,
+            Err(err) => return Ok(auth_error_response(err)),
+        };
+
+        let (mut res, session, msg_stream) = deflate::handle(&req, stream).await?;
+        if let Some(subprotocol) = protocol.response_header(&req) {
+            res.headers_mut().insert(actix_web::http::header::SEC_WEBSOCKET_PROTOCOL, subprotocol);
+        }
 
         // spawn websocket handler (and don't await it) so that the response is returned immediately
-        rt::spawn(handler::canvas_ws((**replica_handle).clone(), session, msg_stream, pool));
+        let options = handler::WsOptions { protocol, encoding: query.encoding, batch: query.batch, proxy: cooldown::from_trusted_proxy(&req) };
+        rt::spawn(handler::canvas_ws((**replica_handle).clone(), session, msg_stream, canvas, author, options));
 
         Ok(res)
     }
 
+/// Most pixels accepted by one `POST /pixels`
+const MAX_BATCH: usize = 1000;
+
+fn not_primary(primary: u16, address: Option<String>) -> HttpResponse {
+    HttpResponse::ServiceUnavailable().json(json!({
+        "command": "error",
+        "payload": { "code": "not_primary", "primary": primary, "address": address },
+    }))
+}
+
+/// Write a pixel to a canvas and wait for it to be replicated. Answers 201 once every replica
+/// has it, 202 if it didn't make it around the ring in time and 503 if this replica isn't the
+/// primary
+async fn post_pixel(replica_handle: &ReplicaHandle, canvas_id: &str, mut data: pixel::Pixel, author: auth::Author) -> HttpResponse {
+    data.canvas_id = canvas_id.to_string();
+    data.user = author.user.clone();
+    let msg = serde_json::to_string(&data).unwrap();
+    match replica_handle.write(canvas_id.to_string(), msg, Some(author)).await {
+        Ok(WriteOutcome::Replicated) => HttpResponse::Created().json(json!({ "command": "replicated", "payload": data })),
+        Ok(WriteOutcome::Unreplicated) => HttpResponse::Accepted().json(json!({ "command": "unreplicated", "payload": data })),
+        Ok(WriteOutcome::NotPrimary { primary, address }) => not_primary(primary, address),
+        Err(err) => {
+            log::debug!("rejected pixel: {}", err);
+            error_response(err)
+        }
+    }
+}
+
+/// Write a batch of pixels to a canvas, all of them or none if any is invalid. Answers 201
+/// once every replica has them all, otherwise 202 listing which were and weren't replicated.
+/// Each pixel is a placement, so while a cooldown is set a batch can only hold one pixel
+async fn post_pixels(replica_handle: &ReplicaHandle, canvases: &canvas::CanvasRegistry, canvas_id: &str, mut pixels: Vec<pixel::Pixel>, author: auth::Author) -> HttpResponse {
+    let max = if cooldown::pixel_cooldown().is_zero() { MAX_BATCH } else { 1 };
+    if pixels.is_empty() || pixels.len() > max {
+        return error_response(canvas::WriteError::Malformed {
+            message: format!("expected 1 to {} pixels, got {}", max, pixels.len()),
+        });
+    }
+    if canvases.get(canvas_id).is_none() {
+        return unknown_canvas(canvas_id);
+    }
+    let mut rejected = Vec::new();
+    for (index, pixel) in pixels.iter_mut().enumerate() {
+        pixel.canvas_id = canvas_id.to_string();
+        pixel.user = author.user.clone();
+        if let Err(err) = canvases.validate(pixel) {
+            rejected.push(json!({ "index": index, "error": err }));
+        }
+    }
+    if !rejected.is_empty() {
+        return HttpResponse::BadRequest().json(json!({
+            "command": "error",
+            "payload": { "code": "invalid_pixels", "rows": rejected },
+        }));
+    }
+
+    // Sent in order, each write goes around the ring on its own
+    let mut replicated = Vec::new();
+    let mut unreplicated = Vec::new();
+    for pixel in pixels.into_iter() {
+        match replica_handle.write(canvas_id.to_string(), serde_json::to_string(&pixel).unwrap(), Some(author.clone())).await {
+            Ok(WriteOutcome::Replicated) => replicated.push(pixel),
+            Ok(WriteOutcome::NotPrimary { primary, address }) => return not_primary(primary, address),
+            // Only a batch of one pixel can still be cooling down
+            Err(err @ canvas::WriteError::Cooldown { .. }) => return error_response(err),
+            // The canvas changed since the batch was checked
+            Ok(WriteOutcome::Unreplicated) | Err(_) => unreplicated.push(pixel),
+        }
+    }
+    let body = json!({
+        "command": "pixels",
+        "payload": { "replicated": replicated, "unreplicated": unreplicated },
+    });
+    if unreplicated.is_empty() {
+        HttpResponse::Created().json(body)
+    } else {
+        HttpResponse::Accepted().json(body)
+    }
+}
+
 #[post("/pixel")]
-async fn set_pixel(pool: web::Data<Pool>, data: Json<pixel::Pixel>) -> HttpResponse {
-    log::debug!("pixel data: {:?}", data);
-    let client = match pool.get().await {
-        Ok(client) => client,
+async fn set_pixel(req: HttpRequest, replica_handle: web::Data<ReplicaHandle>, auth: web::Data<auth::Auth>, data: Json<pixel::Pixel>) -> HttpResponse {
+    match auth.author(&req) {
+        Ok(author) => post_pixel(&replica_handle, canvas::DEFAULT_CANVAS, data.into_inner(), author).await,
+        Err(err) => auth_error_response(err),
+    }
+}
+
+#[post("/canvases/{id}/pixel")]
+async fn set_canvas_pixel(req: HttpRequest, replica_handle: web::Data<ReplicaHandle>, auth: web::Data<auth::Auth>, path: web::Path<String>, data: Json<pixel::Pixel>) -> HttpResponse {
+    match auth.author(&req) {
+        Ok(author) => post_pixel(&replica_handle, &path, data.into_inner(), author).await,
+        Err(err) => auth_error_response(err),
+    }
+}
+
+#[post("/pixels")]
+async fn set_pixels(req: HttpRequest, replica_handle: web::Data<ReplicaHandle>, canvases: web::Data<canvas::CanvasRegistry>, auth: web::Data<auth::Auth>, data: Json<Vec<pixel::Pixel>>) -> HttpResponse {
+    match auth.author(&req) {
+        Ok(author) => post_pixels(&replica_handle, &canvases, canvas::DEFAULT_CANVAS, data.into_inner(), author).await,
+        Err(err) => auth_error_response(err),
+    }
+}
+
+#[post("/canvases/{id}/pixels")]
+async fn set_canvas_pixels(req: HttpRequest, replica_handle: web::Data<ReplicaHandle>, canvases: web::Data<canvas::CanvasRegistry>, auth: web::Data<auth::Auth>, path: web::Path<String>, data: Json<Vec<pixel::Pixel>>) -> HttpResponse {
+    match auth.author(&req) {
+        Ok(author) => post_pixels(&replica_handle, &canvases, &path, data.into_inner(), author).await,
+        Err(err) => auth_error_response(err),
+    }
+}
+
+/// Create an account. Only the primary takes registrations, so names are handed out once
+#[post("/register")]
+async fn register(replica_handle: web::Data<ReplicaHandle>, data: Json<auth::Credentials>) -> HttpResponse {
+    let credentials = data.into_inner();
+    if let Err(err) = credentials.check() {
+        return auth_error_response(err);
+    }
+    let password = credentials.password;
+    let password_hash = match web::block(move || auth::hash_password(&password)).await {
+        Ok(password_hash) => password_hash,
         Err(err) => {
-            log::debug!("unable to get postgres client: {:?}", err);
-            return HttpResponse::InternalServerError().json("unable to get postgres client");
+            log::error!("couldn't hash password: {}", err);
+            return auth_error_response(auth::AuthError::Unavailable);
         }
     };
-    match pixel::Pixel::all(&**client).await {
-        Ok(list) => HttpResponse::Ok().json(list),
+    let user = auth::User {
+        name: credentials.name,
+        password_hash,
+        created: pixel::now(),
+    };
+    let name = user.name.clone();
+    match replica_handle.register(user).await {
+        Ok(()) => HttpResponse::Created().json(json!({ "name": name })),
+        Err(err) => auth_error_response(err),
+    }
+}
+
+/// Trade a name and password for a token, on any replica
+#[post("/login")]
+async fn login(store: web::Data<dyn CanvasStore>, auth: web::Data<auth::Auth>, data: Json<auth::Credentials>) -> HttpResponse {
+    let credentials = data.into_inner();
+    let user = match store.user(&credentials.name).await {
+        Ok(user) => user,
         Err(err) => {
-            log::debug!("unable to fetch pixels: {:?}", err);
-            return HttpResponse::InternalServerError().json("unable to fetch pixels");
+            log::error!("couldn't read user {}: {}", credentials.name, err);
+            return auth_error_response(auth::AuthError::Unavailable);
+        }
+    };
+    // Unknown names are hashed against too, so they take as long as wrong passwords
+    let password_hash = user.as_ref().map(|user| user.password_hash.clone());
+    let password = credentials.password;
+    let verified = web::block(move || match password_hash {
+        Some(password_hash) => auth::verify_password(&password_hash, &password),
+        None => {
+            auth::hash_password(&password);
+            false
         }
+    })
+    .await
+    .unwrap_or(false);
+    if !verified {
+        return auth_error_response(auth::AuthError::BadCredentials);
     }
+    HttpResponse::Ok().json(auth.issue(&credentials.name))
 }
 
 fn address() -> String {
@@ -82,25 +489,78 @@ fn address() -> String {
 async fn main() -> std::io::Result<()> {
     env_logger::init();
 
-    let pg_pool = postgres::create_pool();
-    postgres::migrate_up(&pg_pool).await;
+    let mut args = std::env::args().skip(1).peekable();
+    if args.peek().is_some() {
+        // The HTTP client runs on the current thread
+        return tokio::task::LocalSet::new().run_until(cli::run(args)).await;
+    }
+
+    let store = store::create_store().await.map_err(|e| {
+        log::error!("Couldn't open the store: {}", e);
+        std::io::Error::other(e)
+    })?;
+    let canvases = canvas::load_canvases(&*store).await.map_err(|e| {
+        log::error!("Couldn't load the canvases: {}", e);
+        std::io::Error::other(e)
+    })?;
 
+    let auth = auth::Auth::from_env();
+    if !auth.admin_enabled() {
+        log::warn!("Neither ADMIN_TOKEN nor ADMIN_USERS is set, the /admin routes are disabled");
+    }
     let (cmd_tx, cmd_rx) = mpsc::unbounded_channel();
 
-    let (replica_handler, tx) = ReplicaManager::new(false, pg_pool.clone(), cmd_tx);
+    let (replica_handler, tx) = ReplicaManager::new(false, store.clone(), canvases.clone(), cmd_tx);
 
     
     let replica_join_handle = spawn(replica_handler.run(cmd_rx));
+    spawn(snapshot::run(store.clone(), canvases.clone()));
 
     let address = address();
     log::info!("address {}", address);
+    log::info!("trusted proxies {:?}", cooldown::trusted_proxies());
     let http_server = HttpServer::new(move || {
         App::new()
             .wrap(Cors::permissive())
-            .app_data(web::Data::new(pg_pool.clone()))
+            .app_data(web::Data::from(store.clone()))
             .app_data(web::Data::new(tx.clone()))
+            .app_data(web::Data::new(canvases.clone()))
+            .app_data(web::Data::new(auth.clone()))
             .service(get_pixels)
             .service(set_pixel)
+            .service(list_canvases)
+            .service(create_canvas)
+            .service(get_canvas)
+            .service(get_canvas_pixels)
+            .service(set_canvas_pixel)
+            .service(set_pixels)
+            .service(set_canvas_pixels)
+            .service(register)
+            .service(login)
+            .service(get_palette)
+            .service(set_palette)
+            .service(add_palette_colour)
+            .service(set_palette_colour)
+            .service(delete_palette_colour)
+            .service(
+                web::scope("/admin")
+                    .wrap(from_fn(admin::authorize))
+                    .service(admin::export_canvas)
+                    .service(web::resource("/canvases/{id}/import").app_data(web::PayloadConfig::new(admin::MAX_IMPORT_SIZE)).route(web::post().to(admin::import_canvas)))
+                    .service(admin::take_snapshot)
+                    .service(admin::lock_region)
+                    .service(admin::list_locks)
+                    .service(admin::unlock_region)
+                    .service(admin::add_ban)
+                    .service(admin::list_bans)
+                    .service(admin::unban)
+                    .service(admin::rollback)
+                    .service(admin::audit_log)
+                    .service(admin::list_sessions),
+            )
+            .service(list_snapshots)
+            .service(get_snapshot)
+            .service(events::events)
             // websocket route
             .service(web::resource("/ws").route(web::get().to(canvas_route)))
             .wrap(Logger::default())
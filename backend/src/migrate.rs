@@ -0,0 +1,360 @@
+//! Postgres schema migrations.
+//!
+//! Each script in `migrations/` has an `_down.sql` counterpart that undoes it. Scripts run in
+//! a transaction each and are recorded in the `migrations` table with a checksum, so a script
+//! that was changed after it ran is caught instead of silently leaving replicas with
+//! different schemas.
+
+use deadpool_postgres::{Manager, Pool, PoolError};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use tokio_postgres::{GenericClient, Row};
+
+pub struct Script {
+    pub name: &'static str,
+    up: &'static str,
+    down: &'static str,
+}
+
+macro_rules! script {
+    ($name:literal) => {
+        Script {
+            name: $name,
+            up: include_str!(concat!("../migrations/", $name, ".sql")),
+            down: include_str!(concat!("../migrations/", $name, "_down.sql")),
+        }
+    };
+}
+
+const MIGRATIONS: [Script; 16] = [
+    script!("0001_create-database"),
+    script!("0002_create-canvas-meta"),
+    script!("0003_add-canvas-id"),
+    script!("0004_add-canvas-meta-id"),
+    script!("0005_create-canvas-palette"),
+    script!("0006_add-canvas-indexed"),
+    script!("0007_add-pixel-palette-index"),
+    script!("0008_create-canvas-history"),
+    script!("0009_add-canvas-history-index"),
+    script!("0010_create-canvas-snapshot"),
+    script!("0011_create-canvas-snapshot-pixel"),
+    script!("0012_create-users"),
+    script!("0013_add-history-user"),
+    script!("0014_create-canvas-lock"),
+    script!("0015_create-ban"),
+    script!("0016_create-audit-log"),
+];
+
+/// Schema this build expects, the number of migrations it knows about. Replicas only join
+/// a ring whose members are at the same version
+pub const SCHEMA_VERSION: u32 = MIGRATIONS.len() as u32;
+
+#[derive(Debug)]
+pub enum MigrationError {
+    Postgres(tokio_postgres::Error),
+    Pool(PoolError),
+    /// An applied script was changed after it ran
+    Checksum {
+        name: String,
+    },
+    /// The database was migrated by a newer build
+    Unknown {
+        name: String,
+    },
+    /// The database isn't at `SCHEMA_VERSION` and wasn't migrated on startup
+    Outdated {
+        version: u32,
+    },
+}
+
+impl fmt::Display for MigrationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MigrationError::Postgres(e) => write!(f, "postgres error: {}", e),
+            MigrationError::Pool(e) => write!(f, "postgres pool error: {}", e),
+            MigrationError::Checksum { name } => {
+                write!(f, "migration {} was changed after it was applied", name)
+            }
+            MigrationError::Unknown { name } => write!(
+                f,
+                "migration {} is applied but unknown to this build, it was migrated by a newer one",
+                name
+            ),
+            MigrationError::Outdated { version } => write!(
+                f,
+                "schema is at version {} but this build needs {}, run `cargo run -- migrate up`",
+                version, SCHEMA_VERSION
+            ),
+        }
+    }
+}
+
+impl std::error::Error for MigrationError {}
+
+impl From<tokio_postgres::Error> for MigrationError {
+    fn from(e: tokio_postgres::Error) -> Self {
+        MigrationError::Postgres(e)
+    }
+}
+
+impl From<PoolError> for MigrationError {
+    fn from(e: PoolError) -> Self {
+        MigrationError::Pool(e)
+    }
+}
+
+/// A row of the `migrations` table. Migrations applied before checksums were recorded have none
+struct Applied {
+    name: String,
+    checksum: Option<String>,
+    executed_at: String,
+}
+
+impl From<Row> for Applied {
+    fn from(row: Row) -> Self {
+        Self {
+            name: row.get(0),
+            checksum: row.get(1),
+            executed_at: row.get(2),
+        }
+    }
+}
+
+/// State of one migration, as listed by `migrate status`
+pub struct Status {
+    pub name: String,
+    pub state: &'static str,
+    pub executed_at: Option<String>,
+}
+
+fn checksum(script: &str) -> String {
+    Sha256::digest(script.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+fn script(name: &str) -> Option<&'static Script> {
+    MIGRATIONS.iter().find(|script| script.name == name)
+}
+
+async fn applied<C: GenericClient>(client: &C) -> Result<Vec<Applied>, MigrationError> {
+    // The table may have been created without checksums by an older build
+    client
+        .batch_execute(
+            "CREATE TABLE IF NOT EXISTS migrations (
+                name TEXT NOT NULL PRIMARY KEY,
+                executed_at TIMESTAMP NOT NULL DEFAULT NOW()
+            );
+            ALTER TABLE migrations ADD COLUMN IF NOT EXISTS checksum TEXT;",
+        )
+        .await?;
+    let rows = client
+        .query(
+            "SELECT name, checksum, executed_at::text FROM migrations ORDER BY name",
+            &[],
+        )
+        .await?;
+    Ok(rows.into_iter().map(Applied::from).collect())
+}
+
+/// Fail if an applied migration is unknown or was changed since it ran
+fn verify(applied: &[Applied]) -> Result<(), MigrationError> {
+    for migration in applied.iter() {
+        let script = script(&migration.name).ok_or_else(|| MigrationError::Unknown {
+            name: migration.name.clone(),
+        })?;
+        if let Some(applied_checksum) = &migration.checksum {
+            if *applied_checksum != checksum(script.up) {
+                return Err(MigrationError::Checksum {
+                    name: migration.name.clone(),
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Apply every pending migration, returning their names
+pub async fn up(
+    client: &mut deadpool::managed::Object<Manager>,
+) -> Result<Vec<&'static str>, MigrationError> {
+    let applied = applied(&***client).await?;
+    verify(&applied)?;
+    for migration in applied.iter().filter(|m| m.checksum.is_none()) {
+        log::info!("Recording checksum of migration {}", migration.name);
+        client
+            .execute(
+                "UPDATE migrations SET checksum = $2 WHERE name = $1",
+                &[
+                    &migration.name,
+                    &checksum(script(&migration.name).unwrap().up),
+                ],
+            )
+            .await?;
+    }
+
+    let mut migrated = Vec::new();
+    for script in MIGRATIONS.iter() {
+        if applied.iter().any(|m| m.name == script.name) {
+            continue;
+        }
+        log::info!("Applying migration {}", script.name);
+        let tx = client.transaction().await?;
+        tx.batch_execute(script.up).await?;
+        tx.execute(
+            "INSERT INTO migrations (name, checksum) VALUES ($1, $2)",
+            &[&script.name, &checksum(script.up)],
+        )
+        .await?;
+        tx.commit().await?;
+        migrated.push(script.name);
+    }
+    Ok(migrated)
+}
+
+/// Undo the last `steps` applied migrations, returning their names
+pub async fn down(
+    client: &mut deadpool::managed::Object<Manager>,
+    steps: usize,
+) -> Result<Vec<&'static str>, MigrationError> {
+    let applied = applied(&***client).await?;
+    verify(&applied)?;
+
+    let mut reverted = Vec::new();
+    for migration in applied.iter().rev().take(steps) {
+        // verify() has checked every applied migration is known
+        let script = script(&migration.name).unwrap();
+        log::info!("Reverting migration {}", script.name);
+        let tx = client.transaction().await?;
+        tx.batch_execute(script.down).await?;
+        tx.execute("DELETE FROM migrations WHERE name = $1", &[&script.name])
+            .await?;
+        tx.commit().await?;
+        reverted.push(script.name);
+    }
+    Ok(reverted)
+}
+
+pub async fn status<C: GenericClient>(client: &C) -> Result<Vec<Status>, MigrationError> {
+    let applied = applied(client).await?;
+    let mut status: Vec<Status> = MIGRATIONS
+        .iter()
+        .map(|script| {
+            let migration = applied.iter().find(|m| m.name == script.name);
+            let state = match migration.map(|m| m.checksum.as_ref()) {
+                None => "pending",
+                Some(None) => "applied, no checksum",
+                Some(Some(applied)) if *applied != checksum(script.up) => "changed",
+                Some(Some(_)) => "applied",
+            };
+            Status {
+                name: script.name.to_string(),
+                state,
+                executed_at: migration.map(|m| m.executed_at.clone()),
+            }
+        })
+        .collect();
+    status.extend(
+        applied
+            .into_iter()
+            .filter(|m| script(&m.name).is_none())
+            .map(|m| Status {
+                name: m.name,
+                state: "unknown",
+                executed_at: Some(m.executed_at),
+            }),
+    );
+    Ok(status)
+}
+
+fn migrate_on_start() -> bool {
+    std::env::var("MIGRATE_ON_START").map_or(true, |migrate| migrate != "false")
+}
+
+/// Bring the database up to `SCHEMA_VERSION` on startup, or only check it's there when
+/// `MIGRATE_ON_START` is `false`
+pub async fn migrate_on_startup(pool: &Pool) -> Result<(), MigrationError> {
+    let mut client = pool.get().await?;
+    if migrate_on_start() {
+        let migrated = up(&mut client).await?;
+        if !migrated.is_empty() {
+            log::info!("Migrated to schema version {}", SCHEMA_VERSION);
+        }
+        return Ok(());
+    }
+
+    let applied = applied(&**client).await?;
+    verify(&applied)?;
+    if applied.len() as u32 != SCHEMA_VERSION {
+        return Err(MigrationError::Outdated {
+            version: applied.len() as u32,
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn applied(name: &str, checksum: Option<String>) -> Applied {
+        Applied {
+            name: name.to_string(),
+            checksum,
+            executed_at: String::new(),
+        }
+    }
+
+    fn all_applied() -> Vec<Applied> {
+        MIGRATIONS
+            .iter()
+            .map(|script| applied(script.name, Some(checksum(script.up))))
+            .collect()
+    }
+
+    #[test]
+    fn checksums_are_sha256() {
+        assert_eq!(
+            checksum(""),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+    }
+
+    #[test]
+    fn migrations_are_in_order() {
+        let names: Vec<_> = MIGRATIONS.iter().map(|script| script.name).collect();
+        let mut sorted = names.clone();
+        sorted.sort();
+        sorted.dedup();
+        assert_eq!(names, sorted);
+    }
+
+    #[test]
+    fn verifies_applied_migrations() {
+        assert!(verify(&[]).is_ok());
+        assert!(verify(&all_applied()).is_ok());
+        // Applied before checksums were recorded
+        assert!(verify(&[applied(MIGRATIONS[0].name, None)]).is_ok());
+    }
+
+    #[test]
+    fn migration_changed_after_it_ran() {
+        let mut migrations = all_applied();
+        migrations[1].checksum = Some(checksum("DROP TABLE canvas;"));
+        assert!(matches!(
+            verify(&migrations),
+            Err(MigrationError::Checksum { name }) if name == MIGRATIONS[1].name
+        ));
+    }
+
+    #[test]
+    fn unknown_migration() {
+        let mut migrations = all_applied();
+        migrations.push(applied("9999_from-a-newer-build", None));
+        assert!(matches!(
+            verify(&migrations),
+            Err(MigrationError::Unknown { name }) if name == "9999_from-a-newer-build"
+        ));
+    }
+}
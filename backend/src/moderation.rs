@@ -0,0 +1,411 @@
+//! Region locks, bans and rollbacks.
+//!
+//! Locks and bans are applied by the replica that was asked to and passed around the ring, so
+//! every replica refuses the same writes. They only hold back clients, writes made by the admin
+//! routes such as imports and rollbacks aren't checked against them. A rollback is worked out
+//! from the history of the replica that was asked to roll back, and the colours it restores are
+//! written through the ring like any other write.
+
+use crate::auth::Author;
+use crate::canvas::{CanvasMeta, Region, WriteError};
+use crate::pixel::Pixel;
+use crate::store::{CanvasStore, StoreError};
+use deadpool_postgres::GenericClient;
+use std::collections::{BTreeMap, HashMap};
+use tokio_postgres::{Error, Row};
+
+/// A region of a canvas no client can write to
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Lock {
+    pub id: i64,
+    pub canvas_id: String,
+    #[serde(flatten)]
+    pub region: Region,
+    pub reason: Option<String>,
+    pub created: i32,
+}
+
+impl From<Row> for Lock {
+    fn from(row: Row) -> Self {
+        Self {
+            id: row.get(0),
+            canvas_id: row.get(1),
+            region: Region {
+                x: row.get(2),
+                y: row.get(3),
+                width: row.get(4),
+                height: row.get(5),
+            },
+            reason: row.get(6),
+            created: row.get(7),
+        }
+    }
+}
+
+/// A user or address that can't write, `identity` is `user:<name>` or `ip:<address>`
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Ban {
+    pub identity: String,
+    pub reason: Option<String>,
+    pub created: i32,
+}
+
+impl From<Row> for Ban {
+    fn from(row: Row) -> Self {
+        Self {
+            identity: row.get(0),
+            reason: row.get(1),
+            created: row.get(2),
+        }
+    }
+}
+
+/// A change to the locks or bans, applied on every replica
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum Action {
+    Lock { lock: Lock },
+    Unlock { id: i64 },
+    Ban { ban: Ban },
+    Unban { identity: String },
+}
+
+impl Action {
+    pub async fn apply<C: GenericClient>(&self, client: &C) -> Result<u64, Error> {
+        match self {
+            Action::Lock { lock } => {
+                let stmt = client
+                    .prepare_cached(
+                        "INSERT INTO canvas_lock (id, canvas_id, x, y, width, height, reason, created)
+                    VALUES ($1, $2, $3, $4, $5, $6, $7, $8) ON CONFLICT (id) DO NOTHING",
+                    )
+                    .await?;
+                client
+                    .execute(
+                        &stmt,
+                        &[
+                            &lock.id,
+                            &lock.canvas_id,
+                            &lock.region.x,
+                            &lock.region.y,
+                            &lock.region.width,
+                            &lock.region.height,
+                            &lock.reason,
+                            &lock.created,
+                        ],
+                    )
+                    .await
+            }
+            Action::Unlock { id } => {
+                let stmt = client
+                    .prepare_cached("DELETE FROM canvas_lock WHERE id = $1")
+                    .await?;
+                client.execute(&stmt, &[id]).await
+            }
+            Action::Ban { ban } => {
+                let stmt = client
+                    .prepare_cached(
+                        "INSERT INTO ban (identity, reason, created) VALUES ($1, $2, $3)
+                    ON CONFLICT (identity) DO UPDATE SET reason = $2, created = $3",
+                    )
+                    .await?;
+                client
+                    .execute(&stmt, &[&ban.identity, &ban.reason, &ban.created])
+                    .await
+            }
+            Action::Unban { identity } => {
+                let stmt = client
+                    .prepare_cached("DELETE FROM ban WHERE identity = $1")
+                    .await?;
+                client.execute(&stmt, &[identity]).await
+            }
+        }
+    }
+}
+
+impl Lock {
+    pub async fn all<C: GenericClient>(client: &C) -> Result<Vec<Lock>, Error> {
+        let rows = client
+            .query(
+                "SELECT id, canvas_id, x, y, width, height, reason, created FROM canvas_lock
+                ORDER BY created, id",
+                &[],
+            )
+            .await?;
+        Ok(rows.into_iter().map(Lock::from).collect())
+    }
+}
+
+impl Ban {
+    pub async fn all<C: GenericClient>(client: &C) -> Result<Vec<Ban>, Error> {
+        let rows = client
+            .query(
+                "SELECT identity, reason, created FROM ban ORDER BY created, identity",
+                &[],
+            )
+            .await?;
+        Ok(rows.into_iter().map(Ban::from).collect())
+    }
+}
+
+/// Locks and bans in force on this replica
+#[derive(Debug, Default)]
+pub struct Moderation {
+    locks: Vec<Lock>,
+    bans: BTreeMap<String, Ban>,
+}
+
+impl Moderation {
+    pub async fn load(store: &dyn CanvasStore) -> Result<Moderation, StoreError> {
+        let bans = store.bans().await?;
+        Ok(Moderation {
+            locks: store.locks().await?,
+            bans: bans
+                .into_iter()
+                .map(|ban| (ban.identity.clone(), ban))
+                .collect(),
+        })
+    }
+
+    pub fn apply(&mut self, action: &Action) {
+        match action {
+            Action::Lock { lock } => {
+                if !self.locks.iter().any(|l| l.id == lock.id) {
+                    self.locks.push(lock.clone());
+                }
+            }
+            Action::Unlock { id } => self.locks.retain(|lock| lock.id != *id),
+            Action::Ban { ban } => {
+                self.bans.insert(ban.identity.clone(), ban.clone());
+            }
+            Action::Unban { identity } => {
+                self.bans.remove(identity);
+            }
+        }
+    }
+
+    pub fn locks(&self) -> Vec<Lock> {
+        self.locks.clone()
+    }
+
+    pub fn bans(&self) -> Vec<Ban> {
+        self.bans.values().cloned().collect()
+    }
+
+    /// What has to change to end up with `locks` and `bans`, those of the ring a replica joins
+    pub fn replace(&self, locks: &[Lock], bans: &[Ban]) -> Vec<Action> {
+        let mut actions = Vec::new();
+        for lock in self.locks.iter().filter(|lock| !locks.contains(lock)) {
+            actions.push(Action::Unlock { id: lock.id });
+        }
+        for identity in self
+            .bans
+            .keys()
+            .filter(|identity| !bans.iter().any(|ban| &ban.identity == *identity))
+        {
+            actions.push(Action::Unban {
+                identity: identity.clone(),
+            });
+        }
+        actions.extend(locks.iter().map(|lock| Action::Lock { lock: lock.clone() }));
+        actions.extend(bans.iter().map(|ban| Action::Ban { ban: ban.clone() }));
+        actions
+    }
+
+    /// Fail if the author of a write is banned or the pixel is in a locked region
+    pub fn check(&self, pixel: &Pixel, author: &Author) -> Result<(), WriteError> {
+        let address = format!("ip:{}", author.address);
+        if self.bans.contains_key(&author.identity) || self.bans.contains_key(&address) {
+            return Err(WriteError::Banned);
+        }
+        let lock = self
+            .locks
+            .iter()
+            .find(|lock| lock.canvas_id == pixel.canvas_id && lock.region.contains(pixel));
+        match lock {
+            Some(lock) => Err(WriteError::Locked { lock: lock.id }),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Which writes a rollback undoes, those matching every field that's given
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct RollbackFilter {
+    pub user: Option<String>,
+    pub since: Option<i32>,
+    pub until: Option<i32>,
+}
+
+impl RollbackFilter {
+    pub fn is_empty(&self) -> bool {
+        self.user.is_none() && self.since.is_none() && self.until.is_none()
+    }
+
+    fn matches(&self, pixel: &Pixel) -> bool {
+        self.user
+            .as_ref()
+            .is_none_or(|user| pixel.user.as_ref() == Some(user))
+            && pixel.updated_between(self.since, self.until)
+    }
+}
+
+/// Writes that undo those matching `filter`. Every position whose newest write matches gets
+/// back the colour of its newest write that doesn't, or the background if it has none.
+/// Positions written again since are left alone. `history` is oldest first
+pub fn rollback(
+    meta: &CanvasMeta,
+    history: &[Pixel],
+    current: &[Pixel],
+    filter: &RollbackFilter,
+    now: i32,
+) -> Vec<Pixel> {
+    let mut kept: HashMap<(i32, i32), i32> = HashMap::new();
+    let mut undone: HashMap<(i32, i32), bool> = HashMap::new();
+    for pixel in history.iter() {
+        let position = (pixel.x, pixel.y);
+        let matches = filter.matches(pixel);
+        if !matches {
+            kept.insert(position, pixel.colour);
+        }
+        undone.insert(position, matches);
+    }
+    let updated: HashMap<(i32, i32), i32> = current
+        .iter()
+        .map(|pixel| ((pixel.x, pixel.y), pixel.updated))
+        .collect();
+
+    let mut restored: Vec<Pixel> = undone
+        .into_iter()
+        .filter(|(_, undone)| *undone)
+        .map(|((x, y), _)| Pixel {
+            x,
+            y,
+            colour: kept.get(&(x, y)).copied().unwrap_or(meta.background),
+            // Newer than what's stored so it wins however `updated` was set
+            updated: updated
+                .get(&(x, y))
+                .map_or(now, |updated| now.max(updated.saturating_add(1))),
+            canvas_id: meta.id.clone(),
+            user: None,
+            seq: None,
+        })
+        .collect();
+    restored.sort_by_key(|pixel| (pixel.y, pixel.x));
+    restored
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn meta() -> CanvasMeta {
+        CanvasMeta {
+            width: 10,
+            height: 10,
+            background: 0xffffff,
+            ..CanvasMeta::default()
+        }
+    }
+
+    fn write(x: i32, colour: i32, updated: i32, user: &str) -> Pixel {
+        Pixel {
+            updated,
+            user: Some(user.to_string()),
+            ..Pixel::test(x, 0, colour)
+        }
+    }
+
+    fn window(since: i32, until: i32) -> RollbackFilter {
+        RollbackFilter {
+            user: None,
+            since: Some(since),
+            until: Some(until),
+        }
+    }
+
+    fn colours(restored: &[Pixel]) -> Vec<(i32, i32)> {
+        restored
+            .iter()
+            .map(|pixel| (pixel.x, pixel.colour))
+            .collect()
+    }
+
+    #[test]
+    fn window_includes_both_ends() {
+        let history = [
+            write(0, 1, 99, "alice"),
+            write(1, 2, 100, "alice"),
+            write(2, 3, 150, "alice"),
+            write(3, 4, 200, "alice"),
+            write(4, 5, 201, "alice"),
+        ];
+        let restored = rollback(&meta(), &history, &[], &window(100, 200), 300);
+        assert_eq!(
+            colours(&restored),
+            [(1, 0xffffff), (2, 0xffffff), (3, 0xffffff)]
+        );
+    }
+
+    #[test]
+    fn open_ended_windows() {
+        let history = [write(0, 1, 100, "alice"), write(1, 2, 200, "alice")];
+        let since = RollbackFilter {
+            until: None,
+            ..window(200, 0)
+        };
+        assert_eq!(
+            colours(&rollback(&meta(), &history, &[], &since, 300)),
+            [(1, 0xffffff)]
+        );
+        let until = RollbackFilter {
+            since: None,
+            ..window(0, 100)
+        };
+        assert_eq!(
+            colours(&rollback(&meta(), &history, &[], &until, 300)),
+            [(0, 0xffffff)]
+        );
+    }
+
+    #[test]
+    fn restores_the_newest_write_before_the_window() {
+        let history = [
+            write(0, 1, 50, "alice"),
+            write(0, 2, 60, "bob"),
+            write(0, 3, 100, "mallory"),
+            write(0, 4, 110, "mallory"),
+        ];
+        let restored = rollback(&meta(), &history, &[], &window(100, 200), 300);
+        assert_eq!(colours(&restored), [(0, 2)]);
+    }
+
+    #[test]
+    fn leaves_positions_written_after_the_window() {
+        let history = [write(0, 1, 150, "mallory"), write(0, 2, 250, "alice")];
+        assert!(rollback(&meta(), &history, &[], &window(100, 200), 300).is_empty());
+    }
+
+    #[test]
+    fn only_undoes_the_user() {
+        let history = [write(0, 1, 150, "mallory"), write(1, 2, 150, "alice")];
+        let filter = RollbackFilter {
+            user: Some("mallory".to_string()),
+            ..window(100, 200)
+        };
+        let restored = rollback(&meta(), &history, &[], &filter, 300);
+        assert_eq!(colours(&restored), [(0, 0xffffff)]);
+    }
+
+    #[test]
+    fn restored_pixels_win_over_stored_ones() {
+        let history = [write(0, 1, 150, "mallory")];
+        let current = [Pixel {
+            updated: 500,
+            ..Pixel::test(0, 0, 1)
+        }];
+        let restored = rollback(&meta(), &history, &current, &window(100, 200), 300);
+        assert_eq!(restored[0].updated, 501);
+        assert!(restored[0].user.is_none());
+    }
+}
@@ -1,13 +1,36 @@
-use deadpool_postgres::Manager;
-use serde_json;
-use tokio_postgres::{Error, GenericClient, Row};
+use crate::canvas::{default_canvas_id, CanvasRegistry};
+use crate::CanvasId;
+use deadpool_postgres::{GenericClient, Manager};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::pin;
+use tokio_postgres::binary_copy::BinaryCopyInWriter;
+use tokio_postgres::types::Type;
+use tokio_postgres::{Error, Row};
 
-#[derive(Debug, serde::Serialize, serde::Deserialize)]
+/// Current time in the unix seconds stored in `updated`
+pub fn now() -> i32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i32)
+        .unwrap_or_default()
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct Pixel {
     pub x: i32,
     pub y: i32,
     pub colour: i32,
     pub updated: i32,
+    #[serde(default = "default_canvas_id")]
+    pub canvas_id: CanvasId,
+    /// Account that made the write. Only kept in the history, the current pixels of a canvas
+    /// have none
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    /// Number the primary gave the write, see `replay`. Only writes going around the ring and
+    /// sent to clients have one, it isn't stored
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seq: Option<u64>,
 }
 
 impl From<Row> for Pixel {
@@ -17,79 +40,283 @@ impl From<Row> for Pixel {
             y: row.get(1),
             colour: row.get(2),
             updated: row.get(3),
+            canvas_id: row.get(4),
+            // Only history rows have a user
+            user: row.try_get(5).ok().flatten(),
+            seq: None,
         }
     }
 }
 
 impl Pixel {
+    /// Whether the pixel was written between `since` and `until`, when they're given
+    pub fn updated_between(&self, since: Option<i32>, until: Option<i32>) -> bool {
+        since.is_none_or(|since| self.updated >= since)
+            && until.is_none_or(|until| self.updated <= until)
+    }
+
+    /// Every pixel of every canvas
     pub async fn all<C: GenericClient>(client: &C) -> Result<Vec<Pixel>, Error> {
         let stmt = client
-            .prepare("SELECT x, y, colour, updated FROM canvas")
+            .prepare_cached(
+                "SELECT c.x, c.y, COALESCE(c.colour, p.colour), c.updated, c.canvas_id FROM canvas c
+            LEFT JOIN canvas_palette p ON p.canvas_id = c.canvas_id AND p.idx = c.palette_index",
+            )
             .await?;
         let rows = client.query(&stmt, &[]).await?;
 
         Ok(rows.into_iter().map(Pixel::from).collect())
     }
 
+    /// Every pixel of a single canvas
+    pub async fn all_in<C: GenericClient>(
+        client: &C,
+        canvas_id: &str,
+    ) -> Result<Vec<Pixel>, Error> {
+        let stmt = client
+            .prepare_cached(
+                "SELECT c.x, c.y, COALESCE(c.colour, p.colour), c.updated, c.canvas_id FROM canvas c
+            LEFT JOIN canvas_palette p ON p.canvas_id = c.canvas_id AND p.idx = c.palette_index
+            WHERE c.canvas_id = $1",
+            )
+            .await?;
+        let rows = client.query(&stmt, &[&canvas_id]).await?;
+
+        Ok(rows.into_iter().map(Pixel::from).collect())
+    }
+
+    /// The pixel stored at a position, if it was ever written
+    pub async fn get<C: GenericClient>(
+        client: &C,
+        canvas_id: &str,
+        x: i32,
+        y: i32,
+    ) -> Result<Option<Pixel>, Error> {
+        let stmt = client
+            .prepare_cached(
+                "SELECT c.x, c.y, COALESCE(c.colour, p.colour), c.updated, c.canvas_id FROM canvas c
+            LEFT JOIN canvas_palette p ON p.canvas_id = c.canvas_id AND p.idx = c.palette_index
+            WHERE c.canvas_id = $1 AND c.x = $2 AND c.y = $3",
+            )
+            .await?;
+        let row = client.query_opt(&stmt, &[&canvas_id, &x, &y]).await?;
+
+        Ok(row.map(Pixel::from))
+    }
+
+    /// Store a pixel, either as its colour or as `palette_index` if its canvas uses indexed storage.
+    /// The write is also appended to the canvas history in the same statement
     pub async fn insert_pixel(
         client: &deadpool::managed::Object<Manager>,
         pixel: &Pixel,
+        palette_index: Option<i16>,
     ) -> Result<u64, Error> {
         let stmt = client
-            .prepare(
-                "INSERT INTO canvas (x, y, colour, updated) 
-            VALUES ($1, $2, $3, $4) 
-            ON CONFLICT (x, y) DO UPDATE SET 
+            .prepare_cached(
+                "WITH history AS (
+                INSERT INTO canvas_history (x, y, colour, updated, canvas_id, user_name)
+                VALUES ($1, $2, $7, $4, $5, $8)
+            )
+            INSERT INTO canvas (x, y, colour, updated, canvas_id, palette_index) 
+            VALUES ($1, $2, $3, $4, $5, $6) 
+            ON CONFLICT (canvas_id, x, y) DO UPDATE SET 
             colour = CASE WHEN canvas.updated < $4 THEN $3 ELSE canvas.colour END,
+            palette_index = CASE WHEN canvas.updated < $4 THEN $6 ELSE canvas.palette_index END,
             updated = CASE WHEN canvas.updated < $4 THEN $4 ELSE canvas.updated END",
             )
-            .await
-            .unwrap();
+            .await?;
+        let colour = match palette_index {
+            Some(_) => None,
+            None => Some(pixel.colour),
+        };
         client
-            .execute(&stmt, &[&pixel.x, &pixel.y, &pixel.colour, &pixel.updated])
+            .execute(
+                &stmt,
+                &[
+                    &pixel.x,
+                    &pixel.y,
+                    &colour,
+                    &pixel.updated,
+                    &pixel.canvas_id,
+                    &palette_index,
+                    &pixel.colour,
+                    &pixel.user,
+                ],
+            )
             .await
     }
 
-    pub async fn insert(
-        client: deadpool::managed::Object<Manager>,
-        data: String,
+    /// Store a batch of pixels in one statement, as `insert_pixel` does for each of them in
+    /// order. The batch is sent as arrays so one cached statement serves batches of any size
+    pub async fn insert_pixels(
+        client: &deadpool::managed::Object<Manager>,
+        pixels: &[Pixel],
+        canvases: &CanvasRegistry,
     ) -> Result<u64, Error> {
-        match serde_json::from_str::<Pixel>(&data) {
-            Ok(pixel) => Pixel::insert_pixel(&client, &pixel).await,
-            Err(_) => {
-                log::error!("Error converting pixel {}", data);
-                Ok(0)
-            }
+        let stmt = client
+            .prepare_cached(
+                "WITH batch AS (
+                SELECT * FROM UNNEST(
+                    $1::integer[], $2::integer[], $3::integer[], $4::integer[], $5::text[],
+                    $6::smallint[], $7::integer[], $8::text[]
+                ) WITH ORDINALITY AS b (x, y, colour, updated, canvas_id, palette_index, raw, user_name, n)
+            ), history AS (
+                INSERT INTO canvas_history (x, y, colour, updated, canvas_id, user_name)
+                SELECT x, y, raw, updated, canvas_id, user_name FROM batch ORDER BY n
+            )
+            INSERT INTO canvas (x, y, colour, updated, canvas_id, palette_index)
+            SELECT DISTINCT ON (canvas_id, x, y) x, y, colour, updated, canvas_id, palette_index
+            FROM batch ORDER BY canvas_id, x, y, updated DESC, n
+            ON CONFLICT (canvas_id, x, y) DO UPDATE SET
+            colour = CASE WHEN canvas.updated < EXCLUDED.updated THEN EXCLUDED.colour ELSE canvas.colour END,
+            palette_index = CASE WHEN canvas.updated < EXCLUDED.updated THEN EXCLUDED.palette_index ELSE canvas.palette_index END,
+            updated = GREATEST(canvas.updated, EXCLUDED.updated)",
+            )
+            .await?;
+        // A row can only be updated once per statement, so only the write that wins at each
+        // position is upserted: the newest, or the first of those written at the same time
+        let mut columns = (
+            Vec::with_capacity(pixels.len()),
+            Vec::with_capacity(pixels.len()),
+            Vec::with_capacity(pixels.len()),
+            Vec::with_capacity(pixels.len()),
+            Vec::with_capacity(pixels.len()),
+            Vec::with_capacity(pixels.len()),
+            Vec::with_capacity(pixels.len()),
+            Vec::with_capacity(pixels.len()),
+        );
+        for pixel in pixels.iter() {
+            let palette_index = canvases.palette_index(pixel);
+            columns.0.push(pixel.x);
+            columns.1.push(pixel.y);
+            columns.2.push(match palette_index {
+                Some(_) => None,
+                None => Some(pixel.colour),
+            });
+            columns.3.push(pixel.updated);
+            columns.4.push(pixel.canvas_id.as_str());
+            columns.5.push(palette_index);
+            columns.6.push(pixel.colour);
+            columns.7.push(pixel.user.as_deref());
         }
+        client
+            .execute(
+                &stmt,
+                &[
+                    &columns.0, &columns.1, &columns.2, &columns.3, &columns.4, &columns.5,
+                    &columns.6, &columns.7,
+                ],
+            )
+            .await
     }
-    pub async fn update_all_vec(
-        client: deadpool::managed::Object<Manager>,
-        pixels: &Vec<Pixel>,
-    ) -> Result<u64, Error> {
-        // Clear previous data
-        let stmt = client.prepare("TRUNCATE TABLE canvas").await.unwrap();
-        let mut result = client.execute(&stmt, &[]).await.unwrap();
 
-        for pixel in pixels.iter() {
-            result += Pixel::insert_pixel(&client, pixel).await.unwrap();
-        }
+    /// Writes stored to a canvas after the history entry `after`, oldest first, with `updated`
+    /// between `since` and `until`
+    pub async fn history<C: GenericClient>(
+        client: &C,
+        canvas_id: &str,
+        after: i64,
+        since: Option<i32>,
+        until: Option<i32>,
+    ) -> Result<Vec<Pixel>, Error> {
+        let stmt = client
+            .prepare_cached(
+                "SELECT x, y, colour, updated, canvas_id, user_name FROM canvas_history
+            WHERE canvas_id = $1 AND id > $2
+            AND ($3::integer IS NULL OR updated >= $3)
+            AND ($4::integer IS NULL OR updated <= $4)
+            ORDER BY id",
+            )
+            .await?;
+        let rows = client
+            .query(&stmt, &[&canvas_id, &after, &since, &until])
+            .await?;
 
-        Ok(result)
+        Ok(rows.into_iter().map(Pixel::from).collect())
+    }
+
+    /// Convert the stored pixels of a canvas to or from palette indices. Colours that aren't
+    /// in the palette are left as colours
+    pub async fn reindex<C: GenericClient>(
+        client: &C,
+        canvas_id: &str,
+        indexed: bool,
+    ) -> Result<u64, Error> {
+        let query = if indexed {
+            "UPDATE canvas c SET palette_index = p.idx, colour = NULL FROM canvas_palette p
+            WHERE c.canvas_id = $1 AND p.canvas_id = c.canvas_id AND p.colour = c.colour"
+        } else {
+            "UPDATE canvas c SET colour = p.colour, palette_index = NULL FROM canvas_palette p
+            WHERE c.canvas_id = $1 AND p.canvas_id = c.canvas_id AND p.idx = c.palette_index"
+        };
+        let stmt = client.prepare_cached(query).await?;
+        client.execute(&stmt, &[&canvas_id]).await
     }
 
-    pub async fn update_all(
-        client: deadpool::managed::Object<Manager>,
-        data: &String,
+    /// Replace every stored pixel in one transaction. The old pixels stay readable until the
+    /// new ones are committed, and a failure part way through leaves them untouched
+    pub async fn update_all_vec(
+        client: &mut deadpool::managed::Object<Manager>,
+        pixels: &[Pixel],
+        canvases: &CanvasRegistry,
     ) -> Result<u64, Error> {
-        // Clear previous data
-        let stmt = client.prepare("TRUNCATE TABLE canvas").await.unwrap();
-        let mut result = client.execute(&stmt, &[]).await.unwrap();
+        let tx = client.transaction().await?;
 
-        let pixels: Vec<Pixel> = serde_json::from_str(&data).unwrap();
+        // DELETE rather than TRUNCATE, which would lock out readers until we commit
+        tx.execute("DELETE FROM canvas", &[]).await?;
+
+        let sink = tx
+            .copy_in(
+                "COPY canvas (x, y, colour, updated, canvas_id, palette_index) FROM STDIN BINARY",
+            )
+            .await?;
+        let writer = BinaryCopyInWriter::new(
+            sink,
+            &[
+                Type::INT4,
+                Type::INT4,
+                Type::INT4,
+                Type::INT4,
+                Type::TEXT,
+                Type::INT2,
+            ],
+        );
+        pin!(writer);
         for pixel in pixels.iter() {
-            result += Pixel::insert_pixel(&client, pixel).await.unwrap();
+            let palette_index = canvases.palette_index(pixel);
+            let colour = match palette_index {
+                Some(_) => None,
+                None => Some(pixel.colour),
+            };
+            writer
+                .as_mut()
+                .write(&[
+                    &pixel.x,
+                    &pixel.y,
+                    &colour,
+                    &pixel.updated,
+                    &pixel.canvas_id,
+                    &palette_index,
+                ])
+                .await?;
         }
+        let result = writer.finish().await?;
 
+        tx.commit().await?;
         Ok(result)
     }
+
+    /// A pixel for tests, written at time 0
+    #[cfg(test)]
+    pub fn test(x: i32, y: i32, colour: i32) -> Pixel {
+        Pixel {
+            x,
+            y,
+            colour,
+            updated: 0,
+            canvas_id: default_canvas_id(),
+            user: None,
+            seq: None,
+        }
+    }
 }
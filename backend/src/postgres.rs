@@ -1,43 +1,447 @@
-use deadpool_postgres::{Config, Pool, Runtime};
+//! Postgres connection settings.
+//!
+//! The database is given by `DATABASE_URL`, a `postgres://` URL or `key=value` connection
+//! string, and the `PG_*` variables override single parts of it:
+//!
+//! | Variable | |
+//! | --- | --- |
+//! | `PG_HOST`, `PG_PORT`, `PG_DBNAME`, `PG_USER`, `PG_PASSWORD` | Where to connect |
+//! | `PG_SSLMODE` | `disable`, `prefer` (default), `require`, `verify-ca` or `verify-full` |
+//! | `PG_SSLROOTCERT` | PEM file of the CAs to trust, the public web CAs otherwise |
+//! | `PG_POOL_SIZE` | Most connections a replica keeps open |
+//! | `PG_POOL_TIMEOUT` | Seconds to wait for a free connection |
+//! | `PG_CONNECT_TIMEOUT` | Seconds to wait when opening a connection, default 10 |
+//! | `PG_CONNECT_RETRIES` | Attempts to reach the database on startup, default 10 |
+
+use deadpool_postgres::{
+    Config, CreatePoolError, Pool, PoolConfig, PoolError, Runtime, SslMode, Timeouts,
+};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::client::WebPkiServerVerifier;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{
+    CertificateError, ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme,
+};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
 use tokio_postgres::NoTls;
-use tokio_postgres_migration::Migration;
+use tokio_postgres_rustls::MakeRustlsConnect;
+
+const DEFAULT_CONNECT_TIMEOUT: u64 = 10;
+const DEFAULT_CONNECT_RETRIES: u32 = 10;
+const FIRST_RETRY_DELAY: Duration = Duration::from_millis(500);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+#[derive(Debug)]
+pub enum ConfigError {
+    /// A variable couldn't be parsed
+    Invalid {
+        name: &'static str,
+        value: String,
+    },
+    Tls(String),
+    Pool(CreatePoolError),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Invalid { name, value } => write!(f, "invalid {}: {}", name, value),
+            ConfigError::Tls(message) => write!(f, "couldn't set up TLS: {}", message),
+            ConfigError::Pool(e) => write!(f, "couldn't create postgres pool: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// How much of the server's certificate is checked, like libpq's `sslmode`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TlsMode {
+    Disable,
+    /// Use TLS when the server supports it, without checking its certificate
+    Prefer,
+    /// Always use TLS, without checking the server's certificate
+    Require,
+    /// Check the certificate is signed by a trusted CA
+    VerifyCa,
+    /// Also check the certificate is for the host connected to
+    VerifyFull,
+}
+
+impl FromStr for TlsMode {
+    type Err = ();
+
+    fn from_str(mode: &str) -> Result<Self, Self::Err> {
+        match mode {
+            "disable" => Ok(TlsMode::Disable),
+            "prefer" => Ok(TlsMode::Prefer),
+            "require" => Ok(TlsMode::Require),
+            "verify-ca" => Ok(TlsMode::VerifyCa),
+            "verify-full" => Ok(TlsMode::VerifyFull),
+            _ => Err(()),
+        }
+    }
+}
+
+fn var(name: &'static str) -> Option<String> {
+    std::env::var(name).ok().filter(|value| !value.is_empty())
+}
 
-const SCRIPTS_UP: [(&str, &str); 1] = [(
-    "0001_create-database",
-    include_str!("../migrations/0001_create-database.sql"),
-)];
+fn parse_var<T: FromStr>(name: &'static str) -> Result<Option<T>, ConfigError> {
+    match var(name) {
+        Some(value) => match value.parse() {
+            Ok(parsed) => Ok(Some(parsed)),
+            Err(_) => Err(ConfigError::Invalid { name, value }),
+        },
+        None => Ok(None),
+    }
+}
 
-fn create_config() -> Config {
+/// The connection settings and how to check the server's certificate
+fn create_config() -> Result<(Config, TlsMode), ConfigError> {
     let mut cfg = Config::new();
-    if let Ok(host) = std::env::var("PG_HOST") {
-        cfg.host = Some(host);
+    let mut url_mode = None;
+    if let Some(url) = var("DATABASE_URL") {
+        let (url, mode) = take_sslmode(&url);
+        cfg.url = Some(url);
+        url_mode = mode;
+    }
+    cfg.host = var("PG_HOST");
+    cfg.dbname = var("PG_DBNAME");
+    cfg.user = var("PG_USER");
+    cfg.password = var("PG_PASSWORD");
+    cfg.port = parse_var("PG_PORT")?;
+    cfg.connect_timeout = Some(Duration::from_secs(
+        parse_var("PG_CONNECT_TIMEOUT")?.unwrap_or(DEFAULT_CONNECT_TIMEOUT),
+    ));
+
+    let mut pool = PoolConfig::default();
+    if let Some(size) = parse_var("PG_POOL_SIZE")? {
+        pool.max_size = size;
+    }
+    let wait = parse_var("PG_POOL_TIMEOUT")?.map(Duration::from_secs);
+    pool.timeouts = Timeouts {
+        wait,
+        create: cfg.connect_timeout,
+        recycle: cfg.connect_timeout,
+    };
+    cfg.pool = Some(pool);
+    Ok((cfg, tls_mode(url_mode)?))
+}
+
+/// `PG_SSLMODE`, or the `sslmode` of `DATABASE_URL`
+fn tls_mode(url_mode: Option<String>) -> Result<TlsMode, ConfigError> {
+    let (name, mode) = match (var("PG_SSLMODE"), url_mode) {
+        (Some(mode), _) => ("PG_SSLMODE", mode),
+        (None, Some(mode)) => ("DATABASE_URL", mode),
+        (None, None) => return Ok(TlsMode::Prefer),
+    };
+    mode.parse()
+        .map_err(|_| ConfigError::Invalid { name, value: mode })
+}
+
+/// Split the `sslmode` out of a connection string. tokio-postgres doesn't know `verify-ca`
+/// and `verify-full` and would refuse the whole string, the rest is left for it to parse
+fn take_sslmode(url: &str) -> (String, Option<String>) {
+    if url.starts_with("postgres://") || url.starts_with("postgresql://") {
+        let (base, query) = match url.split_once('?') {
+            Some(parts) => parts,
+            None => return (url.to_string(), None),
+        };
+        let mut mode = None;
+        let params: Vec<&str> = query
+            .split('&')
+            .filter(|param| match param.strip_prefix("sslmode=") {
+                Some(value) => {
+                    mode = Some(value.to_string());
+                    false
+                }
+                None => true,
+            })
+            .collect();
+        let url = if params.is_empty() {
+            base.to_string()
+        } else {
+            format!("{}?{}", base, params.join("&"))
+        };
+        return (url, mode);
+    }
+
+    let pairs = match connection_pairs(url) {
+        Some(pairs) => pairs,
+        // Left for tokio-postgres to report
+        None => return (url.to_string(), None),
+    };
+    let mut mode = None;
+    let mut kept = Vec::new();
+    for (key, value) in pairs {
+        if key == "sslmode" {
+            mode = Some(value.trim_matches('\'').to_string());
+        } else {
+            kept.push(format!("{}={}", key, value));
+        }
+    }
+    (kept.join(" "), mode)
+}
+
+/// The `key = value` pairs of a connection string, values may be quoted with `'` and
+/// characters escaped with `\`
+fn connection_pairs(s: &str) -> Option<Vec<(&str, &str)>> {
+    let bytes = s.as_bytes();
+    let skip_space = |mut i: usize| {
+        while i < bytes.len() && bytes[i].is_ascii_whitespace() {
+            i += 1;
+        }
+        i
+    };
+    let mut pairs = Vec::new();
+    let mut i = skip_space(0);
+    while i < bytes.len() {
+        let start = i;
+        while i < bytes.len() && bytes[i] != b'=' && !bytes[i].is_ascii_whitespace() {
+            i += 1;
+        }
+        let key = &s[start..i];
+        i = skip_space(i);
+        if bytes.get(i) != Some(&b'=') {
+            return None;
+        }
+        i = skip_space(i + 1);
+        let value_start = i;
+        let quoted = bytes.get(i) == Some(&b'\'');
+        if quoted {
+            i += 1;
+        }
+        loop {
+            match bytes.get(i) {
+                None if quoted => return None,
+                None => break,
+                Some(b'\\') => i += 2,
+                Some(b'\'') if quoted => {
+                    i += 1;
+                    break;
+                }
+                Some(b) if !quoted && b.is_ascii_whitespace() => break,
+                Some(_) => i += 1,
+            }
+        }
+        let value_end = i.min(bytes.len());
+        pairs.push((key, &s[value_start..value_end]));
+        i = skip_space(value_end);
     }
-    if let Ok(dbname) = std::env::var("PG_DBNAME") {
-        cfg.dbname = Some(dbname);
+    Some(pairs)
+}
+
+fn root_certs() -> Result<RootCertStore, ConfigError> {
+    let mut roots = RootCertStore::empty();
+    match var("PG_SSLROOTCERT") {
+        Some(path) => {
+            let certs = CertificateDer::pem_file_iter(&path)
+                .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+                .map_err(|e| ConfigError::Tls(format!("couldn't read {}: {}", path, e)))?;
+            let (added, _) = roots.add_parsable_certificates(certs);
+            if added == 0 {
+                return Err(ConfigError::Tls(format!("no certificates in {}", path)));
+            }
+        }
+        None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
     }
-    if let Ok(user) = std::env::var("PG_USER") {
-        cfg.user = Some(user);
+    Ok(roots)
+}
+
+/// Checks as much of the server's certificate as the `TlsMode` asks for. The handshake
+/// signatures are always checked
+#[derive(Debug)]
+struct Verifier {
+    mode: TlsMode,
+    webpki: Arc<WebPkiServerVerifier>,
+}
+
+impl ServerCertVerifier for Verifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let verified = self.webpki.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            ocsp_response,
+            now,
+        );
+        match (self.mode, verified) {
+            (TlsMode::VerifyFull, verified) => verified,
+            (
+                TlsMode::VerifyCa,
+                Err(rustls::Error::InvalidCertificate(
+                    CertificateError::NotValidForName
+                    | CertificateError::NotValidForNameContext { .. },
+                )),
+            ) => Ok(ServerCertVerified::assertion()),
+            (TlsMode::VerifyCa, verified) => verified,
+            _ => Ok(ServerCertVerified::assertion()),
+        }
     }
-    if let Ok(password) = std::env::var("PG_PASSWORD") {
-        cfg.password = Some(password);
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.webpki.verify_tls12_signature(message, cert, dss)
     }
-    if let Ok(port) = std::env::var("PG_PORT") {
-        cfg.port = Some(port.parse::<u16>().unwrap());
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.webpki.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.webpki.supported_verify_schemes()
+    }
+}
+
+fn tls_connector(mode: TlsMode) -> Result<MakeRustlsConnect, ConfigError> {
+    let webpki = WebPkiServerVerifier::builder(Arc::new(root_certs()?))
+        .build()
+        .map_err(|e| ConfigError::Tls(e.to_string()))?;
+    let config = ClientConfig::builder()
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(Verifier { mode, webpki }))
+        .with_no_client_auth();
+    Ok(MakeRustlsConnect::new(config))
+}
+
+pub fn create_pool() -> Result<Pool, ConfigError> {
+    let (mut cfg, mode) = create_config()?;
+    log::info!("Connecting to postgres with sslmode {:?}", mode);
+    if mode == TlsMode::Disable {
+        return cfg
+            .create_pool(Some(Runtime::Tokio1), NoTls)
+            .map_err(ConfigError::Pool);
     }
-    cfg
+    // tokio-postgres only knows whether TLS is optional, the certificate is checked above
+    cfg.ssl_mode = Some(match mode {
+        TlsMode::Prefer => SslMode::Prefer,
+        _ => SslMode::Require,
+    });
+    cfg.create_pool(Some(Runtime::Tokio1), tls_connector(mode)?)
+        .map_err(ConfigError::Pool)
 }
 
-pub fn create_pool() -> Pool {
-    create_config()
-        .create_pool(Some(Runtime::Tokio1), NoTls)
-        .expect("couldn't create postgres pool")
+/// Wait for the database to accept connections, retrying with exponential backoff
+/// `PG_CONNECT_RETRIES` times. Replicas are often started alongside their database
+pub async fn wait_for_database(pool: &Pool) -> Result<(), PoolError> {
+    let retries = parse_var("PG_CONNECT_RETRIES")
+        .ok()
+        .flatten()
+        .unwrap_or(DEFAULT_CONNECT_RETRIES);
+    let mut delay = FIRST_RETRY_DELAY;
+    let mut attempt = 0;
+    loop {
+        match pool.get().await {
+            Ok(_) => return Ok(()),
+            Err(e) if attempt < retries => {
+                attempt += 1;
+                log::warn!(
+                    "Couldn't reach postgres ({}), retrying in {:?} ({}/{})",
+                    e,
+                    delay,
+                    attempt,
+                    retries
+                );
+                tokio::time::sleep(delay).await;
+                delay = (delay * 2).min(MAX_RETRY_DELAY);
+            }
+            Err(e) => return Err(e),
+        }
+    }
 }
 
-pub async fn migrate_up(pool: &Pool) {
-    let mut client = pool.get().await.expect("couldn't get postgres client");
-    let migration = Migration::new("migrations".to_string());
-    migration
-        .up(&mut **client, &SCRIPTS_UP)
-        .await
-        .expect("couldn't run migrations");
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MODES: [(&str, TlsMode); 5] = [
+        ("disable", TlsMode::Disable),
+        ("prefer", TlsMode::Prefer),
+        ("require", TlsMode::Require),
+        ("verify-ca", TlsMode::VerifyCa),
+        ("verify-full", TlsMode::VerifyFull),
+    ];
+
+    fn url_mode(url: &str) -> (String, TlsMode) {
+        let (rest, mode) = take_sslmode(url);
+        tokio_postgres::Config::from_str(&rest).expect("tokio-postgres takes the rest");
+        (rest, mode.expect("sslmode").parse().expect("known sslmode"))
+    }
+
+    #[test]
+    fn sslmodes_of_urls() {
+        for (name, mode) in MODES.iter() {
+            let url = format!("postgres://u:p@db.example.com/canvas?sslmode={}", name);
+            let (rest, parsed) = url_mode(&url);
+            assert_eq!(parsed, *mode, "{}", name);
+            assert_eq!(rest, "postgres://u:p@db.example.com/canvas");
+        }
+    }
+
+    #[test]
+    fn sslmodes_between_url_params() {
+        for (name, mode) in MODES.iter() {
+            let url = format!(
+                "postgresql://db.example.com/canvas?connect_timeout=5&sslmode={}&user=u",
+                name
+            );
+            let (rest, parsed) = url_mode(&url);
+            assert_eq!(parsed, *mode, "{}", name);
+            assert_eq!(
+                rest,
+                "postgresql://db.example.com/canvas?connect_timeout=5&user=u"
+            );
+        }
+    }
+
+    #[test]
+    fn sslmodes_of_connection_strings() {
+        for (name, mode) in MODES.iter() {
+            let url = format!("host=db.example.com sslmode = {} dbname=canvas", name);
+            let (rest, parsed) = url_mode(&url);
+            assert_eq!(parsed, *mode, "{}", name);
+            assert_eq!(rest, "host=db.example.com dbname=canvas");
+
+            let quoted = format!("sslmode='{}' password='a \\' b'", name);
+            let (rest, parsed) = url_mode(&quoted);
+            assert_eq!(parsed, *mode, "{}", name);
+            assert_eq!(rest, "password='a \\' b'");
+        }
+    }
+
+    #[test]
+    fn no_sslmode() {
+        let url = "postgres://u:p@db.example.com/canvas";
+        assert_eq!(take_sslmode(url), (url.to_string(), None));
+        let url = "host=db.example.com dbname=canvas";
+        assert_eq!(take_sslmode(url), (url.to_string(), None));
+    }
+
+    #[test]
+    fn unknown_sslmode() {
+        let (_, mode) = take_sslmode("postgres://db.example.com?sslmode=sometimes");
+        assert_eq!(mode.as_deref(), Some("sometimes"));
+        assert!("sometimes".parse::<TlsMode>().is_err());
+    }
 }
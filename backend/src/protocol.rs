@@ -0,0 +1,673 @@
+//! Messages exchanged over `/ws`.
+//!
+//! Version 1 is the text protocol the proxy speaks and what a connection gets unless it asks
+//! for another: the client sends bare pixels, and gets back `primary`, bare replicated pixels
+//! and `{"command": "error", ...}` objects.
+//!
+//! Writes sent to clients carry the `seq` the primary numbered them with, see `replay`. A
+//! connection that dropped can send `resume since=<seq>` (`resume` in version 2) on its new
+//! connection to get the writes it missed, or the whole canvas if they're no longer kept.
+//!
+//! Version 2 is asked for with `?protocol=2` or the `canvas.v2` subprotocol. Every frame is a
+//! JSON object with a `type`, and requests may carry an `id`, a number or a string, that is
+//! echoed in the reply:
+//!
+//! | Client | |
+//! | --- | --- |
+//! | `{"type": "set_pixel", "id": 1, "x": 0, "y": 0, "colour": 255}` | Write a pixel, `updated` defaults to now |
+//! | `{"type": "ping", "id": 2}` | Answered with `pong` |
+//! | `{"type": "subscribe", "id": 3, "x": 0, "y": 0, "width": 100, "height": 50}` | Only get `pixel_update`s inside the region, and the other subscribed regions |
+//! | `{"type": "subscribe", "id": 4, "tile": [1, 0]}` | Same for a `TILE_SIZE` square tile |
+//! | `{"type": "unsubscribe", "id": 5, "tile": [1, 0]}` | Stop getting the updates of a region, or of a tile. Without any region left the connection gets every update again |
+//! | `{"type": "resume", "id": 6, "since": 120}` | Get the writes after `seq` 120 |
+//!
+//! | Server | |
+//! | --- | --- |
+//! | `{"type": "hello", "version": 2, "canvas": "default"}` | Sent once the connection is open |
+//! | `{"type": "ack", "id": 1}` | The write was accepted and sent around the ring |
+//! | `{"type": "nack", "id": 1, "error": {"code": "out_of_bounds", ...}}` | The write was refused, `error` is the same as in version 1 |
+//! | `{"type": "error", "id": 1, "code": "malformed", "message": "..."}` | The frame couldn't be read, `id` is echoed when it could be found |
+//! | `{"type": "pong", "id": 2}` | |
+//! | `{"type": "subscribed", "id": 3, "region": {...}}` | |
+//! | `{"type": "unsubscribed", "id": 5, "region": {...}}` | |
+//! | `{"type": "resumed", "id": 6, "since": 120, "seq": 130, "replayed": 4}` | Followed by the `pixel_update`s replayed |
+//! | `{"type": "snapshot", "id": 6, "seq": 130, "canvas": {...}, "pixels": [...]}` | The writes since couldn't be replayed, this is the whole canvas as of `seq` |
+//! | `{"type": "pixel_batch", "seq": 131, "pixels": [...]}` | Writes of other connections, with `?batch=json`, see `batch` |
+//! | `{"type": "resync_required"}` | The connection fell too far behind and is closed, see `session_queue` |
+//! | `{"type": "primary"}` | This replica became the primary |
+//! | `{"type": "leader", "primary": 2, "address": "10.0.0.2:8000"}` | Another replica became the primary |
+//! | `{"type": "pixel_update", "pixel": {...}}` | A write from another connection reached every replica |
+//! | `{"type": "replicated", "id": 1, "pixel": {...}}` | A write from this connection reached every replica |
+//! | `{"type": "unreplicated", "id": 1, "pixel": {...}, "current": {...}}` | A write from this connection didn't make it around the ring, `current` is what the primary has at that position |
+//!
+//! Version 1 connections get bare pixels for writes of other connections, and
+//! `{"command": "replicated", "payload": <pixel>}` or
+//! `{"command": "unreplicated", "payload": {"pixel": ..., "current": ...}}` for their own. They
+//! are answered `{"command": "resumed", "payload": {"since": ..., "seq": ..., "replayed": ...}}`
+//! or the canvas as `{"command": "get_pixels", "payload": [...], "canvas": {...}, "seq": ...}`.
+//! With `?batch=json` they get `{"command": "pixel_batch", "seq": ..., "payload": [...]}`, and
+//! `{"command": "resync_required"}` when they fall too far behind.
+//!
+//! With `?batch=binary` either version gets batches as binary frames, see `encode_batch`.
+//!
+//! With `?encoding=binary` either version can also write pixels with binary frames, and gets
+//! writes as binary frames instead of JSON. Every binary frame starts with a byte for its kind
+//! followed by fixed size records of `RECORD_SIZE` bytes, a pixel's `x`, `y` and `colour` as
+//! big endian `i32`s. Other messages stay JSON.
+//!
+//! | Frame | |
+//! | --- | --- |
+//! | `0x01`, records | Client: write each pixel with `updated` now. A rejected pixel is answered like a rejected JSON write, the `id` of a `nack` is the index of the record |
+//! | `0x02`, `seq: u64`, records | Writes of other connections, `seq` is the newest of them |
+//! | `0x03`, `seq: u64`, record | A write from this connection reached every replica |
+//! | `0x04`, record, record | A write from this connection didn't make it around the ring, and the pixel the primary has at that position |
+
+use crate::canvas::{CanvasMeta, Region, WriteError};
+use crate::pixel::{self, Pixel};
+use crate::subscriptions;
+use crate::ConnId;
+use actix_web::http::header::{HeaderValue, SEC_WEBSOCKET_PROTOCOL};
+use actix_web::HttpRequest;
+use serde::Deserialize;
+
+/// Newest version of the protocol
+pub const VERSION: u32 = 2;
+
+/// Subprotocol a browser can offer to get version 2
+pub const SUBPROTOCOL: &str = "canvas.v2";
+
+/// Protocol spoken on one connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    /// Version 1
+    Legacy,
+    V2,
+}
+
+impl Protocol {
+    /// Version asked for by `?protocol=` or, failing that, the offered subprotocols. Fails with
+    /// the requested version when it isn't one this replica speaks
+    pub fn negotiate(req: &HttpRequest, requested: Option<u32>) -> Result<Protocol, u32> {
+        match requested {
+            Some(1) => return Ok(Protocol::Legacy),
+            Some(2) => return Ok(Protocol::V2),
+            Some(version) => return Err(version),
+            None => {}
+        }
+        if offered_subprotocol(req) {
+            Ok(Protocol::V2)
+        } else {
+            Ok(Protocol::Legacy)
+        }
+    }
+
+    /// `Sec-WebSocket-Protocol` to answer with, browsers drop the connection if a subprotocol
+    /// they offered isn't confirmed
+    pub fn response_header(&self, req: &HttpRequest) -> Option<HeaderValue> {
+        (*self == Protocol::V2 && offered_subprotocol(req))
+            .then(|| HeaderValue::from_static(SUBPROTOCOL))
+    }
+}
+
+fn offered_subprotocol(req: &HttpRequest) -> bool {
+    req.headers()
+        .get_all(SEC_WEBSOCKET_PROTOCOL)
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|protocol| protocol.trim() == SUBPROTOCOL)
+}
+
+/// Id a client gave a request
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(untagged)]
+pub enum RequestId {
+    Number(u64),
+    Text(String),
+}
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientMessage {
+    SetPixel {
+        id: Option<RequestId>,
+        x: i32,
+        y: i32,
+        colour: i32,
+        updated: Option<i32>,
+    },
+    Ping {
+        id: Option<RequestId>,
+    },
+    Subscribe {
+        id: Option<RequestId>,
+        #[serde(flatten)]
+        area: Area,
+    },
+    Unsubscribe {
+        id: Option<RequestId>,
+        #[serde(flatten)]
+        area: Area,
+    },
+    Resume {
+        id: Option<RequestId>,
+        since: u64,
+    },
+}
+
+/// Part of a canvas to subscribe to
+#[derive(Debug, serde::Deserialize)]
+#[serde(untagged)]
+pub enum Area {
+    Tile { tile: [i32; 2] },
+    Region(Region),
+}
+
+impl Area {
+    pub fn region(&self) -> Region {
+        match self {
+            Area::Tile { tile: [x, y] } => subscriptions::tile(*x, *y),
+            Area::Region(region) => *region,
+        }
+    }
+}
+
+/// Connection a write came from and the id it gave the request, so it can be told what became
+/// of the write
+#[derive(Debug, Clone)]
+pub struct Origin {
+    pub conn: ConnId,
+    pub id: Option<RequestId>,
+}
+
+/// A write that made it around the ring, sent to the connection it came from as
+/// `written: <json>`. Every other connection gets the pixel as `replicated: <pixel>`
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct ReplicatedWrite {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<RequestId>,
+    pub pixel: Pixel,
+}
+
+impl ReplicatedWrite {
+    /// Version 1 message for the write
+    pub fn to_legacy_message(&self) -> String {
+        serde_json::json!({ "command": "replicated", "payload": self.pixel }).to_string()
+    }
+}
+
+/// A write that didn't make it around the ring, sent to the connection it came from as
+/// `unreplicated: <json>`
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct FailedWrite {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<RequestId>,
+    pub pixel: Pixel,
+    /// The pixel at that position on the primary, or the background if it was never written
+    pub current: Pixel,
+}
+
+impl FailedWrite {
+    /// Version 1 message for the write
+    pub fn to_legacy_message(&self) -> String {
+        serde_json::json!({
+            "command": "unreplicated",
+            "payload": { "pixel": self.pixel, "current": self.current },
+        })
+        .to_string()
+    }
+}
+
+/// Version 1 `resume since=<seq>`, None if the text isn't one
+pub fn parse_legacy_resume(text: &str) -> Option<Result<u64, std::num::ParseIntError>> {
+    text.trim()
+        .strip_prefix("resume since=")
+        .map(|since| since.parse())
+}
+
+/// Another replica became the primary, sent to every connection as `leader: <json>`
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Leader {
+    pub primary: u16,
+    /// Where clients connect to it
+    pub address: Option<String>,
+}
+
+/// Answer to a `resume` that could be replayed, sent to the connection as `resumed: <json>`
+/// ahead of the writes
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Resumed {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<RequestId>,
+    pub since: u64,
+    /// Newest write number
+    pub seq: u64,
+    pub replayed: usize,
+}
+
+impl Resumed {
+    /// Version 1 message
+    pub fn to_legacy_message(&self) -> String {
+        serde_json::json!({
+            "command": "resumed",
+            "payload": { "since": self.since, "seq": self.seq, "replayed": self.replayed },
+        })
+        .to_string()
+    }
+}
+
+/// Answer to a `resume` that couldn't be replayed, sent as `snapshot: <json>`
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Resync {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<RequestId>,
+    /// Newest write number, writes after it are sent like any other
+    pub seq: u64,
+    pub canvas: CanvasMeta,
+    pub pixels: Vec<Pixel>,
+}
+
+impl Resync {
+    /// Version 1 message, the same as `GET /canvas` so the proxy can pass it on
+    pub fn to_legacy_message(&self) -> String {
+        serde_json::json!({
+            "command": "get_pixels",
+            "payload": self.pixels,
+            "canvas": self.canvas,
+            "seq": self.seq,
+        })
+        .to_string()
+    }
+}
+
+/// How writes are sent to a connection, see the module docs
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+    #[default]
+    Json,
+    Binary,
+}
+
+/// Size of a pixel in binary frames: `x`, `y` and `colour` as big endian `i32`s
+pub const RECORD_SIZE: usize = 12;
+
+/// First byte of a binary frame of pixels to write
+pub const SET_PIXELS_FRAME: u8 = 0x01;
+
+/// First byte of a binary batch of writes
+pub const BATCH_FRAME: u8 = 0x02;
+
+/// First byte of a binary frame for a write of the connection that was replicated
+pub const REPLICATED_FRAME: u8 = 0x03;
+
+/// First byte of a binary frame for a write of the connection that wasn't replicated
+pub const UNREPLICATED_FRAME: u8 = 0x04;
+
+fn write_record(frame: &mut Vec<u8>, pixel: &Pixel) {
+    frame.extend_from_slice(&pixel.x.to_be_bytes());
+    frame.extend_from_slice(&pixel.y.to_be_bytes());
+    frame.extend_from_slice(&pixel.colour.to_be_bytes());
+}
+
+/// `x`, `y` and `colour` of each record in a `SET_PIXELS_FRAME`
+pub fn decode_set_pixels(frame: &[u8]) -> Result<Vec<(i32, i32, i32)>, String> {
+    let Some((&kind, records)) = frame.split_first() else {
+        return Err("empty frame".to_string());
+    };
+    if kind != SET_PIXELS_FRAME {
+        return Err(format!("unknown frame kind {:#04x}", kind));
+    }
+    if records.is_empty() || records.len() % RECORD_SIZE != 0 {
+        return Err(format!(
+            "expected records of {} bytes, got {} bytes",
+            RECORD_SIZE,
+            records.len()
+        ));
+    }
+    let field = |record: &[u8], at: usize| {
+        i32::from_be_bytes([record[at], record[at + 1], record[at + 2], record[at + 3]])
+    };
+    Ok(records
+        .chunks_exact(RECORD_SIZE)
+        .map(|record| (field(record, 0), field(record, 4), field(record, 8)))
+        .collect())
+}
+
+/// Whether a message from the replica manager is about a write, and has a binary frame
+pub fn has_binary_form(msg: &str) -> bool {
+    ["replicated: ", "written: ", "unreplicated: "]
+        .iter()
+        .any(|prefix| msg.starts_with(prefix))
+}
+
+/// Binary frame for a message from the replica manager, if it has one
+pub fn binary_from_manager(msg: &str) -> Option<Vec<u8>> {
+    if let Some(pixel) = msg.strip_prefix("replicated: ") {
+        let pixel: Pixel = serde_json::from_str(pixel).ok()?;
+        return Some(encode_batch(&[pixel]));
+    }
+    if let Some(written) = msg.strip_prefix("written: ") {
+        let written: ReplicatedWrite = serde_json::from_str(written).ok()?;
+        let mut frame = Vec::with_capacity(9 + RECORD_SIZE);
+        frame.push(REPLICATED_FRAME);
+        frame.extend_from_slice(&written.pixel.seq.unwrap_or_default().to_be_bytes());
+        write_record(&mut frame, &written.pixel);
+        return Some(frame);
+    }
+    if let Some(failed) = msg.strip_prefix("unreplicated: ") {
+        let failed: FailedWrite = serde_json::from_str(failed).ok()?;
+        let mut frame = Vec::with_capacity(1 + 2 * RECORD_SIZE);
+        frame.push(UNREPLICATED_FRAME);
+        write_record(&mut frame, &failed.pixel);
+        write_record(&mut frame, &failed.current);
+        return Some(frame);
+    }
+    None
+}
+
+/// Newest write number of a batch
+pub fn newest_seq(pixels: &[Pixel]) -> Option<u64> {
+    pixels.iter().filter_map(|pixel| pixel.seq).max()
+}
+
+/// Binary batch of writes: `BATCH_FRAME`, the newest `seq` of the batch as a big endian `u64`
+/// (0 if none has one), then a `RECORD_SIZE` record for each pixel
+pub fn encode_batch(pixels: &[Pixel]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(9 + pixels.len() * RECORD_SIZE);
+    frame.push(BATCH_FRAME);
+    frame.extend_from_slice(&newest_seq(pixels).unwrap_or_default().to_be_bytes());
+    for pixel in pixels {
+        write_record(&mut frame, pixel);
+    }
+    frame
+}
+
+/// Version 1 message for a connection that fell too far behind
+pub fn legacy_resync_required() -> String {
+    serde_json::json!({ "command": "resync_required" }).to_string()
+}
+
+/// Version 1 text batch of writes
+pub fn legacy_batch(pixels: &[Pixel]) -> String {
+    serde_json::json!({
+        "command": "pixel_batch",
+        "seq": newest_seq(pixels),
+        "payload": pixels,
+    })
+    .to_string()
+}
+
+/// A frame that couldn't be read, with the id of the request when it had one
+#[derive(Debug)]
+pub struct FrameError {
+    pub id: Option<RequestId>,
+    pub message: String,
+}
+
+impl ClientMessage {
+    pub fn parse(text: &str) -> Result<ClientMessage, FrameError> {
+        let value: serde_json::Value = serde_json::from_str(text).map_err(|e| FrameError {
+            id: None,
+            message: e.to_string(),
+        })?;
+        let id = value
+            .get("id")
+            .and_then(|id| RequestId::deserialize(id).ok());
+        ClientMessage::deserialize(value).map_err(|e| FrameError {
+            id,
+            message: e.to_string(),
+        })
+    }
+}
+
+/// A pixel written with `set_pixel`, as the replica manager takes it
+pub fn pixel_write(x: i32, y: i32, colour: i32, updated: Option<i32>, canvas: &str) -> String {
+    let pixel = Pixel {
+        x,
+        y,
+        colour,
+        updated: updated.unwrap_or_else(pixel::now),
+        canvas_id: canvas.to_string(),
+        user: None,
+        seq: None,
+    };
+    serde_json::to_string(&pixel).unwrap()
+}
+
+#[derive(Debug, serde::Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerMessage {
+    Hello {
+        version: u32,
+        canvas: String,
+    },
+    Ack {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        id: Option<RequestId>,
+    },
+    Nack {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        id: Option<RequestId>,
+        error: WriteError,
+    },
+    Error {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        id: Option<RequestId>,
+        code: &'static str,
+        message: String,
+    },
+    Pong {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        id: Option<RequestId>,
+    },
+    Subscribed {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        id: Option<RequestId>,
+        region: Region,
+    },
+    Unsubscribed {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        id: Option<RequestId>,
+        region: Region,
+    },
+    Primary,
+    Leader(Leader),
+    PixelUpdate {
+        pixel: Pixel,
+    },
+    PixelBatch {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        seq: Option<u64>,
+        pixels: Vec<Pixel>,
+    },
+    ResyncRequired,
+    Replicated(ReplicatedWrite),
+    Unreplicated(FailedWrite),
+    Resumed(Resumed),
+    Snapshot(Resync),
+}
+
+impl ServerMessage {
+    /// The frame for a message from the replica manager, if clients are told about it
+    pub fn from_manager(msg: &str) -> Option<ServerMessage> {
+        if msg == "primary" {
+            return Some(ServerMessage::Primary);
+        }
+        if let Some(leader) = msg.strip_prefix("leader: ") {
+            return match serde_json::from_str(leader) {
+                Ok(leader) => Some(ServerMessage::Leader(leader)),
+                Err(e) => {
+                    log::error!("Unreadable leader {}: {}", leader, e);
+                    None
+                }
+            };
+        }
+        if let Some(resumed) = msg.strip_prefix("resumed: ") {
+            return match serde_json::from_str(resumed) {
+                Ok(resumed) => Some(ServerMessage::Resumed(resumed)),
+                Err(e) => {
+                    log::error!("Unreadable resume {}: {}", resumed, e);
+                    None
+                }
+            };
+        }
+        if let Some(resync) = msg.strip_prefix("snapshot: ") {
+            return match serde_json::from_str(resync) {
+                Ok(resync) => Some(ServerMessage::Snapshot(resync)),
+                Err(e) => {
+                    log::error!("Unreadable snapshot {}: {}", resync, e);
+                    None
+                }
+            };
+        }
+        if let Some(written) = msg.strip_prefix("written: ") {
+            return match serde_json::from_str(written) {
+                Ok(written) => Some(ServerMessage::Replicated(written)),
+                Err(e) => {
+                    log::error!("Unreadable replicated write {}: {}", written, e);
+                    None
+                }
+            };
+        }
+        if let Some(failed) = msg.strip_prefix("unreplicated: ") {
+            return match serde_json::from_str(failed) {
+                Ok(failed) => Some(ServerMessage::Unreplicated(failed)),
+                Err(e) => {
+                    log::error!("Unreadable unreplicated write {}: {}", failed, e);
+                    None
+                }
+            };
+        }
+        if let Some(pixel) = msg.strip_prefix("replicated: ") {
+            return match serde_json::from_str(pixel) {
+                Ok(pixel) => Some(ServerMessage::PixelUpdate { pixel }),
+                Err(e) => {
+                    log::error!("Unreadable replicated pixel {}: {}", pixel, e);
+                    None
+                }
+            };
+        }
+        None
+    }
+
+    pub fn to_text(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(x: i32, y: i32, colour: i32) -> Vec<u8> {
+        [x, y, colour]
+            .iter()
+            .flat_map(|v| v.to_be_bytes())
+            .collect()
+    }
+
+    fn set_pixels(records: &[(i32, i32, i32)]) -> Vec<u8> {
+        let mut frame = vec![SET_PIXELS_FRAME];
+        for &(x, y, colour) in records {
+            frame.extend(record(x, y, colour));
+        }
+        frame
+    }
+
+    /// A pixel numbered `seq` around the ring
+    fn numbered(x: i32, y: i32, colour: i32, seq: u64) -> Pixel {
+        Pixel {
+            seq: Some(seq),
+            ..Pixel::test(x, y, colour)
+        }
+    }
+
+    #[test]
+    fn decodes_records() {
+        let records = [(1, 2, 0xff0000), (-1, i32::MAX, 0), (i32::MIN, 0, -1)];
+        assert_eq!(
+            decode_set_pixels(&set_pixels(&records)),
+            Ok(records.to_vec())
+        );
+    }
+
+    #[test]
+    fn refuses_truncated_records() {
+        let frame = set_pixels(&[(1, 2, 3), (4, 5, 6)]);
+        for len in [frame.len() - 1, frame.len() - 4, 1 + RECORD_SIZE + 1, 2] {
+            assert!(decode_set_pixels(&frame[..len]).is_err(), "{} bytes", len);
+        }
+    }
+
+    #[test]
+    fn refuses_odd_lengths() {
+        let mut frame = set_pixels(&[(1, 2, 3)]);
+        frame.push(0);
+        assert_eq!(
+            decode_set_pixels(&frame),
+            Err(format!(
+                "expected records of {} bytes, got {} bytes",
+                RECORD_SIZE,
+                RECORD_SIZE + 1
+            ))
+        );
+    }
+
+    #[test]
+    fn refuses_empty_and_unknown_frames() {
+        assert_eq!(decode_set_pixels(&[]), Err("empty frame".to_string()));
+        assert!(decode_set_pixels(&[SET_PIXELS_FRAME]).is_err());
+        let mut frame = set_pixels(&[(1, 2, 3)]);
+        frame[0] = BATCH_FRAME;
+        assert_eq!(
+            decode_set_pixels(&frame),
+            Err("unknown frame kind 0x02".to_string())
+        );
+    }
+
+    #[test]
+    fn encodes_batches() {
+        let frame = encode_batch(&[numbered(1, 2, 3, 7), numbered(4, 5, 6, 9)]);
+        assert_eq!(frame.len(), 9 + 2 * RECORD_SIZE);
+        assert_eq!(frame[0], BATCH_FRAME);
+        assert_eq!(frame[1..9], 9u64.to_be_bytes());
+        assert_eq!(frame[9..21], record(1, 2, 3)[..]);
+        assert_eq!(frame[21..], record(4, 5, 6)[..]);
+
+        // Without numbered writes the seq is 0
+        assert_eq!(encode_batch(&[]), [&[BATCH_FRAME][..], &[0; 8]].concat());
+    }
+
+    #[test]
+    fn binary_frames_from_manager() {
+        let replicated = numbered(1, 2, 3, 5);
+        let msg = format!(
+            "replicated: {}",
+            serde_json::to_string(&replicated).unwrap()
+        );
+        assert_eq!(binary_from_manager(&msg), Some(encode_batch(&[replicated])));
+
+        let written = ReplicatedWrite {
+            id: None,
+            pixel: numbered(1, 2, 3, 5),
+        };
+        let msg = format!("written: {}", serde_json::to_string(&written).unwrap());
+        let frame = binary_from_manager(&msg).unwrap();
+        assert_eq!(frame[0], REPLICATED_FRAME);
+        assert_eq!(frame[1..9], 5u64.to_be_bytes());
+        assert_eq!(frame[9..], record(1, 2, 3)[..]);
+
+        let failed = FailedWrite {
+            id: None,
+            pixel: Pixel::test(1, 2, 3),
+            current: Pixel::test(1, 2, 4),
+        };
+        let msg = format!("unreplicated: {}", serde_json::to_string(&failed).unwrap());
+        let frame = binary_from_manager(&msg).unwrap();
+        assert_eq!(frame[0], UNREPLICATED_FRAME);
+        assert_eq!(frame[1..], [record(1, 2, 3), record(1, 2, 4)].concat()[..]);
+
+        assert_eq!(binary_from_manager("primary"), None);
+        assert_eq!(binary_from_manager("replicated: {"), None);
+    }
+}
@@ -0,0 +1,189 @@
+//! Updates recently sent to WebSocket clients, so a connection that dropped can pick up where it
+//! left off with `resume`.
+//!
+//! The primary numbers each write it sends around the ring with a `seq` one past the newest it
+//! has seen, and every replica keeps the last `REPLAY_BUFFER` writes, so a replica that becomes
+//! primary carries on the numbering and can still replay them. Numbers can skip writes that
+//! never made it around the ring.
+
+use crate::pixel::Pixel;
+use std::collections::VecDeque;
+
+/// Writes kept when `REPLAY_BUFFER` isn't set
+const DEFAULT_CAPACITY: usize = 10_000;
+
+/// What a connection resuming after a `seq` gets
+#[derive(Debug)]
+pub enum Replay {
+    /// Every write made since, oldest first
+    Updates(Vec<Pixel>),
+    /// Some of the writes since are no longer kept, or the `seq` is from before the ring last
+    /// started, so the connection needs the whole canvas
+    Snapshot,
+}
+
+/// The newest writes, see the module docs
+#[derive(Debug)]
+pub struct ReplayBuffer {
+    updates: VecDeque<Pixel>,
+    capacity: usize,
+    /// Newest number handed out or seen
+    last: u64,
+    /// Writes up to this number can't be replayed, they were dropped from the buffer or made
+    /// before this replica joined
+    forgotten: u64,
+}
+
+/// `REPLAY_BUFFER`, the number of writes kept
+fn replay_capacity() -> usize {
+    std::env::var("REPLAY_BUFFER")
+        .ok()
+        .and_then(|capacity| capacity.parse().ok())
+        .unwrap_or(DEFAULT_CAPACITY)
+}
+
+impl ReplayBuffer {
+    pub fn new(capacity: usize) -> ReplayBuffer {
+        ReplayBuffer {
+            updates: VecDeque::new(),
+            capacity,
+            last: 0,
+            forgotten: 0,
+        }
+    }
+
+    pub fn from_env() -> ReplayBuffer {
+        ReplayBuffer::new(replay_capacity())
+    }
+
+    /// Newest number handed out or seen
+    pub fn last(&self) -> u64 {
+        self.last
+    }
+
+    /// Number for a new write, only handed out by the primary
+    pub fn next_seq(&mut self) -> u64 {
+        self.last += 1;
+        self.last
+    }
+
+    /// Keep a write that made it around the ring
+    pub fn record(&mut self, pixel: &Pixel) {
+        let Some(seq) = pixel.seq else {
+            return;
+        };
+        self.last = self.last.max(seq);
+        self.updates.push_back(pixel.clone());
+        while self.updates.len() > self.capacity {
+            if let Some(dropped) = self.updates.pop_front() {
+                self.forgotten = self.forgotten.max(dropped.seq.unwrap_or_default());
+            }
+        }
+    }
+
+    /// Joined a ring that got up to `seq`, the writes before can't be replayed from here
+    pub fn start_at(&mut self, seq: u64) {
+        self.last = self.last.max(seq);
+        self.forgotten = self.forgotten.max(seq);
+    }
+
+    /// Writes to a canvas after `seq`
+    pub fn since(&self, canvas: &str, seq: u64) -> Replay {
+        if seq < self.forgotten || seq > self.last {
+            return Replay::Snapshot;
+        }
+        Replay::Updates(
+            self.updates
+                .iter()
+                .filter(|pixel| pixel.seq.is_some_and(|s| s > seq) && pixel.canvas_id == canvas)
+                .cloned()
+                .collect(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pixel(canvas: &str, seq: u64) -> Pixel {
+        Pixel {
+            canvas_id: canvas.into(),
+            seq: Some(seq),
+            ..Pixel::test(0, 0, 0)
+        }
+    }
+
+    /// Numbers of the writes replayed, None for a snapshot
+    fn replayed(buffer: &ReplayBuffer, canvas: &str, since: u64) -> Option<Vec<u64>> {
+        match buffer.since(canvas, since) {
+            Replay::Updates(pixels) => Some(pixels.iter().filter_map(|p| p.seq).collect()),
+            Replay::Snapshot => None,
+        }
+    }
+
+    fn buffer(capacity: usize, seqs: impl IntoIterator<Item = u64>) -> ReplayBuffer {
+        let mut buffer = ReplayBuffer::new(capacity);
+        for seq in seqs {
+            buffer.record(&pixel("default", seq));
+        }
+        buffer
+    }
+
+    #[test]
+    fn replays_writes_since() {
+        let buffer = buffer(10, 1..=5);
+        assert_eq!(replayed(&buffer, "default", 0), Some(vec![1, 2, 3, 4, 5]));
+        assert_eq!(replayed(&buffer, "default", 3), Some(vec![4, 5]));
+        // Up to date
+        assert_eq!(replayed(&buffer, "default", 5), Some(vec![]));
+    }
+
+    #[test]
+    fn replays_only_the_canvas() {
+        let mut buffer = buffer(10, [1, 3]);
+        buffer.record(&pixel("other", 2));
+        assert_eq!(replayed(&buffer, "default", 0), Some(vec![1, 3]));
+        assert_eq!(replayed(&buffer, "other", 1), Some(vec![2]));
+    }
+
+    #[test]
+    fn since_below_the_forgotten_range() {
+        let buffer = buffer(3, 1..=5);
+        assert_eq!(buffer.forgotten, 2);
+        assert_eq!(replayed(&buffer, "default", 0), None);
+        assert_eq!(replayed(&buffer, "default", 1), None);
+        // Everything after 2 is still kept
+        assert_eq!(replayed(&buffer, "default", 2), Some(vec![3, 4, 5]));
+    }
+
+    #[test]
+    fn since_above_the_newest() {
+        let buffer = buffer(10, 1..=5);
+        // From a numbering this replica never saw, like before the ring restarted
+        assert_eq!(replayed(&buffer, "default", 6), None);
+        assert_eq!(replayed(&buffer, "default", u64::MAX), None);
+    }
+
+    #[test]
+    fn writes_before_joining_are_forgotten() {
+        let mut buffer = ReplayBuffer::new(10);
+        buffer.start_at(100);
+        assert_eq!(buffer.last(), 100);
+        assert_eq!(replayed(&buffer, "default", 99), None);
+        assert_eq!(replayed(&buffer, "default", 100), Some(vec![]));
+        assert_eq!(buffer.next_seq(), 101);
+    }
+
+    #[test]
+    fn numbering_carries_on_from_writes_seen() {
+        let mut buffer = buffer(10, [4, 7]);
+        assert_eq!(buffer.next_seq(), 8);
+        // Writes without a number aren't kept
+        buffer.record(&Pixel {
+            seq: None,
+            ..pixel("default", 0)
+        });
+        assert_eq!(replayed(&buffer, "default", 0), Some(vec![4, 7]));
+    }
+}
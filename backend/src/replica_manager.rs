@@ -1,13 +1,30 @@
-//! A multi-room chat server.
+//! The replicated canvas. Replicas form a ring over TCP, each sending to its successor, and
+//! the one elected primary takes the writes of every canvas. A write goes around the ring, every
+//! replica storing it on the way, and is replicated once it gets back to the primary, which then
+//! tells the websocket sessions of the canvas. A replica joining the ring is sent the pixels,
+//! canvases, history, users, cooldowns and moderation state with `/sync`, and the replicas
+//! around one that leaves reconnect to close the ring.
+//!
+//! Canvases, accounts, placements and moderation actions are passed around the ring the same
+//! way, so every replica can take over as primary.
+use crate::auth::{AuthError, Author, User};
+use crate::canvas::{CanvasMeta, CanvasRegistry, Region, WriteError};
+use crate::cooldown::{Cooldowns, Placement, Reservation};
+use crate::migrate::SCHEMA_VERSION;
+use crate::moderation::{self, Ban, Lock, Moderation};
 use crate::pixel::Pixel;
-use crate::Msg;
-use deadpool_postgres::Pool;
+use crate::protocol::{FailedWrite, Leader, Origin, ReplicatedWrite, RequestId, Resumed, Resync};
+use crate::replay::{Replay, ReplayBuffer};
+use crate::session_queue::{OverflowPolicy, SessionSender};
+use crate::snapshot::CanvasHistory;
+use crate::store::{Store, StoreError};
+use crate::subscriptions::{SubscribeError, Subscriptions};
+use crate::{CanvasId, ConnId, Msg};
 use futures::select;
 use futures::FutureExt;
 use rand::{thread_rng, Rng as _};
-use serde_json;
-use std::collections::HashMap;
 use std::collections::VecDeque;
+use std::collections::{HashMap, HashSet};
 use std::fs::File;
 use std::io;
 use std::io::BufReader;
@@ -17,7 +34,7 @@ use std::sync::Arc;
 use std::sync::Mutex;
 use std::thread;
 use std::time::Duration;
-use tokio::io::AsyncWriteExt;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream};
 use tokio::pin;
 use tokio::sync::mpsc::UnboundedReceiver;
@@ -27,18 +44,123 @@ use tokio::sync::{mpsc, oneshot};
 #[derive(Debug)]
 pub enum Command {
     Connect {
-        conn_tx: mpsc::UnboundedSender<Msg>,
+        canvas: CanvasId,
+        conn_tx: SessionSender,
         res_tx: oneshot::Sender<usize>,
     },
 
     Message {
+        canvas: CanvasId,
         msg: Msg,
-        res_tx: oneshot::Sender<()>,
+        res_tx: oneshot::Sender<Result<(), WriteError>>,
+        /// Told what became of the write once it's back around the ring
+        outcome_tx: Option<oneshot::Sender<WriteOutcome>>,
+        /// Who made the write, held to the cooldown. Imports have no author
+        author: Option<Author>,
+        /// WebSocket connection the write came from
+        origin: Option<Origin>,
+    },
+
+    /// Only send a session the updates in some regions
+    Subscribe {
+        canvas: CanvasId,
+        conn: ConnId,
+        region: Region,
+        res_tx: oneshot::Sender<Result<(), SubscribeError>>,
+    },
+
+    /// Stop sending a session the updates in a region, false if it wasn't subscribed
+    Unsubscribe {
+        canvas: CanvasId,
+        conn: ConnId,
+        region: Region,
+        res_tx: oneshot::Sender<bool>,
+    },
+
+    /// Send a session the writes it missed after `since`, or the whole canvas if they aren't
+    /// all kept
+    Resume {
+        canvas: CanvasId,
+        conn: ConnId,
+        since: u64,
+        id: Option<RequestId>,
+    },
+
+    /// How far behind each WebSocket session is
+    Sessions {
+        res_tx: oneshot::Sender<Vec<SessionInfo>>,
+    },
+
+    /// A write didn't make it around the ring in time
+    Unreplicated {
+        canvas: CanvasId,
+        msg: String,
+        origin: Option<Origin>,
+        reservation: Option<Reservation>,
     },
 
     Disconnect {
+        canvas: CanvasId,
         conn: usize,
     },
+
+    UpdateCanvas {
+        meta: CanvasMeta,
+        res_tx: oneshot::Sender<Result<(), WriteError>>,
+    },
+
+    Register {
+        user: User,
+        res_tx: oneshot::Sender<Result<(), AuthError>>,
+    },
+
+    Moderate {
+        action: moderation::Action,
+        res_tx: oneshot::Sender<Result<(), StoreError>>,
+    },
+}
+
+/// What became of a write sent with [`ReplicaHandle::write`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WriteOutcome {
+    /// Stored by every replica in the ring
+    Replicated,
+    /// Didn't make it back around the ring in time, some replicas may not have it
+    Unreplicated,
+    /// Only the primary takes writes, `address` is where its clients connect
+    NotPrimary {
+        primary: u16,
+        address: Option<String>,
+    },
+}
+
+/// A WebSocket session and the messages waiting to be sent to it, see `session_queue`
+#[derive(Debug, serde::Serialize)]
+pub struct SessionInfo {
+    pub canvas: CanvasId,
+    pub conn: ConnId,
+    pub depth: usize,
+    pub capacity: usize,
+    pub dropped: u64,
+    pub policy: OverflowPolicy,
+}
+
+/// A write the primary sent around the ring and is waiting to get back
+#[derive(Debug)]
+struct PendingWrite {
+    msg: String,
+    outcome_tx: Option<oneshot::Sender<WriteOutcome>>,
+    origin: Option<Origin>,
+    /// The writer's cooldown, given back if the write doesn't make it around
+    reservation: Option<Reservation>,
+}
+
+impl PendingWrite {
+    fn resolve(self, outcome: WriteOutcome) {
+        if let Some(outcome_tx) = self.outcome_tx {
+            let _ = outcome_tx.send(outcome);
+        }
+    }
 }
 
 #[derive(Debug, serde::Serialize, serde::Deserialize, Clone)]
@@ -55,6 +177,10 @@ pub struct ReplicaInfo {
     pub address: String,
     pub socket_port: u16,
     pub active: bool,
+    /// Migrations applied to the replica's database, replicas with a different schema can't
+    /// join the ring
+    #[serde(default)]
+    pub schema_version: u32,
 }
 
 #[derive(Debug, serde::Serialize, serde::Deserialize, Clone)]
@@ -65,13 +191,13 @@ pub struct ConnectionInfoDict {
 }
 
 impl ConnectionInfoDict {
-    fn get_socket_addr(backend: &Vec<ReplicaInfo>, id: u16) -> SocketAddrV4 {
-        let replica_info: &ReplicaInfo = &backend.iter().find(|r| r.id == id).unwrap();
+    fn get_socket_addr(backend: &[ReplicaInfo], id: u16) -> SocketAddrV4 {
+        let replica_info: &ReplicaInfo = backend.iter().find(|r| r.id == id).unwrap();
         let addr: Ipv4Addr = replica_info.address.parse::<Ipv4Addr>().unwrap();
         SocketAddrV4::new(addr, replica_info.socket_port)
     }
 
-    fn get_successor_id(backend: &Vec<ReplicaInfo>, id: u16) -> u16 {
+    fn get_successor_id(backend: &[ReplicaInfo], id: u16) -> u16 {
         let index = backend.iter().position(|r| r.id == id).unwrap();
 
         // Create an iterator that starts from the specified index and cycles back to the beginning
@@ -87,7 +213,7 @@ impl ConnectionInfoDict {
         id // No active element found
     }
 
-    fn get_predecessor_id(backend: &Vec<ReplicaInfo>, id: u16) -> u16 {
+    fn get_predecessor_id(backend: &[ReplicaInfo], id: u16) -> u16 {
         let index = backend.iter().rev().position(|r| r.id == id).unwrap();
 
         // Create an iterator that starts from the specified index and cycles back to the beginning, but in reverse
@@ -102,11 +228,11 @@ impl ConnectionInfoDict {
 
         id // No active element found
     }
-    fn get_own_info(backend: &Vec<ReplicaInfo>, id: u16) -> &ReplicaInfo {
+    fn get_own_info(backend: &[ReplicaInfo], id: u16) -> &ReplicaInfo {
         backend.iter().find(|r| r.id == id).unwrap()
     }
 
-    fn get_own_info_str(backend: &Vec<ReplicaInfo>, id: u16) -> String {
+    fn get_own_info_str(backend: &[ReplicaInfo], id: u16) -> String {
         serde_json::to_string(backend.iter().find(|r| r.id == id).unwrap()).unwrap()
     }
 }
@@ -120,14 +246,64 @@ pub struct NewConMessage {
 #[derive(Debug, serde::Serialize, serde::Deserialize)]
 pub struct SyncMessage {
     pixels: Vec<Pixel>,
+    #[serde(default)]
+    canvases: Vec<CanvasMeta>,
+    /// Newest checkpoint and later writes of each canvas, so the history catches up too
+    #[serde(default)]
+    history: Vec<CanvasHistory>,
+    /// Clients still cooling down
+    #[serde(default)]
+    placements: Vec<Placement>,
+    #[serde(default)]
+    users: Vec<User>,
+    #[serde(default)]
+    locks: Vec<Lock>,
+    #[serde(default)]
+    bans: Vec<Ban>,
+    /// Newest write number, so a joining replica carries on from it if it becomes primary
+    #[serde(default)]
+    seq: u64,
     conn: ConnectionInfoDict,
     leader: u16,
     predecessor_id: u16,
 }
 
+/// A canvas was created or resized on the replica `from`
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct CanvasMessage {
+    from: u16,
+    meta: CanvasMeta,
+}
+
+/// A client placed a pixel on the replica `from`. Sent as bare JSON like pixel writes, so the
+/// two can be told apart when they arrive in the same read
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct PlacementMessage {
+    from: u16,
+    placement: Placement,
+}
+
+/// An account was created on the replica `from`, sent as bare JSON like placements
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct UserMessage {
+    from: u16,
+    user: User,
+}
+
+/// A lock or ban was added or removed on the replica `from`, sent as bare JSON like placements
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct ModerationMessage {
+    from: u16,
+    action: moderation::Action,
+}
+
 // TODO calc max size or find it experimentally
 const REPLICA_BUFFER_SIZE: usize = 1400;
 const SMALL_REPLICA_BUFFER_SIZE: usize = 1400;
+/// How long the primary waits for a write to come back around the ring
+const REPLICATION_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long a joining replica waits for the ring to accept its schema version
+const SCHEMA_TIMEOUT: Duration = Duration::from_secs(5);
 
 fn connections_file() -> String {
     std::env::var("CONNECTIONS_FILE").unwrap_or_else(|_| "../../process_connections.json".into())
@@ -137,18 +313,12 @@ fn proc_id() -> u16 {
     std::env::var("ID")
         .unwrap_or_else(|_| "0".into())
         .parse::<u16>()
-        .unwrap_or_else(|_| 0)
+        .unwrap_or(0)
 }
 
 fn is_debug_enabled() -> bool {
     match std::env::var("DEBUG") {
-        Ok(val) => {
-            if val == "1" || val.to_lowercase() == "true" {
-                true
-            } else {
-                false
-            }
-        }
+        Ok(val) => val == "1" || val.to_lowercase() == "true",
         Err(_) => false,
     }
 }
@@ -158,16 +328,31 @@ fn is_debug_enabled() -> bool {
 /// Call and spawn [`run`](Self::run) to start processing commands.
 #[derive(Debug)]
 pub struct ReplicaManager {
-    /// Map of connection IDs to their message receivers.
-    sessions: HashMap<usize, mpsc::UnboundedSender<Msg>>,
+    /// Map of canvas to the connection IDs subscribed to it and their message receivers.
+    sessions: HashMap<CanvasId, HashMap<usize, SessionSender>>,
+
+    /// Regions sessions asked for, sessions without any get every update of their canvas
+    subscriptions: HashMap<CanvasId, Subscriptions>,
 
     is_primary: bool,
 
     /// Process id
     id: u16,
 
-    /// Postgres db_connection
-    db: Pool,
+    /// Where this replica's canvases are stored
+    store: Store,
+
+    /// Canvases writes are validated against
+    canvases: CanvasRegistry,
+
+    /// When each client last placed a pixel anywhere in the ring
+    cooldowns: Cooldowns,
+
+    /// Newest writes, for WebSocket connections resuming after they dropped
+    replay: ReplayBuffer,
+
+    /// Locks and bans writes are checked against, loaded from the store when the manager starts
+    moderation: Moderation,
 
     successor_stream: Option<TcpStream>,
 
@@ -181,19 +366,26 @@ pub struct ReplicaManager {
 
     leader_id: u16,
 
-    expected_queue: Arc<Mutex<VecDeque<String>>>,
+    expected_queue: Arc<Mutex<VecDeque<PendingWrite>>>,
+
+    /// Commands to this manager, for the threads waiting on writes to send theirs back
+    cmd_tx: mpsc::UnboundedSender<Command>,
 
     connected: bool,
 
     sent_sync: bool,
     sync_ended: bool,
-    sync_message: String
+    sync_message: String,
+    /// Pixel messages arrive back to back on the socket, this holds a message cut off at
+    /// the end of the last read
+    pixel_message: String,
 }
 
 impl ReplicaManager {
     pub fn new(
         is_primary: bool,
-        db: Pool,
+        store: Store,
+        canvases: CanvasRegistry,
         cmd_tx: mpsc::UnboundedSender<Command>,
     ) -> (Self, ReplicaHandle) {
         let id = proc_id();
@@ -203,7 +395,12 @@ impl ReplicaManager {
         let reader = BufReader::new(file);
 
         // Read the JSON contents of the file
-        let connections_info: ConnectionInfoDict = serde_json::from_reader(reader).unwrap();
+        let mut connections_info: ConnectionInfoDict = serde_json::from_reader(reader).unwrap();
+        for backend in connections_info.backend.iter_mut() {
+            if backend.id == id {
+                backend.schema_version = SCHEMA_VERSION;
+            }
+        }
 
         let successor_id = ConnectionInfoDict::get_successor_id(&connections_info.backend, id);
         let predecessor_id = ConnectionInfoDict::get_predecessor_id(&connections_info.backend, id);
@@ -218,21 +415,28 @@ impl ReplicaManager {
         (
             Self {
                 sessions: HashMap::new(),
+                subscriptions: HashMap::new(),
                 is_primary,
                 id,
-                db,
+                store,
+                canvases,
+                cooldowns: Cooldowns::from_env(),
+                replay: ReplayBuffer::from_env(),
+                moderation: Moderation::default(),
                 successor_stream: None,
                 // predecessor_stream: None,
                 election_running: false,
                 connections_info,
-                predecessor_id: predecessor_id,
-                successor_id: successor_id,
+                predecessor_id,
+                successor_id,
                 leader_id,
                 expected_queue,
+                cmd_tx: cmd_tx.clone(),
                 connected: false,
                 sent_sync: false,
                 sync_ended: false,
                 sync_message: "".to_string(),
+                pixel_message: "".to_string(),
             },
             ReplicaHandle { cmd_tx },
         )
@@ -248,7 +452,7 @@ impl ReplicaManager {
             // unwrap: we have guaranteed non-zero string length already
             match cmd_args.next().unwrap() {
                 "/all_pixels" => match cmd_args.next() {
-                    Some(pixels) => self.handle_all_pixels_msg(pixels.to_string()).await,
+                    Some(pixels) => self.handle_all_pixels_msg(pixels.to_string()).await?,
                     None => {
                         log::info!("No pixels provided to all pixels update");
                     }
@@ -279,6 +483,12 @@ impl ReplicaManager {
                         log::info!("No ID provided for disconnect");
                     }
                 },
+                "/canvas" => match cmd_args.next() {
+                    Some(message) => self.handle_canvas_msg(message.to_string()).await?,
+                    None => {
+                        log::info!("No canvas provided for canvas update");
+                    }
+                },
 
                 _ => {
                     log::info!("Unknown command {}", msg);
@@ -288,71 +498,259 @@ impl ReplicaManager {
             if !self.sync_ended {
                 self.handle_sync_msg(msg).await?;
             } else {
-                self.handle_pixel_msg(msg).await;
+                for msg in self.split_pixel_msgs(&msg) {
+                    if let Ok(placement_msg) = serde_json::from_str::<PlacementMessage>(&msg) {
+                        self.handle_placement_msg(placement_msg).await?;
+                    } else if let Ok(user_msg) = serde_json::from_str::<UserMessage>(&msg) {
+                        self.handle_user_msg(user_msg).await?;
+                    } else if let Ok(moderation_msg) =
+                        serde_json::from_str::<ModerationMessage>(&msg)
+                    {
+                        self.handle_moderation_msg(moderation_msg).await?;
+                    } else {
+                        self.handle_pixel_msg(msg).await?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Split what was read from the socket into whole pixel messages. Several writes sent in
+    /// quick succession (e.g. by an import) can arrive in one read, and the last one can be
+    /// cut off by the buffer size
+    fn split_pixel_msgs(&mut self, recv: &str) -> Vec<String> {
+        self.pixel_message.push_str(recv);
+        let buffered = std::mem::take(&mut self.pixel_message);
+
+        let mut msgs = Vec::new();
+        let mut start = 0;
+        let mut stream =
+            serde_json::Deserializer::from_str(&buffered).into_iter::<serde::de::IgnoredAny>();
+        loop {
+            match stream.next() {
+                Some(Ok(_)) => {
+                    let end = stream.byte_offset();
+                    msgs.push(buffered[start..end].trim().to_string());
+                    start = end;
+                }
+                Some(Err(e)) if e.is_eof() => {
+                    self.pixel_message = buffered[start..].to_string();
+                    break;
+                }
+                Some(Err(e)) => {
+                    log::error!(
+                        "Dropping unreadable pixel message {}: {}",
+                        &buffered[start..],
+                        e
+                    );
+                    break;
+                }
+                None => break,
             }
         }
-        return Ok(());
+        msgs
     }
 
     /// Normal pixel update, add it to db
-    /// Really these should return errors too, but to lazy to box
-    pub async fn handle_pixel_msg(&mut self, msg: String) {
+    pub async fn handle_pixel_msg(&mut self, msg: String) -> io::Result<()> {
         // For testing consistency
         if is_debug_enabled() {
             log::info!("Pixel update received: {}", msg);
             log::info!("DEBUG is enabled so we are not sending to successor");
-            return;
+            return Ok(());
         }
 
         if self.is_primary {
             let expected = self.expected_queue.lock().unwrap().pop_front();
             match expected {
                 None => log::warn!("Received unexpected pixel message: {}", msg),
-                Some(expected) => {
-                    if expected == msg {
+                Some(mut expected) => {
+                    if expected.msg == msg {
                         log::info!("Validated expected pixel message: {}", msg);
-                        let db = self.db.get().await.unwrap();
-                        Pixel::insert(db, msg.clone()).await.unwrap();
-                        self.send_replicated_to_ws(msg).await;
+                        let pixel = match self.canvases.parse_replicated_pixel(&msg) {
+                            Ok(pixel) => pixel,
+                            Err(e) => {
+                                log::error!(
+                                    "Expected pixel message no longer valid {}: {}",
+                                    msg,
+                                    e
+                                );
+                                self.refund_cooldown(expected.reservation.take());
+                                return Ok(());
+                            }
+                        };
+                        self.store_pixel(&pixel).await;
+                        self.replay.record(&pixel);
+                        self.accept_placement(expected.reservation.take()).await;
+                        self.send_replicated_to_ws(&pixel, msg, expected.origin.as_ref())
+                            .await;
+                        expected.resolve(WriteOutcome::Replicated);
                     } else {
-                        log::info!("Invalid pixel message: {}, expected: {}", msg, expected);
+                        log::info!("Invalid pixel message: {}, expected: {}", msg, expected.msg);
+                        self.refund_cooldown(expected.reservation.take());
                     }
                 }
             }
-            return;
+            return Ok(());
         }
         log::info!("Pixel update received: {}", msg);
-        let db = self.db.get().await.unwrap();
-        Pixel::insert(db, msg.clone()).await.unwrap();
+        let pixel = match self.canvases.parse_replicated_pixel(&msg) {
+            Ok(pixel) => pixel,
+            Err(e) => {
+                log::error!("Dropping invalid pixel update {}: {}", msg, e);
+                return Ok(());
+            }
+        };
+        self.store_pixel(&pixel).await;
+        self.replay.record(&pixel);
 
         if !self.is_primary {
             log::info!("Sent message to successor: {}", msg);
-            self.send_successor(msg.as_bytes()).await.unwrap();
+            self.send_successor(msg.as_bytes()).await?;
         } else {
             log::info!("Ignored message: {}", msg);
         }
+        Ok(())
+    }
+
+    /// A client placed a pixel somewhere in the ring. Record it and forward until it gets back
+    /// to the replica that took the write
+    pub async fn handle_placement_msg(&mut self, msg: PlacementMessage) -> io::Result<()> {
+        if msg.from == self.id {
+            return Ok(());
+        }
+        self.cooldowns.record(msg.placement.clone());
+        self.send_successor(serde_json::to_string(&msg).unwrap().as_bytes())
+            .await
+    }
+
+    /// An account was created somewhere in the ring. Store it and forward until it gets back
+    /// to the replica that created it
+    pub async fn handle_user_msg(&mut self, msg: UserMessage) -> io::Result<()> {
+        if msg.from == self.id {
+            log::info!("User {} added to all replicas", msg.user.name);
+            return Ok(());
+        }
+        self.store_user(&msg.user).await;
+        self.send_successor(serde_json::to_string(&msg).unwrap().as_bytes())
+            .await
+    }
+
+    /// A lock or ban changed somewhere in the ring. Apply it and forward until it gets back to
+    /// the replica it was made on
+    pub async fn handle_moderation_msg(&mut self, msg: ModerationMessage) -> io::Result<()> {
+        if msg.from == self.id {
+            log::info!(
+                "Moderation action applied by all replicas: {:?}",
+                msg.action
+            );
+            return Ok(());
+        }
+        if let Err(e) = self.apply_moderation(&msg.action).await {
+            log::error!("Couldn't store moderation action {:?}: {}", msg.action, e);
+        }
+        self.send_successor(serde_json::to_string(&msg).unwrap().as_bytes())
+            .await
+    }
+
+    /// Enforced as soon as it's applied, even if the store couldn't keep it
+    async fn apply_moderation(&mut self, action: &moderation::Action) -> Result<(), StoreError> {
+        self.moderation.apply(action);
+        self.store.apply_moderation(action).await
+    }
+
+    async fn store_user(&self, user: &User) {
+        if let Err(e) = self.store.add_user(user).await {
+            log::error!("Couldn't store user {}: {}", user.name, e);
+        }
+    }
+
+    /// Id of the primary and the address its clients connect to
+    fn not_primary(&self) -> (u16, Option<String>) {
+        let address = self
+            .connections_info
+            .backend
+            .iter()
+            .find(|backend| backend.id == self.leader_id)
+            .map(|leader| format!("{}:{}", leader.public_address, leader.public_port));
+        (self.leader_id, address)
+    }
+
+    /// Hold the client making a write to its cooldown, or fail if it's still cooling down. The
+    /// placement is only sent around the ring once the write is accepted, see `accept_placement`
+    fn reserve_cooldown(
+        &mut self,
+        identity: Option<String>,
+    ) -> Result<Option<Reservation>, WriteError> {
+        let Some(identity) = identity else {
+            return Ok(None);
+        };
+        self.cooldowns
+            .reserve(identity)
+            .map_err(|remaining| WriteError::Cooldown {
+                remaining_ms: remaining.as_millis() as u64,
+            })
+    }
+
+    /// The write of a reservation was accepted, tell the other replicas about the placement
+    async fn accept_placement(&mut self, reservation: Option<Reservation>) {
+        let Some(reservation) = reservation else {
+            return;
+        };
+        if self.connected {
+            let msg = PlacementMessage {
+                from: self.id,
+                placement: reservation.placement,
+            };
+            if let Err(e) = self
+                .send_successor(serde_json::to_string(&msg).unwrap().as_bytes())
+                .await
+            {
+                log::error!("Couldn't send placement to successor: {}", e);
+            }
+        }
+    }
+
+    /// The write of a reservation failed, the client can write again right away
+    fn refund_cooldown(&mut self, reservation: Option<Reservation>) {
+        if let Some(reservation) = reservation {
+            log::info!("Refunding cooldown of {}", reservation.placement.identity);
+            self.cooldowns.refund(&reservation);
+        }
     }
 
     /// Clear and set the entire database to list of pixels provided
-    /// Really these should return errors too, but to lazy to box
-    pub async fn handle_all_pixels_msg(&mut self, msg: String) {
+    pub async fn handle_all_pixels_msg(&mut self, msg: String) -> io::Result<()> {
         if self.is_primary {
             // We already updated our database, do nothing
-            return;
+            return Ok(());
         }
         log::info!("All pixels update received");
         // log::info!("All pixels update received: {}", msg);
-        let db = self.db.get().await.unwrap();
-        Pixel::update_all(db, &msg).await.unwrap();
+        let pixels: Vec<Pixel> = match serde_json::from_str(&msg) {
+            Ok(pixels) => pixels,
+            Err(e) => {
+                log::error!("Dropping invalid all pixels update: {}", e);
+                return Ok(());
+            }
+        };
+        if let Err(e) = self.store.replace_pixels(&pixels, &self.canvases).await {
+            log::error!(
+                "Couldn't apply all pixels update, keeping old pixels: {}",
+                e
+            );
+        }
 
         if !self.is_primary {
             log::info!("Sent all_pixels message to successor");
             // log::info!("Sent message to successor: {}", msg);
             let pixels_str = format!("/all_pixels {}", msg);
-            self.send_successor(pixels_str.as_bytes()).await.unwrap();
+            self.send_successor(pixels_str.as_bytes()).await?;
         } else {
             log::info!("Ignored all pixels update");
         }
+        Ok(())
     }
 
     pub async fn initiate_election(&mut self) -> io::Result<()> {
@@ -390,9 +788,13 @@ impl ReplicaManager {
         if election_type == "leader" {
             self.election_running = false;
             log::info!("New leader elected: {}", id);
+            let changed = self.leader_id != id;
             self.leader_id = id;
             if id != self.id {
                 self.is_primary = false;
+                if changed {
+                    self.send_leader_to_ws();
+                }
                 let election_message = format!("/election leader {}", id);
                 log::info!("Election sending: {}...", election_message);
                 self.send_successor(election_message.as_bytes()).await?
@@ -425,30 +827,32 @@ impl ReplicaManager {
                 election_type
             );
         }
-        return Ok(());
+        Ok(())
     }
 
     pub async fn handle_sync_msg(&mut self, recv: String) -> io::Result<()> {
         log::info!("Got sync");
         self.sync_ended = false;
-        let mut msg = "".to_string();
         self.sync_message = format!("{}{}", self.sync_message, recv);
-        if self.sync_message.contains("^^^^") {
+        let msg = if self.sync_message.contains("^^^^") {
             let original_len = self.sync_message.len();
             self.sync_message.drain(original_len - 4..);
-            msg = self.sync_message.clone();
+            let msg = self.sync_message.clone();
             self.sync_ended = true;
             self.sync_message = "".to_string();
             log::info!("SYNC OVER {}", msg);
+            msg
         } else {
             return Ok(());
-        }
-
-
-
-
+        };
 
-        let mut sync: SyncMessage = serde_json::from_str(&msg).unwrap();
+        let mut sync: SyncMessage = match serde_json::from_str(&msg) {
+            Ok(sync) => sync,
+            Err(e) => {
+                log::error!("Dropping invalid sync message: {}", e);
+                return Ok(());
+            }
+        };
         self.predecessor_id = sync.predecessor_id;
         if self.is_primary {
             // We already updated our database, do nothing
@@ -471,13 +875,45 @@ impl ReplicaManager {
                 log::info!("Our leader is {}", self.leader_id);
             }
 
-            
             return Ok(());
         }
         log::info!("All pixels update received");
         // log::info!("All pixels update received: {}", msg);
-        let db = self.db.get().await.unwrap();
-        Pixel::update_all_vec(db, &sync.pixels).await.unwrap();
+        for meta in sync.canvases.iter() {
+            self.apply_canvas(meta.clone()).await;
+        }
+        if let Err(e) = self
+            .store
+            .replace_pixels(&sync.pixels, &self.canvases)
+            .await
+        {
+            log::error!("Couldn't apply synced pixels, keeping old pixels: {}", e);
+        }
+        for placement in sync.placements.iter() {
+            self.cooldowns.record(placement.clone());
+        }
+        self.replay.start_at(sync.seq);
+        for user in sync.users.iter() {
+            self.store_user(user).await;
+        }
+        for action in self.moderation.replace(&sync.locks, &sync.bans) {
+            if let Err(e) = self.apply_moderation(&action).await {
+                log::error!(
+                    "Couldn't apply synced moderation action {:?}: {}",
+                    action,
+                    e
+                );
+            }
+        }
+        for history in sync.history.iter() {
+            if let Err(e) = self.store.restore_history(history).await {
+                log::error!(
+                    "Couldn't apply synced history of canvas {}: {}",
+                    history.canvas_id,
+                    e
+                );
+            }
+        }
 
         self.connections_info = sync.conn.clone();
         log::info!(
@@ -496,9 +932,66 @@ impl ReplicaManager {
         Ok(())
     }
 
+    /// Store a pixel on this replica. The write has already been accepted by the ring, so a
+    /// replica that can't reach its database logs it rather than stopping
+    async fn store_pixel(&self, pixel: &Pixel) {
+        let pixel = &Pixel {
+            seq: None,
+            ..pixel.clone()
+        };
+        if let Err(e) = self
+            .store
+            .insert_pixel(pixel, self.canvases.palette_index(pixel))
+            .await
+        {
+            log::error!(
+                "Couldn't store pixel ({}, {}) of canvas {}: {}",
+                pixel.x,
+                pixel.y,
+                pixel.canvas_id,
+                e
+            );
+        }
+    }
+
+    /// Store a canvas on this replica and make it available for writes
+    async fn apply_canvas(&mut self, meta: CanvasMeta) {
+        if let Err(e) = self.store.set_canvas(&meta).await {
+            log::error!("Couldn't store canvas {}: {}", meta.id, e);
+        }
+        self.canvases.insert(meta);
+    }
+
+    /// A canvas was created somewhere in the ring. Apply it and forward until it gets back to
+    /// the replica that created it
+    pub async fn handle_canvas_msg(&mut self, msg: String) -> io::Result<()> {
+        let canvas_msg = match serde_json::from_str::<CanvasMessage>(&msg) {
+            Ok(canvas_msg) => canvas_msg,
+            Err(e) => {
+                log::error!("Invalid canvas message {}: {}", msg, e);
+                return Ok(());
+            }
+        };
+        if canvas_msg.from == self.id {
+            log::info!("Canvas {} applied to all replicas", canvas_msg.meta.id);
+            return Ok(());
+        }
+        log::info!("Canvas update received: {}", msg);
+        self.apply_canvas(canvas_msg.meta).await;
+
+        let canvas_str = format!("/canvas {}", msg);
+        self.send_successor(canvas_str.as_bytes()).await
+    }
+
     /// Received a new connection message.
     pub async fn handle_new_connection_msg(&mut self, msg: String) -> io::Result<()> {
-        let new_conn_message = serde_json::from_str::<NewConMessage>(&msg).unwrap();
+        let new_conn_message = match serde_json::from_str::<NewConMessage>(&msg) {
+            Ok(new_conn_message) => new_conn_message,
+            Err(e) => {
+                log::error!("Invalid new connection message {}: {}", msg, e);
+                return Ok(());
+            }
+        };
         let from_info = new_conn_message.from;
         let effected_info = new_conn_message.effecting;
         if effected_info.id != self.successor_id {
@@ -507,7 +1000,17 @@ impl ReplicaManager {
             return self.send_successor(new_con_str.as_bytes()).await;
         }
 
-        let addr: Ipv4Addr = from_info.address.parse::<Ipv4Addr>().unwrap();
+        let addr: Ipv4Addr = match from_info.address.parse() {
+            Ok(addr) => addr,
+            Err(_) => {
+                log::error!(
+                    "Invalid address of replica {}: {}",
+                    from_info.id,
+                    from_info.address
+                );
+                return Ok(());
+            }
+        };
         log::info!("Trying to connect to {}", addr);
         match TcpStream::connect(SocketAddrV4::new(addr, from_info.socket_port)).await {
             Ok(stream) => {
@@ -573,7 +1076,7 @@ impl ReplicaManager {
             if id == self.leader_id {
                 self.initiate_election().await?;
             }
-            return Ok(());
+            Ok(())
         } else {
             // Forward
             let disconnect_msg = format!("/disconnect {}", id);
@@ -624,111 +1127,474 @@ impl ReplicaManager {
         self.send_successor(msg.as_bytes()).await?;
 
         let (predecessor_stream, _) = listener.accept().await?;
-        self.predecessor_id = ConnectionInfoDict::get_predecessor_id(&self.connections_info.backend, self.id);
-        return Ok(predecessor_stream);
+        self.predecessor_id =
+            ConnectionInfoDict::get_predecessor_id(&self.connections_info.backend, self.id);
+        Ok(predecessor_stream)
     }
 
     /// Let all ws sessions know that we are the new primary so they can forward that to their proxies
     async fn send_primary_to_ws(&self) {
-        let msg = format!("primary");
+        let msg = "primary".to_string();
 
-        for (id, session) in &self.sessions {
+        for (id, session) in self.sessions.values().flatten() {
             log::info!("Sending primary to session {}", id);
             let _ = session.send(msg.clone());
         }
     }
 
-    /// Let all ws sessions know that the message was successfully applied to all replicas
-    async fn send_replicated_to_ws(&self, msg: String) {
-        let msg = format!("replicated: {}", msg);
+    /// Let all ws sessions know that another replica is the new primary
+    fn send_leader_to_ws(&self) {
+        let (primary, address) = self.not_primary();
+        let leader = Leader { primary, address };
+        let msg = format!("leader: {}", serde_json::to_string(&leader).unwrap());
 
-        for (id, session) in &self.sessions {
-            log::info!("Sending replicated to session {}", id);
-            print!("Sending replicated to session {}", id);
+        for session in self.sessions.values().flat_map(HashMap::values) {
             let _ = session.send(msg.clone());
         }
     }
 
+    /// Let the ws sessions of a canvas know that the message was successfully applied to all
+    /// replicas. The session that made the write is told it was its own
+    async fn send_replicated_to_ws(&self, pixel: &Pixel, msg: String, origin: Option<&Origin>) {
+        let msg = format!("replicated: {}", msg);
+        let Some(sessions) = self.sessions.get(&pixel.canvas_id) else {
+            return;
+        };
+
+        // Sessions that subscribed to regions only get the pixels inside them
+        let subscriptions = self.subscriptions.get(&pixel.canvas_id);
+        let subscribers: HashSet<ConnId> = subscriptions
+            .map(|subscriptions| subscriptions.subscribers(pixel).collect())
+            .unwrap_or_default();
+        let wants = |id: &ConnId| {
+            subscribers.contains(id)
+                || !subscriptions.is_some_and(|subscriptions| subscriptions.is_subscribed(*id))
+        };
+
+        for (id, session) in sessions {
+            if let Some(origin) = origin.filter(|origin| origin.conn == *id) {
+                log::info!("Sending replicated write to session {}", id);
+                let written = ReplicatedWrite {
+                    id: origin.id.clone(),
+                    pixel: pixel.clone(),
+                };
+                let _ = session.send(format!(
+                    "written: {}",
+                    serde_json::to_string(&written).unwrap()
+                ));
+            } else if wants(id) {
+                log::info!("Sending replicated to session {}", id);
+                let _ = session.send(msg.clone());
+            }
+        }
+    }
+
+    /// Send a session that dropped the writes it missed, after `resumed`, or the whole canvas
+    /// when they aren't all kept. They go through its channel so they come before any newer
+    /// write
+    async fn resume_session(&self, canvas: &str, conn: ConnId, since: u64, id: Option<RequestId>) {
+        let Some(session) = self
+            .sessions
+            .get(canvas)
+            .and_then(|sessions| sessions.get(&conn))
+        else {
+            return;
+        };
+        let seq = self.replay.last();
+        match self.replay.since(canvas, since) {
+            Replay::Updates(mut updates) => {
+                if let Some(subscriptions) = self.subscriptions.get(canvas) {
+                    updates.retain(|pixel| subscriptions.wants(conn, pixel));
+                }
+                log::info!("Replaying {} writes to session {}", updates.len(), conn);
+                let resumed = Resumed {
+                    id,
+                    since,
+                    seq,
+                    replayed: updates.len(),
+                };
+                let _ = session.send(format!(
+                    "resumed: {}",
+                    serde_json::to_string(&resumed).unwrap()
+                ));
+                for pixel in updates {
+                    let _ = session.send(format!(
+                        "replicated: {}",
+                        serde_json::to_string(&pixel).unwrap()
+                    ));
+                }
+            }
+            Replay::Snapshot => {
+                log::info!(
+                    "Can't replay from {}, sending session {} the canvas",
+                    since,
+                    conn
+                );
+                let pixels = match self.store.canvas_pixels(canvas).await {
+                    Ok(pixels) => pixels,
+                    Err(e) => {
+                        log::error!("Couldn't read canvas {} to resync: {}", canvas, e);
+                        return;
+                    }
+                };
+                let resync = Resync {
+                    id,
+                    seq,
+                    canvas: self.canvases.get(canvas).unwrap_or_default(),
+                    pixels,
+                };
+                let _ = session.send(format!(
+                    "snapshot: {}",
+                    serde_json::to_string(&resync).unwrap()
+                ));
+            }
+        }
+    }
+
+    /// Tell the connection a write came from that it didn't make it around the ring, and what
+    /// the pixel is now so it can undo it
+    async fn send_unreplicated_to_ws(&self, canvas: &str, msg: &str, origin: Origin) {
+        let Some(session) = self
+            .sessions
+            .get(canvas)
+            .and_then(|sessions| sessions.get(&origin.conn))
+        else {
+            return;
+        };
+        let pixel: Pixel = match serde_json::from_str(msg) {
+            Ok(pixel) => pixel,
+            Err(e) => {
+                log::error!("Unreadable unreplicated write {}: {}", msg, e);
+                return;
+            }
+        };
+        let current = match self.store.pixel(canvas, pixel.x, pixel.y).await {
+            Ok(Some(current)) => current,
+            Ok(None) => Pixel {
+                colour: self.canvases.get(canvas).unwrap_or_default().background,
+                updated: 0,
+                user: None,
+                seq: None,
+                ..pixel.clone()
+            },
+            Err(e) => {
+                log::error!("Couldn't read the current colour of {}: {}", msg, e);
+                return;
+            }
+        };
+        let failed = FailedWrite {
+            id: origin.id,
+            pixel,
+            current,
+        };
+        log::info!("Sending unreplicated to session {}", origin.conn);
+        let _ = session.send(format!(
+            "unreplicated: {}",
+            serde_json::to_string(&failed).unwrap()
+        ));
+    }
+
     /// Register new session and assign unique ID to this session. This is to talk to the other thread
-    async fn register_session(&mut self, tx: mpsc::UnboundedSender<Msg>) -> usize {
+    async fn register_session(&mut self, canvas: CanvasId, tx: SessionSender) -> usize {
         // register session with random connection ID
         let id = thread_rng().gen::<usize>();
-        log::info!("Registering session {}", id);
+        log::info!("Registering session {} on canvas {}", id, canvas);
 
-        self.sessions.insert(id, tx);
+        self.sessions.entry(canvas).or_default().insert(id, tx);
 
         // send id back
         id
     }
 
     /// Unregister a session and remove from map
-    async fn unregister_session(&mut self, conn_id: usize) {
+    async fn unregister_session(&mut self, canvas: &str, conn_id: usize) {
         log::info!("Unregistering session {}", conn_id);
-        self.sessions.remove(&conn_id);
+        if let Some(sessions) = self.sessions.get_mut(canvas) {
+            sessions.remove(&conn_id);
+            if sessions.is_empty() {
+                self.sessions.remove(canvas);
+            }
+        }
+        if let Some(subscriptions) = self.subscriptions.get_mut(canvas) {
+            subscriptions.remove(conn_id);
+        }
     }
 
     async fn handle_command(&mut self, cmd: Command) -> io::Result<()> {
         match cmd {
-            Command::Connect { conn_tx, res_tx } => {
-                let conn_id = self.register_session(conn_tx).await;
-                let _ = res_tx.send(conn_id);
+            Command::Connect {
+                canvas,
+                conn_tx,
+                res_tx,
+            } => {
+                // Only the new session needs telling, the others already know
                 if self.is_primary {
-                    self.send_primary_to_ws().await;
+                    log::info!("Sending primary to the new session");
+                    let _ = conn_tx.send("primary".to_string());
                 }
+                let conn_id = self.register_session(canvas, conn_tx).await;
+                let _ = res_tx.send(conn_id);
             }
-            Command::Message { msg, res_tx } => {
+            Command::Message {
+                canvas,
+                msg,
+                res_tx,
+                outcome_tx,
+                author,
+                origin,
+            } => {
                 log::info!("Message received: {}", msg);
+                let mut pixel = match self.canvases.parse_pixel(&canvas, &msg) {
+                    Ok(pixel) => pixel,
+                    Err(e) => {
+                        log::info!("Rejected pixel write {}: {}", msg, e);
+                        let _ = res_tx.send(Err(e));
+                        return Ok(());
+                    }
+                };
+                if let Some(author) = author.as_ref() {
+                    if let Err(e) = self.moderation.check(&pixel, author) {
+                        log::info!("Rejected pixel write {}: {}", msg, e);
+                        let _ = res_tx.send(Err(e));
+                        return Ok(());
+                    }
+                }
+                // Websocket writes sent to other replicas are forwarded as before, but a caller
+                // waiting for the outcome is told to write to the primary instead
+                if !self.is_primary && outcome_tx.is_some() {
+                    let (primary, address) = self.not_primary();
+                    let _ = res_tx.send(Ok(()));
+                    let pending = PendingWrite {
+                        msg,
+                        outcome_tx,
+                        origin,
+                        reservation: None,
+                    };
+                    pending.resolve(WriteOutcome::NotPrimary { primary, address });
+                    return Ok(());
+                }
+
+                let reservation = match self
+                    .reserve_cooldown(author.as_ref().map(|author| author.identity.clone()))
+                {
+                    Ok(reservation) => reservation,
+                    Err(e) => {
+                        log::info!("Rejected pixel write {}: {}", msg, e);
+                        let _ = res_tx.send(Err(e));
+                        return Ok(());
+                    }
+                };
+
+                // Clients can't claim writes for other users, or number them. Only writes that
+                // passed every check are numbered, so resuming clients don't wait on gaps
+                pixel.user = author.as_ref().and_then(|author| author.user.clone());
+                pixel.seq = self.is_primary.then(|| self.replay.next_seq());
+                // Replicas compare messages as strings so forward the parsed pixel, which
+                // carries the canvas it was written to and who wrote it
+                let msg = serde_json::to_string(&pixel).unwrap();
+
+                let mut pending = PendingWrite {
+                    msg: msg.clone(),
+                    outcome_tx,
+                    origin,
+                    reservation,
+                };
+
                 if !self.connected {
-                    let db = self.db.get().await.unwrap();
-                    Pixel::insert(db, msg.clone()).await.unwrap();
+                    self.store_pixel(&pixel).await;
+                    self.replay.record(&pixel);
                     log::info!("Only replica, ignoring message");
-                    let _ = res_tx.send(());
-                    self.send_replicated_to_ws(msg).await;
+                    self.accept_placement(pending.reservation.take()).await;
+                    let _ = res_tx.send(Ok(()));
+                    self.send_replicated_to_ws(&pixel, msg, pending.origin.as_ref())
+                        .await;
+                    pending.resolve(WriteOutcome::Replicated);
                     return Ok(());
                 }
 
+                // Only the primary waits for its writes to get back, other replicas count a
+                // write as accepted once it's forwarded
+                let forwarded = if self.is_primary {
+                    None
+                } else {
+                    pending.reservation.take()
+                };
                 if self.is_primary {
                     // Ensure that the message has been fully replicated
                     // We do this by adding the message to an expected message queue
                     // 5 seconds later we check if the expected message queue no longer contains
                     // that message
-                    self.expected_queue.lock().unwrap().push_back(msg.clone());
+                    self.expected_queue.lock().unwrap().push_back(pending);
                     log::info!("Added message to expected message queue");
 
                     // If you have the displeasure of having to read the following 20 lines, I apologize in advance
                     let msg_clone = msg.clone();
                     let queue_clone = Arc::clone(&self.expected_queue);
-                    let sessions_clone = self.sessions.clone();
+                    let cmd_tx = self.cmd_tx.clone();
                     thread::spawn(move || {
                         // Wait for 5 seconds
-                        thread::sleep(Duration::from_secs(5));
+                        thread::sleep(REPLICATION_TIMEOUT);
 
                         // Check if the first item in the queue has changed
                         let mut queue = queue_clone.lock().unwrap();
                         if let Some(expected) = queue.front() {
-                            if expected.as_str() == msg_clone {
+                            if expected.msg == msg_clone {
                                 log::info!("Expected message was not received after 5 seconds");
-                                queue.pop_front();
-
-                                let ws_msg = format!("unreplicated: {}", msg_clone);
-                                for (id, session) in sessions_clone {
-                                    log::info!("Sending unreplicated to session {}", id);
-                                    let _ = session.send(ws_msg.clone());
+                                if let Some(mut expected) = queue.pop_front() {
+                                    // The manager looks up the current colour to send with it
+                                    // and gives the writer its cooldown back
+                                    let _ = cmd_tx.send(Command::Unreplicated {
+                                        canvas,
+                                        msg: msg_clone,
+                                        origin: expected.origin.take(),
+                                        reservation: expected.reservation.take(),
+                                    });
+                                    expected.resolve(WriteOutcome::Unreplicated);
                                 }
                             }
                         }
                     });
                 }
 
-                self.send_successor(msg.as_bytes()).await?;
-                let _ = res_tx.send(());
+                if let Err(e) = self.send_successor(msg.as_bytes()).await {
+                    self.refund_cooldown(forwarded);
+                    return Err(e);
+                }
+                self.accept_placement(forwarded).await;
+                let _ = res_tx.send(Ok(()));
             }
-            Command::Disconnect { conn } => {
-                self.unregister_session(conn).await;
+            Command::Subscribe {
+                canvas,
+                conn,
+                region,
+                res_tx,
+            } => {
+                let res = self
+                    .subscriptions
+                    .entry(canvas)
+                    .or_default()
+                    .subscribe(conn, region);
+                let _ = res_tx.send(res);
+            }
+            Command::Unsubscribe {
+                canvas,
+                conn,
+                region,
+                res_tx,
+            } => {
+                let removed = self
+                    .subscriptions
+                    .get_mut(&canvas)
+                    .is_some_and(|subscriptions| subscriptions.unsubscribe(conn, &region));
+                let _ = res_tx.send(removed);
+            }
+            Command::Resume {
+                canvas,
+                conn,
+                since,
+                id,
+            } => {
+                self.resume_session(&canvas, conn, since, id).await;
+            }
+            Command::Sessions { res_tx } => {
+                let sessions = self
+                    .sessions
+                    .iter()
+                    .flat_map(|(canvas, sessions)| {
+                        sessions.iter().map(move |(conn, session)| SessionInfo {
+                            canvas: canvas.clone(),
+                            conn: *conn,
+                            depth: session.depth(),
+                            capacity: session.capacity(),
+                            dropped: session.dropped(),
+                            policy: session.policy(),
+                        })
+                    })
+                    .collect();
+                let _ = res_tx.send(sessions);
+            }
+            Command::Unreplicated {
+                canvas,
+                msg,
+                origin,
+                reservation,
+            } => {
+                self.refund_cooldown(reservation);
+                if let Some(origin) = origin {
+                    self.send_unreplicated_to_ws(&canvas, &msg, origin).await;
+                }
+            }
+            Command::Disconnect { canvas, conn } => {
+                self.unregister_session(&canvas, conn).await;
+            }
+            Command::UpdateCanvas { meta, res_tx } => {
+                let checked = match self.canvases.get(&meta.id) {
+                    Some(current) => meta.check().and_then(|_| meta.check_change(&current)),
+                    None => meta.check(),
+                };
+                if let Err(e) = checked {
+                    let _ = res_tx.send(Err(e));
+                    return Ok(());
+                }
+                log::info!("Updating canvas {}", meta.id);
+                self.apply_canvas(meta.clone()).await;
+                if self.connected {
+                    let canvas_msg = CanvasMessage {
+                        from: self.id,
+                        meta,
+                    };
+                    let canvas_str =
+                        format!("/canvas {}", serde_json::to_string(&canvas_msg).unwrap());
+                    self.send_successor(canvas_str.as_bytes()).await?;
+                }
+                let _ = res_tx.send(Ok(()));
+            }
+            Command::Register { user, res_tx } => {
+                // The primary decides who gets a name, so two replicas can't both hand it out
+                if self.connected && !self.is_primary {
+                    let (primary, address) = self.not_primary();
+                    let _ = res_tx.send(Err(AuthError::NotPrimary { primary, address }));
+                    return Ok(());
+                }
+                match self.store.add_user(&user).await {
+                    Ok(true) => {}
+                    Ok(false) => {
+                        let _ = res_tx.send(Err(AuthError::NameTaken { name: user.name }));
+                        return Ok(());
+                    }
+                    Err(e) => {
+                        log::error!("Couldn't store user {}: {}", user.name, e);
+                        let _ = res_tx.send(Err(AuthError::Unavailable));
+                        return Ok(());
+                    }
+                }
+                log::info!("Registered user {}", user.name);
+                if self.connected {
+                    let user_msg = UserMessage {
+                        from: self.id,
+                        user,
+                    };
+                    self.send_successor(serde_json::to_string(&user_msg).unwrap().as_bytes())
+                        .await?;
+                }
+                let _ = res_tx.send(Ok(()));
+            }
+            Command::Moderate { action, res_tx } => {
+                log::info!("Applying moderation action {:?}", action);
+                let stored = self.apply_moderation(&action).await;
+                if self.connected {
+                    let moderation_msg = ModerationMessage {
+                        from: self.id,
+                        action,
+                    };
+                    self.send_successor(serde_json::to_string(&moderation_msg).unwrap().as_bytes())
+                        .await?;
+                }
+                let _ = res_tx.send(stored);
             }
         }
 
-        return Ok(());
+        Ok(())
     }
 
     pub async fn handle_socket(&mut self, predecessor_stream: &TcpStream) -> io::Result<()> {
@@ -760,39 +1626,68 @@ impl ReplicaManager {
             }
             Err(e) => {
                 log::error!("Err try_read {}", e);
-                return Err(e.into());
+                return Err(e);
             }
         }
-        return Ok(());
+        Ok(())
     }
 
+    /// Add the replica that connected on `stream` to the ring, unless its schema version differs
+    /// from ours. Either way it's told our version with `/schema N`. Returns whether it was added
     pub async fn handle_accepted_stream(
         &mut self,
         stream: &TcpStream,
         alone: bool,
-    ) -> io::Result<()> {
+    ) -> io::Result<bool> {
         log::info!("Accepting connection");
         let mut predecessor_buf = vec![0; SMALL_REPLICA_BUFFER_SIZE];
         stream.readable().await?;
         let n = stream.try_read(&mut predecessor_buf)?;
         predecessor_buf.truncate(n);
         let conn_info = match str::from_utf8(&predecessor_buf) {
-            Ok(v) => v,
-            Err(e) => panic!("Invalid UTF-8 sequence: {}", e),
-        }
-        .trim();
+            Ok(v) => v.trim(),
+            Err(e) => {
+                log::error!("Refusing connection, invalid UTF-8 sequence: {}", e);
+                return Ok(false);
+            }
+        };
         log::info!("Received connection from {}", conn_info);
-        let new_conn_info = serde_json::from_str::<ReplicaInfo>(&conn_info).unwrap();
+        let new_conn_info = match serde_json::from_str::<ReplicaInfo>(conn_info) {
+            Ok(new_conn_info) => new_conn_info,
+            Err(e) => {
+                log::error!("Refusing connection, invalid replica info: {}", e);
+                return Ok(false);
+            }
+        };
+
+        let schema = format!("/schema {}", SCHEMA_VERSION);
+        stream.writable().await?;
+        stream.try_write(schema.as_bytes())?;
+        if new_conn_info.schema_version != SCHEMA_VERSION {
+            log::error!(
+                "Refusing replica {} with schema version {}, ours is {}",
+                new_conn_info.id,
+                new_conn_info.schema_version,
+                SCHEMA_VERSION
+            );
+            return Ok(false);
+        }
+        let ip: Ipv4Addr = match new_conn_info.address.parse() {
+            Ok(ip) => ip,
+            Err(_) => {
+                log::error!(
+                    "Refusing replica {} with invalid address {}",
+                    new_conn_info.id,
+                    new_conn_info.address
+                );
+                return Ok(false);
+            }
+        };
+
         self.connections_info.backend.push(new_conn_info.clone());
         self.predecessor_id = new_conn_info.id;
         if alone {
             // If only replica connect ourselves
-            // Parse the IP address
-            let ip: Ipv4Addr = new_conn_info
-                .address
-                .parse()
-                .expect("Failed to parse IP address");
-
             // Create a SocketAddrV4 from the parsed IP address and port number
             let socket_addr_v4 = SocketAddrV4::new(ip, new_conn_info.socket_port);
             log::info!("Connecting to {}", socket_addr_v4);
@@ -800,7 +1695,7 @@ impl ReplicaManager {
             self.successor_id = new_conn_info.id;
             self.connected = true;
             self.send_initial_sync().await?;
-            return Ok(());
+            return Ok(true);
         }
         let new_conn_message: NewConMessage = NewConMessage {
             from: new_conn_info,
@@ -813,15 +1708,72 @@ impl ReplicaManager {
         );
         self.send_successor(new_con_str.as_bytes()).await?;
         self.send_initial_sync().await?;
-        return Ok(());
+        Ok(true)
+    }
+
+    /// Wait for the replica we connected to to accept our schema version
+    async fn check_schema(&mut self) -> io::Result<()> {
+        let stream = match self.successor_stream.as_mut() {
+            Some(stream) => stream,
+            None => return Err(io::Error::other("No successor to check the schema with")),
+        };
+        let mut buf = vec![0; SMALL_REPLICA_BUFFER_SIZE];
+        let n = match tokio::time::timeout(SCHEMA_TIMEOUT, stream.read(&mut buf)).await {
+            Ok(n) => n?,
+            Err(_) => {
+                return Err(io::Error::other(
+                    "Timed out waiting for the ring's schema version",
+                ))
+            }
+        };
+        let version = str::from_utf8(&buf[..n])
+            .ok()
+            .and_then(|reply| reply.trim().strip_prefix("/schema "))
+            .and_then(|version| version.parse::<u32>().ok());
+        match version {
+            Some(SCHEMA_VERSION) => Ok(()),
+            Some(version) => Err(io::Error::other(format!(
+                "Refusing to join the ring, its schema version is {} and ours is {}",
+                version, SCHEMA_VERSION
+            ))),
+            None => Err(io::Error::other(
+                "Refusing to join the ring, it didn't send its schema version",
+            )),
+        }
     }
 
     /// New replica was added. Lets sync all again
     pub async fn send_initial_sync(&mut self) -> io::Result<()> {
         self.sent_sync = true;
-        let db = self.db.get().await.unwrap();
+        let canvases = self.canvases.all();
+        let mut history = Vec::with_capacity(canvases.len());
+        for meta in canvases.iter() {
+            match self.store.catch_up(&meta.id).await {
+                Ok(canvas_history) => history.push(canvas_history),
+                Err(e) => log::error!("Couldn't read history of canvas {}: {}", meta.id, e),
+            }
+        }
         let sync = SyncMessage {
-            pixels: Pixel::all(&**db).await.unwrap(),
+            pixels: match self.store.pixels().await {
+                Ok(pixels) => pixels,
+                Err(e) => {
+                    log::error!("Couldn't read pixels to sync, not sending sync: {}", e);
+                    return Ok(());
+                }
+            },
+            canvases,
+            history,
+            placements: self.cooldowns.placements(),
+            users: match self.store.users().await {
+                Ok(users) => users,
+                Err(e) => {
+                    log::error!("Couldn't read users to sync: {}", e);
+                    Vec::new()
+                }
+            },
+            locks: self.moderation.locks(),
+            bans: self.moderation.bans(),
+            seq: self.replay.last(),
             conn: self.connections_info.clone(),
             leader: self.leader_id,
             predecessor_id: self.id,
@@ -892,7 +1844,7 @@ impl ReplicaManager {
         log::info!("Successor id {}", self.successor_id);
         log::info!("Predecessor id {}", self.predecessor_id);
         log::info!("Leader id {}", self.leader_id);
-        
+
         to_return
     }
 
@@ -922,7 +1874,7 @@ impl ReplicaManager {
                         }
                         None => {
                             log::error!("None command received");
-                            return Err(io::Error::new(io::ErrorKind::Other, "None Command Received"));
+                            return Err(io::Error::other("None Command Received"));
                         }
                     }
                 }
@@ -930,8 +1882,9 @@ impl ReplicaManager {
                 accepted = accept_connection => {
                     match accepted {
                         Ok((stream, _)) => {
-                            self.handle_accepted_stream(&stream, true).await?;
-                            return Ok(stream);
+                            if self.handle_accepted_stream(&stream, true).await? {
+                                return Ok(stream);
+                            }
                         }
                         Err(err) => {
                             log::error!("Accept error {}", err);
@@ -969,7 +1922,7 @@ impl ReplicaManager {
                         }
                         None => {
                             log::error!("None command received");
-                            return Err(io::Error::new(io::ErrorKind::Other, "None Command Received"));
+                            return Err(io::Error::other("None Command Received"));
                         }
                     }
                 }
@@ -977,7 +1930,7 @@ impl ReplicaManager {
                 response = stream_ready => {
                     match response {
                         Ok(_) => {
-                            self.handle_socket(&predecessor_stream).await?;
+                            self.handle_socket(predecessor_stream).await?;
                         }
                         Err(err) => {
                             log::error!("Socket error {}", err);
@@ -988,8 +1941,9 @@ impl ReplicaManager {
                 accepted = accept_connection => {
                     match accepted {
                         Ok((stream, _)) => {
-                            self.handle_accepted_stream(&stream, false).await?;
-                            return Ok(Some(stream));
+                            if self.handle_accepted_stream(&stream, false).await? {
+                                return Ok(Some(stream));
+                            }
                         }
                         Err(err) => {
                             log::error!("Accept error {}", err);
@@ -1001,6 +1955,13 @@ impl ReplicaManager {
     }
 
     pub async fn run(mut self, mut cmd_rx: UnboundedReceiver<Command>) -> io::Result<()> {
+        self.moderation = match Moderation::load(self.store.as_ref()).await {
+            Ok(moderation) => moderation,
+            Err(e) => {
+                log::error!("Couldn't load locks and bans: {}", e);
+                Moderation::default()
+            }
+        };
         let backend = &self.connections_info.backend;
         let addr = ConnectionInfoDict::get_socket_addr(backend, self.id);
         let listener = TcpListener::bind(addr).await?;
@@ -1010,6 +1971,10 @@ impl ReplicaManager {
                 let my_replica_str =
                     ConnectionInfoDict::get_own_info_str(&self.connections_info.backend, self.id);
                 self.send_successor(my_replica_str.as_bytes()).await?;
+                if let Err(e) = self.check_schema().await {
+                    log::error!("{}", e);
+                    return Err(e);
+                }
                 let (stream, _) = listener.accept().await?;
                 stream
             }
@@ -1051,7 +2016,7 @@ impl ReplicaManager {
                     log::info!("Received Ctrl-c error {} ", e);
                     break;
                 }
-                Err(ref e) => {
+                Err(_e) => {
                     match self
                         .handle_predecessor_disconnect(&mut cmd_rx, &listener)
                         .await
@@ -1068,7 +2033,7 @@ impl ReplicaManager {
             }
         }
 
-        return Ok(());
+        Ok(())
     }
 }
 
@@ -1081,37 +2046,185 @@ pub struct ReplicaHandle {
 }
 
 impl ReplicaHandle {
-    /// Register client message sender and obtain connection ID.
-    pub async fn connect(&self, conn_tx: mpsc::UnboundedSender<String>) -> usize {
+    /// Register client message sender for a canvas and obtain connection ID.
+    pub async fn connect(&self, canvas: CanvasId, conn_tx: SessionSender) -> usize {
         log::info!("Replica Handle connect");
         let (res_tx, res_rx) = oneshot::channel();
 
         // unwrap: manager should not have been dropped
         self.cmd_tx
-            .send(Command::Connect { conn_tx, res_tx })
+            .send(Command::Connect {
+                canvas,
+                conn_tx,
+                res_tx,
+            })
             .unwrap();
 
         // unwrap: manager does not drop out response channel
         res_rx.await.unwrap()
     }
 
-    /// Send message to manager
-    pub async fn send_message(&self, msg: impl Into<String>) {
+    /// Only send the session the updates in `region` and the other regions it subscribed to
+    pub async fn subscribe(
+        &self,
+        canvas: CanvasId,
+        conn: ConnId,
+        region: Region,
+    ) -> Result<(), SubscribeError> {
         let (res_tx, res_rx) = oneshot::channel();
 
+        // unwrap: manager should not have been dropped
+        self.cmd_tx
+            .send(Command::Subscribe {
+                canvas,
+                conn,
+                region,
+                res_tx,
+            })
+            .unwrap();
+
+        res_rx.await.unwrap()
+    }
+
+    /// Stop sending the session the updates in `region`. False if it wasn't subscribed to it
+    pub async fn unsubscribe(&self, canvas: CanvasId, conn: ConnId, region: Region) -> bool {
+        let (res_tx, res_rx) = oneshot::channel();
+
+        // unwrap: manager should not have been dropped
+        self.cmd_tx
+            .send(Command::Unsubscribe {
+                canvas,
+                conn,
+                region,
+                res_tx,
+            })
+            .unwrap();
+
+        res_rx.await.unwrap()
+    }
+
+    /// How far behind each WebSocket session is
+    pub async fn sessions(&self) -> Vec<SessionInfo> {
+        let (res_tx, res_rx) = oneshot::channel();
+
+        // unwrap: manager should not have been dropped
+        self.cmd_tx.send(Command::Sessions { res_tx }).unwrap();
+
+        res_rx.await.unwrap()
+    }
+
+    /// Have the session sent the writes it missed after `since`
+    pub fn resume(&self, canvas: CanvasId, conn: ConnId, since: u64, id: Option<RequestId>) {
+        // unwrap: manager should not have been dropped
+        self.cmd_tx
+            .send(Command::Resume {
+                canvas,
+                conn,
+                since,
+                id,
+            })
+            .unwrap();
+    }
+
+    /// Send message to manager. Fails if the pixel in the message is rejected or its author is
+    /// still cooling down
+    pub async fn send_message(
+        &self,
+        canvas: CanvasId,
+        msg: impl Into<String>,
+        author: Option<Author>,
+        origin: Option<Origin>,
+    ) -> Result<(), WriteError> {
+        let (res_tx, res_rx) = oneshot::channel();
+
+        self.cmd_tx
+            .send(Command::Message {
+                canvas,
+                msg: msg.into(),
+                res_tx,
+                outcome_tx: None,
+                author,
+                origin,
+            })
+            .unwrap();
+
+        res_rx.await.unwrap()
+    }
+
+    /// Send a write and wait for it to go around the ring. Fails if the pixel is rejected or
+    /// its author is still cooling down
+    pub async fn write(
+        &self,
+        canvas: CanvasId,
+        msg: impl Into<String>,
+        author: Option<Author>,
+    ) -> Result<WriteOutcome, WriteError> {
+        let (res_tx, res_rx) = oneshot::channel();
+        let (outcome_tx, outcome_rx) = oneshot::channel();
+
+        // unwrap: manager should not have been dropped
         self.cmd_tx
             .send(Command::Message {
+                canvas,
                 msg: msg.into(),
                 res_tx,
+                outcome_tx: Some(outcome_tx),
+                author,
+                origin: None,
             })
             .unwrap();
 
-        res_rx.await.unwrap();
+        // The channels are dropped when the write is lost, e.g. it couldn't be sent to the next
+        // replica or came back out of order
+        match res_rx.await {
+            Ok(res) => res?,
+            Err(_) => return Ok(WriteOutcome::Unreplicated),
+        }
+        match tokio::time::timeout(REPLICATION_TIMEOUT * 2, outcome_rx).await {
+            Ok(Ok(outcome)) => Ok(outcome),
+            _ => Ok(WriteOutcome::Unreplicated),
+        }
+    }
+
+    /// Create a canvas, or change its size or palette, on every replica
+    pub async fn update_canvas(&self, meta: CanvasMeta) -> Result<(), WriteError> {
+        let (res_tx, res_rx) = oneshot::channel();
+
+        self.cmd_tx
+            .send(Command::UpdateCanvas { meta, res_tx })
+            .unwrap();
+
+        res_rx.await.unwrap()
+    }
+
+    /// Create an account on every replica, failing if the name is taken
+    pub async fn register(&self, user: User) -> Result<(), AuthError> {
+        let (res_tx, res_rx) = oneshot::channel();
+
+        self.cmd_tx
+            .send(Command::Register { user, res_tx })
+            .unwrap();
+
+        res_rx.await.unwrap()
+    }
+
+    /// Add or remove a lock or ban on every replica. Fails if this replica couldn't store it,
+    /// it's enforced either way
+    pub async fn moderate(&self, action: moderation::Action) -> Result<(), StoreError> {
+        let (res_tx, res_rx) = oneshot::channel();
+
+        self.cmd_tx
+            .send(Command::Moderate { action, res_tx })
+            .unwrap();
+
+        res_rx.await.unwrap()
     }
 
     /// Unregister message sender
-    pub fn disconnect(&self, conn: usize) {
+    pub fn disconnect(&self, canvas: CanvasId, conn: usize) {
         // unwrap: chat server should not have been dropped
-        self.cmd_tx.send(Command::Disconnect { conn }).unwrap();
+        self.cmd_tx
+            .send(Command::Disconnect { canvas, conn })
+            .unwrap();
     }
 }
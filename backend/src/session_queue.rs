@@ -0,0 +1,362 @@
+//! Bounded queue of the messages the replica manager sends one WebSocket session.
+//!
+//! A session that doesn't keep up can't make the manager hold on to everything sent to it. Once
+//! `WS_QUEUE_SIZE` messages (default 1024) are waiting, `WS_QUEUE_OVERFLOW` decides what
+//! happens to the next one:
+//!
+//! - `disconnect`, the default: the waiting messages are dropped and the session is sent
+//!   `RESYNC_REQUIRED` and closed. Clients can `resume` on a new connection
+//! - `drop_oldest`: the oldest waiting message is dropped
+//! - `coalesce`: a waiting write to the same pixel is replaced, otherwise the oldest waiting
+//!   write is dropped
+
+use crate::pixel::Pixel;
+use crate::Msg;
+use std::collections::VecDeque;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, MutexGuard};
+use tokio::sync::Notify;
+
+const DEFAULT_CAPACITY: usize = 1024;
+
+/// Last message of a session that fell too far behind
+pub const RESYNC_REQUIRED: &str = "resync required";
+
+/// What happens to a message sent to a full queue, see the module docs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OverflowPolicy {
+    DropOldest,
+    Coalesce,
+    Disconnect,
+}
+
+impl FromStr for OverflowPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drop_oldest" => Ok(OverflowPolicy::DropOldest),
+            "coalesce" => Ok(OverflowPolicy::Coalesce),
+            "disconnect" => Ok(OverflowPolicy::Disconnect),
+            other => Err(format!("unknown overflow policy {}", other)),
+        }
+    }
+}
+
+/// `WS_QUEUE_SIZE`
+fn queue_capacity() -> usize {
+    std::env::var("WS_QUEUE_SIZE")
+        .ok()
+        .and_then(|capacity| capacity.parse().ok())
+        .filter(|capacity| *capacity > 0)
+        .unwrap_or(DEFAULT_CAPACITY)
+}
+
+/// `WS_QUEUE_OVERFLOW`
+fn overflow_policy() -> OverflowPolicy {
+    match std::env::var("WS_QUEUE_OVERFLOW") {
+        Ok(policy) => policy.parse().unwrap_or_else(|e| {
+            log::warn!("{}, disconnecting sessions that fall behind", e);
+            OverflowPolicy::Disconnect
+        }),
+        Err(_) => OverflowPolicy::Disconnect,
+    }
+}
+
+/// A waiting message, with the pixel it writes when the queue coalesces writes
+#[derive(Debug)]
+struct Queued {
+    msg: Msg,
+    pixel: Option<(i32, i32)>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    msgs: VecDeque<Queued>,
+    /// Sent `RESYNC_REQUIRED`, nothing more is queued
+    overflowed: bool,
+    /// The sender or the receiver is gone
+    closed: bool,
+    dropped: u64,
+}
+
+#[derive(Debug)]
+struct Shared {
+    state: Mutex<State>,
+    notify: Notify,
+    capacity: usize,
+    policy: OverflowPolicy,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        // A panic while holding the lock can't leave the queue half updated
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn close(&self) {
+        let mut state = self.lock();
+        state.closed = true;
+        drop(state);
+        self.notify.notify_one();
+    }
+}
+
+/// Queue for a new session, configured from the environment
+pub fn channel() -> (SessionSender, SessionReceiver) {
+    channel_with(queue_capacity(), overflow_policy())
+}
+
+fn channel_with(capacity: usize, policy: OverflowPolicy) -> (SessionSender, SessionReceiver) {
+    let shared = Arc::new(Shared {
+        state: Mutex::new(State::default()),
+        notify: Notify::new(),
+        capacity,
+        policy,
+    });
+    (
+        SessionSender {
+            shared: Arc::clone(&shared),
+        },
+        SessionReceiver { shared },
+    )
+}
+
+/// The replica manager's end of a session's queue
+#[derive(Debug)]
+pub struct SessionSender {
+    shared: Arc<Shared>,
+}
+
+impl SessionSender {
+    /// Queue a message for the session. False if it was dropped, because the session is gone
+    /// or fell too far behind
+    pub fn send(&self, msg: Msg) -> bool {
+        let shared = &self.shared;
+        let mut state = shared.lock();
+        if state.closed || state.overflowed {
+            return false;
+        }
+        let pixel = match shared.policy {
+            OverflowPolicy::Coalesce => written_pixel(&msg),
+            _ => None,
+        };
+
+        if state.msgs.len() >= shared.capacity {
+            state.dropped += 1;
+            match shared.policy {
+                OverflowPolicy::Disconnect => {
+                    state.dropped += state.msgs.len() as u64;
+                    state.msgs.clear();
+                    state.msgs.push_back(Queued {
+                        msg: RESYNC_REQUIRED.to_string(),
+                        pixel: None,
+                    });
+                    state.overflowed = true;
+                    drop(state);
+                    shared.notify.notify_one();
+                    return false;
+                }
+                OverflowPolicy::DropOldest => {
+                    state.msgs.pop_front();
+                }
+                OverflowPolicy::Coalesce => {
+                    let same = pixel.and_then(|pixel| {
+                        state
+                            .msgs
+                            .iter()
+                            .position(|queued| queued.pixel == Some(pixel))
+                    });
+                    if let Some(idx) = same {
+                        // Replaced in place so it keeps its place among the other messages
+                        state.msgs[idx] = Queued { msg, pixel };
+                        return true;
+                    }
+                    match state.msgs.iter().position(|queued| queued.pixel.is_some()) {
+                        Some(oldest) => {
+                            state.msgs.remove(oldest);
+                        }
+                        None => {
+                            state.msgs.pop_front();
+                        }
+                    }
+                }
+            }
+        }
+
+        state.msgs.push_back(Queued { msg, pixel });
+        drop(state);
+        shared.notify.notify_one();
+        true
+    }
+
+    /// Messages waiting to be sent to the session
+    pub fn depth(&self) -> usize {
+        self.shared.lock().msgs.len()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.shared.capacity
+    }
+
+    pub fn policy(&self) -> OverflowPolicy {
+        self.shared.policy
+    }
+
+    /// Messages dropped because the session fell behind
+    pub fn dropped(&self) -> u64 {
+        self.shared.lock().dropped
+    }
+}
+
+impl Drop for SessionSender {
+    fn drop(&mut self) {
+        self.shared.close();
+    }
+}
+
+/// The session's end of its queue
+#[derive(Debug)]
+pub struct SessionReceiver {
+    shared: Arc<Shared>,
+}
+
+impl SessionReceiver {
+    /// Next message, None once the replica manager dropped the session and everything queued
+    /// has been received
+    pub async fn recv(&mut self) -> Option<Msg> {
+        loop {
+            {
+                let mut state = self.shared.lock();
+                if let Some(queued) = state.msgs.pop_front() {
+                    return Some(queued.msg);
+                }
+                if state.closed {
+                    return None;
+                }
+            }
+            self.shared.notify.notified().await;
+        }
+    }
+}
+
+impl Drop for SessionReceiver {
+    fn drop(&mut self) {
+        self.shared.lock().msgs.clear();
+        self.shared.close();
+    }
+}
+
+/// Position of the pixel a `replicated: ` message writes
+fn written_pixel(msg: &str) -> Option<(i32, i32)> {
+    let pixel: Pixel = serde_json::from_str(msg.strip_prefix("replicated: ")?).ok()?;
+    Some((pixel.x, pixel.y))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+
+    fn write(x: i32, colour: i32) -> Msg {
+        format!(
+            r#"replicated: {{"x":{},"y":0,"colour":{},"updated":0}}"#,
+            x, colour
+        )
+    }
+
+    /// Everything waiting, in order
+    fn drain(receiver: &mut SessionReceiver) -> Vec<Msg> {
+        let mut msgs = Vec::new();
+        while receiver.shared.lock().msgs.front().is_some() {
+            msgs.extend(block_on(receiver.recv()));
+        }
+        msgs
+    }
+
+    #[test]
+    fn queues_in_order() {
+        let (sender, mut receiver) = channel_with(3, OverflowPolicy::Disconnect);
+        assert!(sender.send("primary".into()));
+        assert!(sender.send(write(1, 1)));
+        assert_eq!(sender.depth(), 2);
+        assert_eq!(
+            drain(&mut receiver),
+            vec!["primary".to_string(), write(1, 1)]
+        );
+        assert_eq!(sender.dropped(), 0);
+    }
+
+    #[test]
+    fn disconnect_on_overflow() {
+        let (sender, mut receiver) = channel_with(2, OverflowPolicy::Disconnect);
+        assert!(sender.send(write(1, 1)));
+        assert!(sender.send(write(2, 1)));
+        assert!(!sender.send(write(3, 1)));
+        // Nothing is queued after the session overflowed
+        assert!(!sender.send(write(4, 1)));
+        assert_eq!(sender.dropped(), 3);
+        assert_eq!(drain(&mut receiver), vec![RESYNC_REQUIRED.to_string()]);
+    }
+
+    #[test]
+    fn drop_oldest_on_overflow() {
+        let (sender, mut receiver) = channel_with(2, OverflowPolicy::DropOldest);
+        assert!(sender.send("primary".into()));
+        assert!(sender.send(write(1, 1)));
+        assert!(sender.send(write(2, 1)));
+        assert!(sender.send(write(3, 1)));
+        assert_eq!(sender.dropped(), 2);
+        assert_eq!(drain(&mut receiver), vec![write(2, 1), write(3, 1)]);
+    }
+
+    #[test]
+    fn coalesce_replaces_a_write_to_the_same_pixel() {
+        let (sender, mut receiver) = channel_with(3, OverflowPolicy::Coalesce);
+        assert!(sender.send(write(1, 1)));
+        assert!(sender.send(write(2, 1)));
+        assert!(sender.send("primary".into()));
+        assert!(sender.send(write(1, 2)));
+        assert_eq!(sender.dropped(), 1);
+        assert_eq!(
+            drain(&mut receiver),
+            vec![write(1, 2), write(2, 1), "primary".to_string()]
+        );
+    }
+
+    #[test]
+    fn coalesce_drops_the_oldest_write() {
+        let (sender, mut receiver) = channel_with(3, OverflowPolicy::Coalesce);
+        assert!(sender.send("primary".into()));
+        assert!(sender.send(write(1, 1)));
+        assert!(sender.send(write(2, 1)));
+        assert!(sender.send(write(3, 1)));
+        // Messages other than writes are kept over them
+        assert_eq!(
+            drain(&mut receiver),
+            vec!["primary".to_string(), write(2, 1), write(3, 1)]
+        );
+
+        // With no write waiting, the oldest message goes
+        assert!(sender.send("a".into()));
+        assert!(sender.send("b".into()));
+        assert!(sender.send("c".into()));
+        assert!(sender.send(write(1, 1)));
+        assert_eq!(drain(&mut receiver), vec!["b", "c", &write(1, 1)]);
+    }
+
+    #[test]
+    fn closed_once_either_end_is_gone() {
+        let (sender, mut receiver) = channel_with(3, OverflowPolicy::Disconnect);
+        assert!(sender.send("primary".into()));
+        drop(sender);
+        // What was queued is still received
+        assert_eq!(block_on(receiver.recv()), Some("primary".to_string()));
+        assert_eq!(block_on(receiver.recv()), None);
+
+        let (sender, receiver) = channel_with(3, OverflowPolicy::Disconnect);
+        drop(receiver);
+        assert!(!sender.send("primary".into()));
+    }
+}
@@ -0,0 +1,359 @@
+//! Periodic checkpoints of every canvas.
+//!
+//! Each replica checkpoints its own store every `SNAPSHOT_INTERVAL` seconds, keeps the newest
+//! `SNAPSHOT_RETAIN` checkpoints of each canvas and drops the history the newest one covers.
+//! History queries start from a checkpoint instead of the first write, and a replica joining
+//! the ring catches up from the newest checkpoint plus the writes made since.
+
+use crate::canvas::CanvasRegistry;
+use crate::pixel::{self, Pixel};
+use crate::store::Store;
+use crate::CanvasId;
+use deadpool_postgres::Manager;
+use std::time::Duration;
+use tokio_postgres::{Error, GenericClient, IsolationLevel, Row};
+
+const DEFAULT_INTERVAL: u64 = 600;
+const DEFAULT_RETAIN: usize = 3;
+
+/// Pixels of a canvas as they were at `taken`. `history_id` is the last write of this
+/// replica's history the checkpoint includes: the highest id stored when the checkpoint was
+/// taken, with writes held off while it's taken so none with a lower id commits later
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Snapshot {
+    pub id: i64,
+    pub canvas_id: CanvasId,
+    pub taken: i32,
+    pub history_id: i64,
+    pub pixels: Vec<Pixel>,
+}
+
+/// A checkpoint without its pixels
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct SnapshotInfo {
+    pub id: i64,
+    pub canvas_id: CanvasId,
+    pub taken: i32,
+    pub history_id: i64,
+    pub size: i64,
+}
+
+impl Snapshot {
+    pub fn info(&self) -> SnapshotInfo {
+        SnapshotInfo {
+            id: self.id,
+            canvas_id: self.canvas_id.clone(),
+            taken: self.taken,
+            history_id: self.history_id,
+            size: self.pixels.len() as i64,
+        }
+    }
+}
+
+impl From<Row> for SnapshotInfo {
+    fn from(row: Row) -> Self {
+        Self {
+            id: row.get(0),
+            canvas_id: row.get(1),
+            taken: row.get(2),
+            history_id: row.get(3),
+            size: row.get(4),
+        }
+    }
+}
+
+impl Snapshot {
+    /// Checkpoint the current pixels of a canvas, seeing the pixels and the history at the
+    /// same point in time. Waits for writes in flight and holds off new ones until it's done,
+    /// otherwise a write that got its history id before `MAX(id)` was read but committed after
+    /// would be left out of the checkpoint yet pruned with the history it covers
+    pub async fn take(
+        client: &mut deadpool::managed::Object<Manager>,
+        canvas_id: &str,
+        taken: i32,
+    ) -> Result<SnapshotInfo, Error> {
+        let tx = client
+            .build_transaction()
+            .isolation_level(IsolationLevel::RepeatableRead)
+            .start()
+            .await?;
+        // Before any query, so the transaction's snapshot is taken once the lock is held
+        tx.batch_execute("LOCK TABLE canvas_history IN SHARE MODE")
+            .await?;
+        let row = tx
+            .query_one(
+                "INSERT INTO canvas_snapshot (canvas_id, taken, history_id)
+            VALUES ($1, $2, GREATEST(
+                (SELECT MAX(id) FROM canvas_history),
+                (SELECT MAX(history_id) FROM canvas_snapshot),
+                0
+            ))
+            RETURNING id, history_id",
+                &[&canvas_id, &taken],
+            )
+            .await?;
+        let id: i64 = row.get(0);
+        let size = tx
+            .execute(
+                "INSERT INTO canvas_snapshot_pixel (snapshot_id, x, y, colour, updated)
+            SELECT $1, c.x, c.y, COALESCE(c.colour, p.colour), c.updated FROM canvas c
+            LEFT JOIN canvas_palette p ON p.canvas_id = c.canvas_id AND p.idx = c.palette_index
+            WHERE c.canvas_id = $2",
+                &[&id, &canvas_id],
+            )
+            .await?;
+        tx.commit().await?;
+
+        Ok(SnapshotInfo {
+            id,
+            canvas_id: canvas_id.to_string(),
+            taken,
+            history_id: row.get(1),
+            size: size as i64,
+        })
+    }
+
+    /// Checkpoints of a canvas, newest first
+    pub async fn all<C: GenericClient>(
+        client: &C,
+        canvas_id: &str,
+    ) -> Result<Vec<SnapshotInfo>, Error> {
+        let stmt = client
+            .prepare(
+                "SELECT s.id, s.canvas_id, s.taken, s.history_id, COUNT(p.snapshot_id)
+            FROM canvas_snapshot s LEFT JOIN canvas_snapshot_pixel p ON p.snapshot_id = s.id
+            WHERE s.canvas_id = $1 GROUP BY s.id ORDER BY s.id DESC",
+            )
+            .await?;
+        let rows = client.query(&stmt, &[&canvas_id]).await?;
+
+        Ok(rows.into_iter().map(SnapshotInfo::from).collect())
+    }
+
+    pub async fn get<C: GenericClient>(
+        client: &C,
+        canvas_id: &str,
+        id: i64,
+    ) -> Result<Option<Snapshot>, Error> {
+        let stmt = client
+            .prepare(
+                "SELECT id, canvas_id, taken, history_id FROM canvas_snapshot
+            WHERE canvas_id = $1 AND id = $2",
+            )
+            .await?;
+        let row = match client.query_opt(&stmt, &[&canvas_id, &id]).await? {
+            Some(row) => row,
+            None => return Ok(None),
+        };
+
+        let stmt = client
+            .prepare(
+                "SELECT p.x, p.y, p.colour, p.updated, s.canvas_id FROM canvas_snapshot_pixel p
+            JOIN canvas_snapshot s ON s.id = p.snapshot_id
+            WHERE p.snapshot_id = $1",
+            )
+            .await?;
+        let pixels = client.query(&stmt, &[&id]).await?;
+
+        Ok(Some(Snapshot {
+            id: row.get(0),
+            canvas_id: row.get(1),
+            taken: row.get(2),
+            history_id: row.get(3),
+            pixels: pixels.into_iter().map(Pixel::from).collect(),
+        }))
+    }
+
+    /// Drop all but the newest `keep` checkpoints of a canvas and the history covered by the
+    /// newest one
+    pub async fn prune(
+        client: &mut deadpool::managed::Object<Manager>,
+        canvas_id: &str,
+        keep: usize,
+    ) -> Result<u64, Error> {
+        let tx = client.transaction().await?;
+        tx.execute(
+            "DELETE FROM canvas_snapshot WHERE canvas_id = $1 AND id NOT IN (
+                SELECT id FROM canvas_snapshot WHERE canvas_id = $1 ORDER BY id DESC LIMIT $2
+            )",
+            &[&canvas_id, &(keep as i64)],
+        )
+        .await?;
+        let pruned = tx
+            .execute(
+                "DELETE FROM canvas_history WHERE canvas_id = $1 AND id <= (
+                SELECT COALESCE(MAX(history_id), 0) FROM canvas_snapshot WHERE canvas_id = $1
+            )",
+                &[&canvas_id],
+            )
+            .await?;
+        tx.commit().await?;
+        Ok(pruned)
+    }
+
+    /// Replace the checkpoints and history of a canvas. The checkpoint covers whatever history
+    /// is already stored, and the writes are appended after it
+    pub async fn restore(
+        client: &mut deadpool::managed::Object<Manager>,
+        history: &CanvasHistory,
+    ) -> Result<(), Error> {
+        let tx = client.transaction().await?;
+        // Writes made meanwhile get ids above the checkpoint, see `take`
+        tx.batch_execute("LOCK TABLE canvas_history IN SHARE MODE")
+            .await?;
+        let canvas_id = &history.canvas_id;
+        tx.execute(
+            "DELETE FROM canvas_snapshot WHERE canvas_id = $1",
+            &[canvas_id],
+        )
+        .await?;
+        tx.execute(
+            "DELETE FROM canvas_history WHERE canvas_id = $1",
+            &[canvas_id],
+        )
+        .await?;
+
+        if let Some(snapshot) = &history.snapshot {
+            let row = tx
+                .query_one(
+                    "INSERT INTO canvas_snapshot (canvas_id, taken, history_id)
+                VALUES ($1, $2, GREATEST(
+                    (SELECT MAX(id) FROM canvas_history),
+                    (SELECT MAX(history_id) FROM canvas_snapshot),
+                    0
+                )) RETURNING id",
+                    &[canvas_id, &snapshot.taken],
+                )
+                .await?;
+            let id: i64 = row.get(0);
+            let (xs, ys, colours, updated) = columns(&snapshot.pixels);
+            tx.execute(
+                "INSERT INTO canvas_snapshot_pixel (snapshot_id, x, y, colour, updated)
+                SELECT $1, * FROM UNNEST($2::integer[], $3::integer[], $4::integer[], $5::integer[])",
+                &[&id, &xs, &ys, &colours, &updated],
+            )
+            .await?;
+        }
+
+        let (xs, ys, colours, updated) = columns(&history.writes);
+        let users: Vec<Option<&str>> = history
+            .writes
+            .iter()
+            .map(|pixel| pixel.user.as_deref())
+            .collect();
+        tx.execute(
+            "INSERT INTO canvas_history (canvas_id, x, y, colour, updated, user_name)
+            SELECT $1, x, y, colour, updated, user_name
+            FROM UNNEST($2::integer[], $3::integer[], $4::integer[], $5::integer[], $6::text[])
+            WITH ORDINALITY AS w (x, y, colour, updated, user_name, n) ORDER BY n",
+            &[canvas_id, &xs, &ys, &colours, &updated, &users],
+        )
+        .await?;
+
+        tx.commit().await
+    }
+}
+
+/// Split pixels into columns to insert them with `UNNEST`
+fn columns(pixels: &[Pixel]) -> (Vec<i32>, Vec<i32>, Vec<i32>, Vec<i32>) {
+    let mut columns = (
+        Vec::with_capacity(pixels.len()),
+        Vec::with_capacity(pixels.len()),
+        Vec::with_capacity(pixels.len()),
+        Vec::with_capacity(pixels.len()),
+    );
+    for pixel in pixels.iter() {
+        columns.0.push(pixel.x);
+        columns.1.push(pixel.y);
+        columns.2.push(pixel.colour);
+        columns.3.push(pixel.updated);
+    }
+    columns
+}
+
+/// Newest checkpoint of a canvas and the writes made since, sent to a replica joining the ring
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct CanvasHistory {
+    pub canvas_id: CanvasId,
+    pub snapshot: Option<Snapshot>,
+    pub writes: Vec<Pixel>,
+}
+
+/// Checkpoint a history query for writes up to `until` starts from: the newest one taken by
+/// then, or the oldest one when they were all taken later. `snapshots` are newest first
+pub fn base_snapshot(snapshots: &[SnapshotInfo], until: Option<i32>) -> Option<&SnapshotInfo> {
+    snapshots
+        .iter()
+        .find(|snapshot| until.is_none_or(|until| snapshot.taken <= until))
+        .or_else(|| snapshots.last())
+}
+
+/// Writes between `since` and `until`, made of the pixels of the checkpoint the query starts
+/// from followed by the writes made after it
+pub fn history_from(
+    base: Option<Snapshot>,
+    writes: Vec<Pixel>,
+    since: Option<i32>,
+    until: Option<i32>,
+) -> Vec<Pixel> {
+    let mut history: Vec<Pixel> = base.map(|base| base.pixels).unwrap_or_default();
+    history.sort_by_key(|pixel| (pixel.updated, pixel.y, pixel.x));
+    history.extend(writes);
+    history.retain(|pixel| pixel.updated_between(since, until));
+    history
+}
+
+fn snapshot_interval() -> u64 {
+    std::env::var("SNAPSHOT_INTERVAL")
+        .ok()
+        .and_then(|interval| interval.parse().ok())
+        .unwrap_or(DEFAULT_INTERVAL)
+}
+
+fn snapshot_retain() -> usize {
+    std::env::var("SNAPSHOT_RETAIN")
+        .ok()
+        .and_then(|retain| retain.parse().ok())
+        .unwrap_or(DEFAULT_RETAIN)
+        .max(1)
+}
+
+/// Checkpoint a canvas and prune what the checkpoint makes redundant
+pub async fn snapshot_canvas(store: &Store, canvas_id: &str) -> Option<SnapshotInfo> {
+    let info = match store.take_snapshot(canvas_id, pixel::now()).await {
+        Ok(info) => info,
+        Err(e) => {
+            log::error!("Couldn't snapshot canvas {}: {}", canvas_id, e);
+            return None;
+        }
+    };
+    match store.prune_history(canvas_id, snapshot_retain()).await {
+        Ok(pruned) => log::info!(
+            "Snapshot {} of canvas {} has {} pixels, pruned {} history entries",
+            info.id,
+            canvas_id,
+            info.size,
+            pruned
+        ),
+        Err(e) => log::error!("Couldn't prune history of canvas {}: {}", canvas_id, e),
+    }
+    Some(info)
+}
+
+/// Checkpoint every canvas every `SNAPSHOT_INTERVAL` seconds, 0 turns it off
+pub async fn run(store: Store, canvases: CanvasRegistry) {
+    let interval = snapshot_interval();
+    if interval == 0 {
+        log::info!("Periodic snapshots are off");
+        return;
+    }
+    let mut ticker = tokio::time::interval(Duration::from_secs(interval));
+    // The first tick completes straight away, there's nothing new to checkpoint on startup
+    ticker.tick().await;
+    loop {
+        ticker.tick().await;
+        for meta in canvases.all() {
+            snapshot_canvas(&store, &meta.id).await;
+        }
+    }
+}
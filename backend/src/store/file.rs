@@ -0,0 +1,378 @@
+use super::{CanvasStore, MemoryStore, StoreError};
+use crate::audit::{AuditEntry, AuditQuery};
+use crate::auth::User;
+use crate::canvas::{CanvasMeta, CanvasRegistry};
+use crate::moderation::{Action, Ban, Lock};
+use crate::pixel::Pixel;
+use crate::snapshot::{CanvasHistory, Snapshot, SnapshotInfo};
+use async_trait::async_trait;
+use std::borrow::Cow;
+use std::path::{Path, PathBuf};
+use tokio::fs::{self, File, OpenOptions};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+
+/// One line of the journal
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum Entry<'a> {
+    Canvas {
+        meta: Cow<'a, CanvasMeta>,
+    },
+    Pixel {
+        pixel: Cow<'a, Pixel>,
+    },
+    History {
+        pixels: Cow<'a, [Pixel]>,
+        #[serde(default)]
+        ids: Cow<'a, [i64]>,
+    },
+    Snapshot {
+        snapshot: Cow<'a, Snapshot>,
+    },
+    Prune {
+        canvas_id: Cow<'a, str>,
+        keep: usize,
+    },
+    Restore {
+        history: Cow<'a, CanvasHistory>,
+    },
+    Replace {
+        pixels: Cow<'a, [Pixel]>,
+    },
+    User {
+        user: Cow<'a, User>,
+    },
+    Moderation {
+        action: Cow<'a, Action>,
+    },
+    Audit {
+        entry: Cow<'a, AuditEntry>,
+    },
+}
+
+/// Canvas kept in memory and journaled to an append-only file of JSON lines, which is
+/// replayed on startup. The journal is compacted to a snapshot on startup, whenever the
+/// pixels are replaced by a sync and whenever history is pruned.
+#[derive(Debug)]
+pub struct FileStore {
+    path: PathBuf,
+    memory: MemoryStore,
+    journal: Mutex<File>,
+}
+
+impl FileStore {
+    pub async fn open(path: &str) -> Result<FileStore, StoreError> {
+        let path = PathBuf::from(path);
+        let memory = MemoryStore::default();
+
+        match fs::read_to_string(&path).await {
+            Ok(contents) => {
+                let lines: Vec<&str> = contents.lines().collect();
+                for (n, line) in lines.iter().enumerate() {
+                    match serde_json::from_str::<Entry>(line) {
+                        Ok(entry) => {
+                            apply(&memory, &entry);
+                        }
+                        // A crash while appending can leave the last line cut short
+                        Err(e) if n + 1 == lines.len() => {
+                            log::warn!("Ignoring incomplete last line of {:?}: {}", path, e);
+                        }
+                        Err(e) => {
+                            return Err(StoreError::Corrupt(format!(
+                                "{:?} line {}: {}",
+                                path,
+                                n + 1,
+                                e
+                            )))
+                        }
+                    }
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+
+        let journal = compact(&path, &memory, &memory.all_pixels()).await?;
+        Ok(FileStore {
+            path,
+            memory,
+            journal: Mutex::new(journal),
+        })
+    }
+
+    async fn append(&self, journal: &mut File, entry: &Entry<'_>) -> Result<(), StoreError> {
+        let mut line = serde_json::to_string(entry)
+            .map_err(|e| StoreError::Corrupt(format!("couldn't encode entry: {}", e)))?;
+        line.push('\n');
+        journal.write_all(line.as_bytes()).await?;
+        journal.sync_data().await?;
+        Ok(())
+    }
+}
+
+fn apply(memory: &MemoryStore, entry: &Entry) -> u64 {
+    match entry {
+        Entry::Canvas { meta } => {
+            memory.apply_canvas(meta);
+            0
+        }
+        Entry::Pixel { pixel } => memory.apply_pixel(pixel),
+        Entry::History { pixels, ids } => {
+            memory.apply_history(pixels, ids);
+            0
+        }
+        Entry::Snapshot { snapshot } => {
+            memory.apply_snapshot(snapshot);
+            0
+        }
+        Entry::Prune { canvas_id, keep } => memory.apply_prune(canvas_id, *keep),
+        Entry::Restore { history } => {
+            memory.apply_restore(history);
+            0
+        }
+        Entry::Replace { pixels } => memory.apply_replace(pixels),
+        Entry::User { user } => memory.apply_user(user) as u64,
+        Entry::Moderation { action } => {
+            memory.apply_moderation(action);
+            0
+        }
+        Entry::Audit { entry } => {
+            memory.apply_audit(entry);
+            0
+        }
+    }
+}
+
+/// Rewrite the journal as a snapshot of the canvases, history and checkpoints in `memory` with `pixels`,
+/// and reopen it for appending. The old journal is only replaced once the snapshot is safely
+/// on disk
+async fn compact(path: &Path, memory: &MemoryStore, pixels: &[Pixel]) -> Result<File, StoreError> {
+    let mut snapshot = String::new();
+    for meta in memory.all_canvases() {
+        let entry = Entry::Canvas {
+            meta: Cow::Owned(meta),
+        };
+        snapshot.push_str(&serde_json::to_string(&entry).unwrap());
+        snapshot.push('\n');
+    }
+    let (ids, pixels_written): (Vec<i64>, Vec<Pixel>) = memory.all_history().into_iter().unzip();
+    let entry = Entry::History {
+        pixels: Cow::Owned(pixels_written),
+        ids: Cow::Owned(ids),
+    };
+    snapshot.push_str(&serde_json::to_string(&entry).unwrap());
+    snapshot.push('\n');
+    for user in memory.all_users() {
+        let entry = Entry::User {
+            user: Cow::Owned(user),
+        };
+        snapshot.push_str(&serde_json::to_string(&entry).unwrap());
+        snapshot.push('\n');
+    }
+    let locks = memory
+        .all_locks()
+        .into_iter()
+        .map(|lock| Action::Lock { lock });
+    let bans = memory.all_bans().into_iter().map(|ban| Action::Ban { ban });
+    for action in locks.chain(bans) {
+        let entry = Entry::Moderation {
+            action: Cow::Owned(action),
+        };
+        snapshot.push_str(&serde_json::to_string(&entry).unwrap());
+        snapshot.push('\n');
+    }
+    for audit in memory.all_audit_log() {
+        let entry = Entry::Audit {
+            entry: Cow::Owned(audit),
+        };
+        snapshot.push_str(&serde_json::to_string(&entry).unwrap());
+        snapshot.push('\n');
+    }
+    for checkpoint in memory.all_snapshots() {
+        let entry = Entry::Snapshot {
+            snapshot: Cow::Owned(checkpoint),
+        };
+        snapshot.push_str(&serde_json::to_string(&entry).unwrap());
+        snapshot.push('\n');
+    }
+    let entry = Entry::Replace {
+        pixels: Cow::Borrowed(pixels),
+    };
+    snapshot.push_str(&serde_json::to_string(&entry).unwrap());
+    snapshot.push('\n');
+
+    let tmp_path = path.with_extension("tmp");
+    let mut tmp = File::create(&tmp_path).await?;
+    tmp.write_all(snapshot.as_bytes()).await?;
+    tmp.sync_all().await?;
+    fs::rename(&tmp_path, path).await?;
+
+    Ok(OpenOptions::new().append(true).open(path).await?)
+}
+
+#[async_trait]
+impl CanvasStore for FileStore {
+    async fn canvases(&self) -> Result<Vec<CanvasMeta>, StoreError> {
+        self.memory.canvases().await
+    }
+
+    async fn set_canvas(&self, meta: &CanvasMeta) -> Result<(), StoreError> {
+        let mut journal = self.journal.lock().await;
+        let entry = Entry::Canvas {
+            meta: Cow::Borrowed(meta),
+        };
+        self.append(&mut journal, &entry).await?;
+        apply(&self.memory, &entry);
+        Ok(())
+    }
+
+    async fn pixels(&self) -> Result<Vec<Pixel>, StoreError> {
+        self.memory.pixels().await
+    }
+
+    async fn canvas_pixels(&self, canvas_id: &str) -> Result<Vec<Pixel>, StoreError> {
+        self.memory.canvas_pixels(canvas_id).await
+    }
+
+    async fn pixel(&self, canvas_id: &str, x: i32, y: i32) -> Result<Option<Pixel>, StoreError> {
+        self.memory.pixel(canvas_id, x, y).await
+    }
+
+    async fn insert_pixel(
+        &self,
+        pixel: &Pixel,
+        _palette_index: Option<i16>,
+    ) -> Result<u64, StoreError> {
+        let mut journal = self.journal.lock().await;
+        let entry = Entry::Pixel {
+            pixel: Cow::Borrowed(pixel),
+        };
+        self.append(&mut journal, &entry).await?;
+        Ok(apply(&self.memory, &entry))
+    }
+
+    async fn history(
+        &self,
+        canvas_id: &str,
+        since: Option<i32>,
+        until: Option<i32>,
+    ) -> Result<Vec<Pixel>, StoreError> {
+        self.memory.history(canvas_id, since, until).await
+    }
+
+    async fn take_snapshot(&self, canvas_id: &str, taken: i32) -> Result<SnapshotInfo, StoreError> {
+        let mut journal = self.journal.lock().await;
+        let snapshot = self.memory.new_snapshot(canvas_id, taken);
+        let info = snapshot.info();
+        let entry = Entry::Snapshot {
+            snapshot: Cow::Owned(snapshot),
+        };
+        self.append(&mut journal, &entry).await?;
+        apply(&self.memory, &entry);
+        Ok(info)
+    }
+
+    async fn snapshots(&self, canvas_id: &str) -> Result<Vec<SnapshotInfo>, StoreError> {
+        self.memory.snapshots(canvas_id).await
+    }
+
+    async fn snapshot(&self, canvas_id: &str, id: i64) -> Result<Option<Snapshot>, StoreError> {
+        self.memory.snapshot(canvas_id, id).await
+    }
+
+    async fn prune_history(&self, canvas_id: &str, keep: usize) -> Result<u64, StoreError> {
+        let mut journal = self.journal.lock().await;
+        let entry = Entry::Prune {
+            canvas_id: Cow::Borrowed(canvas_id),
+            keep,
+        };
+        self.append(&mut journal, &entry).await?;
+        let pruned = apply(&self.memory, &entry);
+        // Pruning is what keeps the journal from growing, so drop the pruned writes from it too
+        *journal = compact(&self.path, &self.memory, &self.memory.all_pixels()).await?;
+        Ok(pruned)
+    }
+
+    async fn catch_up(&self, canvas_id: &str) -> Result<CanvasHistory, StoreError> {
+        self.memory.catch_up(canvas_id).await
+    }
+
+    async fn restore_history(&self, history: &CanvasHistory) -> Result<(), StoreError> {
+        let mut journal = self.journal.lock().await;
+        let entry = Entry::Restore {
+            history: Cow::Borrowed(history),
+        };
+        self.append(&mut journal, &entry).await?;
+        apply(&self.memory, &entry);
+        Ok(())
+    }
+
+    async fn replace_pixels(
+        &self,
+        pixels: &[Pixel],
+        _canvases: &CanvasRegistry,
+    ) -> Result<u64, StoreError> {
+        let mut journal = self.journal.lock().await;
+        // Everything before this point is now irrelevant. The snapshot is written first so
+        // a failed write leaves both the journal and the pixels in memory as they were
+        *journal = compact(&self.path, &self.memory, pixels).await?;
+        Ok(self.memory.apply_replace(pixels))
+    }
+
+    async fn users(&self) -> Result<Vec<User>, StoreError> {
+        self.memory.users().await
+    }
+
+    async fn user(&self, name: &str) -> Result<Option<User>, StoreError> {
+        self.memory.user(name).await
+    }
+
+    async fn add_user(&self, user: &User) -> Result<bool, StoreError> {
+        let mut journal = self.journal.lock().await;
+        if self.memory.user(&user.name).await?.is_some() {
+            return Ok(false);
+        }
+        let entry = Entry::User {
+            user: Cow::Borrowed(user),
+        };
+        self.append(&mut journal, &entry).await?;
+        Ok(apply(&self.memory, &entry) == 1)
+    }
+
+    async fn locks(&self) -> Result<Vec<Lock>, StoreError> {
+        self.memory.locks().await
+    }
+
+    async fn bans(&self) -> Result<Vec<Ban>, StoreError> {
+        self.memory.bans().await
+    }
+
+    async fn apply_moderation(&self, action: &Action) -> Result<(), StoreError> {
+        let mut journal = self.journal.lock().await;
+        let entry = Entry::Moderation {
+            action: Cow::Borrowed(action),
+        };
+        self.append(&mut journal, &entry).await?;
+        apply(&self.memory, &entry);
+        Ok(())
+    }
+
+    async fn record_audit(&self, entry: &AuditEntry) -> Result<(), StoreError> {
+        let mut journal = self.journal.lock().await;
+        let last_id = self.memory.last_audit_id();
+        let entry = Entry::Audit {
+            entry: Cow::Owned(AuditEntry {
+                id: last_id + 1,
+                ..entry.clone()
+            }),
+        };
+        self.append(&mut journal, &entry).await?;
+        apply(&self.memory, &entry);
+        Ok(())
+    }
+
+    async fn audit_log(&self, query: &AuditQuery) -> Result<Vec<AuditEntry>, StoreError> {
+        self.memory.audit_log(query).await
+    }
+}
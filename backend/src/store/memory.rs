@@ -0,0 +1,419 @@
+use super::{CanvasStore, StoreError};
+use crate::audit::{AuditEntry, AuditQuery};
+use crate::auth::User;
+use crate::canvas::{CanvasMeta, CanvasRegistry};
+use crate::moderation::{Action, Ban, Lock, Moderation};
+use crate::pixel::Pixel;
+use crate::snapshot::{self, CanvasHistory, Snapshot, SnapshotInfo};
+use crate::CanvasId;
+use async_trait::async_trait;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::RwLock;
+
+#[derive(Debug, Default)]
+struct MemoryState {
+    canvases: BTreeMap<CanvasId, CanvasMeta>,
+    pixels: HashMap<(CanvasId, i32, i32), Pixel>,
+    /// Writes with the id they were given, oldest first
+    history: Vec<(i64, Pixel)>,
+    last_history_id: i64,
+    /// Oldest first
+    snapshots: Vec<Snapshot>,
+    last_snapshot_id: i64,
+    users: BTreeMap<String, User>,
+    moderation: Moderation,
+    /// Oldest first
+    audit_log: Vec<AuditEntry>,
+}
+
+impl MemoryState {
+    fn push_history(&mut self, id: i64, pixel: Pixel) {
+        self.last_history_id = self.last_history_id.max(id);
+        self.history.push((id, pixel));
+    }
+
+    /// The writes a checkpoint covers may have been pruned, so it also moves the history ids
+    /// past them
+    fn push_snapshot(&mut self, snapshot: Snapshot) {
+        self.last_snapshot_id = self.last_snapshot_id.max(snapshot.id);
+        self.last_history_id = self.last_history_id.max(snapshot.history_id);
+        self.snapshots.push(snapshot);
+    }
+
+    /// Checkpoints of a canvas, newest first
+    fn canvas_snapshots<'a>(&'a self, canvas_id: &'a str) -> impl Iterator<Item = &'a Snapshot> {
+        self.snapshots
+            .iter()
+            .rev()
+            .filter(move |snapshot| snapshot.canvas_id == canvas_id)
+    }
+
+    fn writes_after(&self, canvas_id: &str, after: i64) -> Vec<Pixel> {
+        self.history
+            .iter()
+            .filter(|(id, pixel)| *id > after && pixel.canvas_id == canvas_id)
+            .map(|(_, pixel)| pixel.clone())
+            .collect()
+    }
+}
+
+/// Canvas kept only in memory. Pixels are always stored as colours, `indexed` palettes
+/// make no difference here.
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    state: RwLock<MemoryState>,
+}
+
+impl MemoryStore {
+    pub(super) fn apply_canvas(&self, meta: &CanvasMeta) {
+        let mut state = self.state.write().unwrap();
+        state.canvases.insert(meta.id.clone(), meta.clone());
+    }
+
+    /// Last write wins, the same as the `updated` check in the Postgres upsert
+    pub(super) fn apply_pixel(&self, pixel: &Pixel) -> u64 {
+        let mut state = self.state.write().unwrap();
+        let id = state.last_history_id + 1;
+        state.push_history(id, pixel.clone());
+        let key = (pixel.canvas_id.clone(), pixel.x, pixel.y);
+        match state.pixels.get(&key) {
+            Some(stored) if stored.updated >= pixel.updated => {}
+            _ => {
+                // The user is only kept in the history, as in the Postgres store
+                let current = Pixel {
+                    user: None,
+                    seq: None,
+                    ..pixel.clone()
+                };
+                state.pixels.insert(key, current);
+            }
+        }
+        1
+    }
+
+    /// The new pixels are collected before taking the lock, so readers see either the old
+    /// snapshot or the new one
+    pub(super) fn apply_replace(&self, pixels: &[Pixel]) -> u64 {
+        let replaced: HashMap<_, _> = pixels
+            .iter()
+            .map(|pixel| ((pixel.canvas_id.clone(), pixel.x, pixel.y), pixel.clone()))
+            .collect();
+        self.state.write().unwrap().pixels = replaced;
+        pixels.len() as u64
+    }
+
+    /// Add writes to the history, numbered with `ids` or after the last write if they have none
+    pub(super) fn apply_history(&self, pixels: &[Pixel], ids: &[i64]) {
+        let mut state = self.state.write().unwrap();
+        for (n, pixel) in pixels.iter().enumerate() {
+            let id = match ids.get(n) {
+                Some(id) => *id,
+                None => state.last_history_id + 1,
+            };
+            state.push_history(id, pixel.clone());
+        }
+    }
+
+    /// Checkpoint of the current pixels of a canvas, which isn't stored until it's applied
+    pub(super) fn new_snapshot(&self, canvas_id: &str, taken: i32) -> Snapshot {
+        let state = self.state.read().unwrap();
+        Snapshot {
+            id: state.last_snapshot_id + 1,
+            canvas_id: canvas_id.to_string(),
+            taken,
+            history_id: state.last_history_id,
+            pixels: state
+                .pixels
+                .values()
+                .filter(|pixel| pixel.canvas_id == canvas_id)
+                .cloned()
+                .collect(),
+        }
+    }
+
+    pub(super) fn apply_snapshot(&self, snapshot: &Snapshot) {
+        self.state.write().unwrap().push_snapshot(snapshot.clone());
+    }
+
+    pub(super) fn apply_prune(&self, canvas_id: &str, keep: usize) -> u64 {
+        let mut state = self.state.write().unwrap();
+        let retained: Vec<i64> = state
+            .canvas_snapshots(canvas_id)
+            .take(keep)
+            .map(|snapshot| snapshot.id)
+            .collect();
+        state
+            .snapshots
+            .retain(|snapshot| snapshot.canvas_id != canvas_id || retained.contains(&snapshot.id));
+
+        let covered = match state.canvas_snapshots(canvas_id).next() {
+            Some(newest) => newest.history_id,
+            None => return 0,
+        };
+        let before = state.history.len();
+        state
+            .history
+            .retain(|(id, pixel)| pixel.canvas_id != canvas_id || *id > covered);
+        (before - state.history.len()) as u64
+    }
+
+    /// The checkpoint covers whatever history is already stored, and the writes are numbered
+    /// after it, the same as in the Postgres store
+    pub(super) fn apply_restore(&self, history: &CanvasHistory) {
+        let mut state = self.state.write().unwrap();
+        let canvas_id = &history.canvas_id;
+        state
+            .snapshots
+            .retain(|snapshot| &snapshot.canvas_id != canvas_id);
+        state
+            .history
+            .retain(|(_, pixel)| &pixel.canvas_id != canvas_id);
+
+        if let Some(snapshot) = &history.snapshot {
+            let restored = Snapshot {
+                id: state.last_snapshot_id + 1,
+                history_id: state.last_history_id,
+                ..snapshot.clone()
+            };
+            state.push_snapshot(restored);
+        }
+        for pixel in history.writes.iter() {
+            let id = state.last_history_id + 1;
+            state.push_history(id, pixel.clone());
+        }
+    }
+
+    pub(super) fn apply_user(&self, user: &User) -> bool {
+        let mut state = self.state.write().unwrap();
+        if state.users.contains_key(&user.name) {
+            return false;
+        }
+        state.users.insert(user.name.clone(), user.clone());
+        true
+    }
+
+    pub(super) fn apply_moderation(&self, action: &Action) {
+        self.state.write().unwrap().moderation.apply(action);
+    }
+
+    pub(super) fn all_locks(&self) -> Vec<Lock> {
+        self.state.read().unwrap().moderation.locks()
+    }
+
+    pub(super) fn all_bans(&self) -> Vec<Ban> {
+        self.state.read().unwrap().moderation.bans()
+    }
+
+    /// Numbered after the last entry, unless it's being replayed with the id it was given
+    pub(super) fn apply_audit(&self, entry: &AuditEntry) {
+        let mut state = self.state.write().unwrap();
+        let last_id = state.audit_log.last().map_or(0, |last| last.id);
+        let id = if entry.id > last_id {
+            entry.id
+        } else {
+            last_id + 1
+        };
+        state.audit_log.push(AuditEntry {
+            id,
+            ..entry.clone()
+        });
+    }
+
+    pub(super) fn last_audit_id(&self) -> i64 {
+        let state = self.state.read().unwrap();
+        state.audit_log.last().map_or(0, |last| last.id)
+    }
+
+    pub(super) fn all_audit_log(&self) -> Vec<AuditEntry> {
+        self.state.read().unwrap().audit_log.clone()
+    }
+
+    pub(super) fn all_users(&self) -> Vec<User> {
+        self.state.read().unwrap().users.values().cloned().collect()
+    }
+
+    pub(super) fn all_canvases(&self) -> Vec<CanvasMeta> {
+        self.state
+            .read()
+            .unwrap()
+            .canvases
+            .values()
+            .cloned()
+            .collect()
+    }
+
+    pub(super) fn all_pixels(&self) -> Vec<Pixel> {
+        self.state
+            .read()
+            .unwrap()
+            .pixels
+            .values()
+            .cloned()
+            .collect()
+    }
+
+    pub(super) fn all_history(&self) -> Vec<(i64, Pixel)> {
+        self.state.read().unwrap().history.clone()
+    }
+
+    pub(super) fn all_snapshots(&self) -> Vec<Snapshot> {
+        self.state.read().unwrap().snapshots.clone()
+    }
+}
+
+#[async_trait]
+impl CanvasStore for MemoryStore {
+    async fn canvases(&self) -> Result<Vec<CanvasMeta>, StoreError> {
+        Ok(self.all_canvases())
+    }
+
+    async fn set_canvas(&self, meta: &CanvasMeta) -> Result<(), StoreError> {
+        self.apply_canvas(meta);
+        Ok(())
+    }
+
+    async fn pixels(&self) -> Result<Vec<Pixel>, StoreError> {
+        Ok(self.all_pixels())
+    }
+
+    async fn canvas_pixels(&self, canvas_id: &str) -> Result<Vec<Pixel>, StoreError> {
+        let state = self.state.read().unwrap();
+        Ok(state
+            .pixels
+            .values()
+            .filter(|pixel| pixel.canvas_id == canvas_id)
+            .cloned()
+            .collect())
+    }
+
+    async fn pixel(&self, canvas_id: &str, x: i32, y: i32) -> Result<Option<Pixel>, StoreError> {
+        let state = self.state.read().unwrap();
+        Ok(state.pixels.get(&(canvas_id.to_string(), x, y)).cloned())
+    }
+
+    async fn insert_pixel(
+        &self,
+        pixel: &Pixel,
+        _palette_index: Option<i16>,
+    ) -> Result<u64, StoreError> {
+        Ok(self.apply_pixel(pixel))
+    }
+
+    async fn history(
+        &self,
+        canvas_id: &str,
+        since: Option<i32>,
+        until: Option<i32>,
+    ) -> Result<Vec<Pixel>, StoreError> {
+        let state = self.state.read().unwrap();
+        let snapshots: Vec<SnapshotInfo> = state
+            .canvas_snapshots(canvas_id)
+            .map(Snapshot::info)
+            .collect();
+        let base = snapshot::base_snapshot(&snapshots, until).and_then(|base| {
+            state
+                .canvas_snapshots(canvas_id)
+                .find(|snapshot| snapshot.id == base.id)
+                .cloned()
+        });
+        let after = base.as_ref().map_or(0, |base| base.history_id);
+        let writes = state.writes_after(canvas_id, after);
+        Ok(snapshot::history_from(base, writes, since, until))
+    }
+
+    async fn take_snapshot(&self, canvas_id: &str, taken: i32) -> Result<SnapshotInfo, StoreError> {
+        let snapshot = self.new_snapshot(canvas_id, taken);
+        self.apply_snapshot(&snapshot);
+        Ok(snapshot.info())
+    }
+
+    async fn snapshots(&self, canvas_id: &str) -> Result<Vec<SnapshotInfo>, StoreError> {
+        let state = self.state.read().unwrap();
+        Ok(state
+            .canvas_snapshots(canvas_id)
+            .map(Snapshot::info)
+            .collect())
+    }
+
+    async fn snapshot(&self, canvas_id: &str, id: i64) -> Result<Option<Snapshot>, StoreError> {
+        let state = self.state.read().unwrap();
+        let snapshot = state
+            .canvas_snapshots(canvas_id)
+            .find(|snapshot| snapshot.id == id)
+            .cloned();
+        Ok(snapshot)
+    }
+
+    async fn prune_history(&self, canvas_id: &str, keep: usize) -> Result<u64, StoreError> {
+        Ok(self.apply_prune(canvas_id, keep))
+    }
+
+    async fn catch_up(&self, canvas_id: &str) -> Result<CanvasHistory, StoreError> {
+        let state = self.state.read().unwrap();
+        let snapshot = state.canvas_snapshots(canvas_id).next().cloned();
+        let after = snapshot.as_ref().map_or(0, |snapshot| snapshot.history_id);
+        Ok(CanvasHistory {
+            canvas_id: canvas_id.to_string(),
+            snapshot,
+            writes: state.writes_after(canvas_id, after),
+        })
+    }
+
+    async fn restore_history(&self, history: &CanvasHistory) -> Result<(), StoreError> {
+        self.apply_restore(history);
+        Ok(())
+    }
+
+    async fn replace_pixels(
+        &self,
+        pixels: &[Pixel],
+        _canvases: &CanvasRegistry,
+    ) -> Result<u64, StoreError> {
+        Ok(self.apply_replace(pixels))
+    }
+
+    async fn users(&self) -> Result<Vec<User>, StoreError> {
+        Ok(self.all_users())
+    }
+
+    async fn user(&self, name: &str) -> Result<Option<User>, StoreError> {
+        Ok(self.state.read().unwrap().users.get(name).cloned())
+    }
+
+    async fn add_user(&self, user: &User) -> Result<bool, StoreError> {
+        Ok(self.apply_user(user))
+    }
+
+    async fn locks(&self) -> Result<Vec<Lock>, StoreError> {
+        Ok(self.all_locks())
+    }
+
+    async fn bans(&self) -> Result<Vec<Ban>, StoreError> {
+        Ok(self.all_bans())
+    }
+
+    async fn apply_moderation(&self, action: &Action) -> Result<(), StoreError> {
+        MemoryStore::apply_moderation(self, action);
+        Ok(())
+    }
+
+    async fn record_audit(&self, entry: &AuditEntry) -> Result<(), StoreError> {
+        self.apply_audit(&AuditEntry {
+            id: 0,
+            ..entry.clone()
+        });
+        Ok(())
+    }
+
+    async fn audit_log(&self, query: &AuditQuery) -> Result<Vec<AuditEntry>, StoreError> {
+        let state = self.state.read().unwrap();
+        Ok(state
+            .audit_log
+            .iter()
+            .rev()
+            .filter(|entry| query.matches(entry))
+            .take(query.limit() as usize)
+            .cloned()
+            .collect())
+    }
+}
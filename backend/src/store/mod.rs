@@ -0,0 +1,208 @@
+use crate::audit::{AuditEntry, AuditQuery};
+use crate::auth::User;
+use crate::canvas::{CanvasMeta, CanvasRegistry};
+use crate::migrate::MigrationError;
+use crate::moderation::{Action, Ban, Lock};
+use crate::pixel::Pixel;
+use crate::postgres::ConfigError;
+use crate::snapshot::{CanvasHistory, Snapshot, SnapshotInfo};
+use async_trait::async_trait;
+use std::fmt;
+use std::sync::Arc;
+
+mod file;
+mod memory;
+mod pg;
+
+pub use self::file::FileStore;
+pub use self::memory::MemoryStore;
+pub use self::pg::PgStore;
+
+/// Storage shared by the HTTP handlers and the replica manager.
+pub type Store = Arc<dyn CanvasStore>;
+
+#[derive(Debug)]
+pub enum StoreError {
+    Postgres(tokio_postgres::Error),
+    Pool(deadpool_postgres::PoolError),
+    Io(std::io::Error),
+    /// A stored record couldn't be read back
+    Corrupt(String),
+    Migration(MigrationError),
+    Config(ConfigError),
+}
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StoreError::Postgres(e) => write!(f, "postgres error: {}", e),
+            StoreError::Pool(e) => write!(f, "postgres pool error: {}", e),
+            StoreError::Io(e) => write!(f, "io error: {}", e),
+            StoreError::Corrupt(message) => write!(f, "corrupt store: {}", message),
+            StoreError::Migration(e) => write!(f, "migration error: {}", e),
+            StoreError::Config(e) => write!(f, "postgres config error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for StoreError {}
+
+impl From<tokio_postgres::Error> for StoreError {
+    fn from(e: tokio_postgres::Error) -> Self {
+        StoreError::Postgres(e)
+    }
+}
+
+impl From<deadpool_postgres::PoolError> for StoreError {
+    fn from(e: deadpool_postgres::PoolError) -> Self {
+        StoreError::Pool(e)
+    }
+}
+
+impl From<MigrationError> for StoreError {
+    fn from(e: MigrationError) -> Self {
+        StoreError::Migration(e)
+    }
+}
+
+impl From<ConfigError> for StoreError {
+    fn from(e: ConfigError) -> Self {
+        StoreError::Config(e)
+    }
+}
+
+impl From<std::io::Error> for StoreError {
+    fn from(e: std::io::Error) -> Self {
+        StoreError::Io(e)
+    }
+}
+
+/// Where a replica keeps its canvases and pixels.
+#[async_trait]
+pub trait CanvasStore: fmt::Debug + Send + Sync {
+    /// Every canvas with its palette
+    async fn canvases(&self) -> Result<Vec<CanvasMeta>, StoreError>;
+
+    /// Create or replace a canvas
+    async fn set_canvas(&self, meta: &CanvasMeta) -> Result<(), StoreError>;
+
+    /// Every pixel of every canvas
+    async fn pixels(&self) -> Result<Vec<Pixel>, StoreError>;
+
+    /// Every pixel of a single canvas
+    async fn canvas_pixels(&self, canvas_id: &str) -> Result<Vec<Pixel>, StoreError>;
+
+    /// The pixel at a position of a canvas, `None` if it was never written
+    async fn pixel(&self, canvas_id: &str, x: i32, y: i32) -> Result<Option<Pixel>, StoreError>;
+
+    /// Store a pixel unless a newer write to the same position is already stored, and append
+    /// it to the canvas history either way. `palette_index` is set when the pixel's canvas stores palette indices
+    async fn insert_pixel(
+        &self,
+        pixel: &Pixel,
+        palette_index: Option<i16>,
+    ) -> Result<u64, StoreError>;
+
+    /// Store a batch of pixels as `insert_pixel` would one after the other
+    async fn insert_pixels(
+        &self,
+        pixels: &[Pixel],
+        canvases: &CanvasRegistry,
+    ) -> Result<u64, StoreError> {
+        let mut inserted = 0;
+        for pixel in pixels.iter() {
+            inserted += self
+                .insert_pixel(pixel, canvases.palette_index(pixel))
+                .await?;
+        }
+        Ok(inserted)
+    }
+
+    /// Writes to a canvas, oldest first, with `updated` between `since` and `until` when
+    /// they're given. Writes covered by a checkpoint are only kept as the pixels of the
+    /// checkpoint, see [`crate::snapshot::history_from`]
+    async fn history(
+        &self,
+        canvas_id: &str,
+        since: Option<i32>,
+        until: Option<i32>,
+    ) -> Result<Vec<Pixel>, StoreError>;
+
+    /// Checkpoint the current pixels of a canvas
+    async fn take_snapshot(&self, canvas_id: &str, taken: i32) -> Result<SnapshotInfo, StoreError>;
+
+    /// Checkpoints of a canvas, newest first
+    async fn snapshots(&self, canvas_id: &str) -> Result<Vec<SnapshotInfo>, StoreError>;
+
+    async fn snapshot(&self, canvas_id: &str, id: i64) -> Result<Option<Snapshot>, StoreError>;
+
+    /// Drop all but the newest `keep` checkpoints of a canvas and the history the newest one
+    /// covers. Returns the number of history entries dropped
+    async fn prune_history(&self, canvas_id: &str, keep: usize) -> Result<u64, StoreError>;
+
+    /// Newest checkpoint of a canvas and the writes made since
+    async fn catch_up(&self, canvas_id: &str) -> Result<CanvasHistory, StoreError>;
+
+    /// Replace the checkpoints and history of a canvas with those of another replica
+    async fn restore_history(&self, history: &CanvasHistory) -> Result<(), StoreError>;
+
+    /// Replace every stored pixel, used when syncing from another replica
+    async fn replace_pixels(
+        &self,
+        pixels: &[Pixel],
+        canvases: &CanvasRegistry,
+    ) -> Result<u64, StoreError>;
+
+    /// Every user account, sent to replicas joining the ring
+    async fn users(&self) -> Result<Vec<User>, StoreError>;
+
+    async fn user(&self, name: &str) -> Result<Option<User>, StoreError>;
+
+    /// Store a user unless one with the same name exists. Returns whether it was stored
+    async fn add_user(&self, user: &User) -> Result<bool, StoreError>;
+
+    /// Every region lock, oldest first
+    async fn locks(&self) -> Result<Vec<Lock>, StoreError>;
+
+    async fn bans(&self) -> Result<Vec<Ban>, StoreError>;
+
+    /// Add or remove a lock or ban
+    async fn apply_moderation(&self, action: &Action) -> Result<(), StoreError>;
+
+    /// Append to the audit log, the entry's `id` is assigned by the store
+    async fn record_audit(&self, entry: &AuditEntry) -> Result<(), StoreError>;
+
+    /// Audit log entries matching `query`, newest first
+    async fn audit_log(&self, query: &AuditQuery) -> Result<Vec<AuditEntry>, StoreError>;
+}
+
+fn store_kind() -> String {
+    std::env::var("STORE").unwrap_or_else(|_| "postgres".into())
+}
+
+fn store_file() -> String {
+    std::env::var("STORE_FILE").unwrap_or_else(|_| {
+        format!(
+            "canvas-{}.jsonl",
+            std::env::var("ID").unwrap_or_else(|_| "0".into())
+        )
+    })
+}
+
+/// Open the store selected by `STORE`: `postgres` (default), `memory` or `file`.
+/// The file store keeps its journal at `STORE_FILE`.
+pub async fn create_store() -> Result<Store, StoreError> {
+    Ok(match store_kind().as_str() {
+        "postgres" => Arc::new(PgStore::connect().await?),
+        "memory" => {
+            log::warn!("Using in-memory store, the canvas is lost when this replica stops");
+            Arc::new(MemoryStore::default())
+        }
+        "file" => {
+            let path = store_file();
+            log::info!("Using file store {}", path);
+            Arc::new(FileStore::open(&path).await?)
+        }
+        other => panic!("Unknown STORE {}, expected postgres, memory or file", other),
+    })
+}
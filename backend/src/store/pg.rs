@@ -0,0 +1,201 @@
+use super::{CanvasStore, StoreError};
+use crate::audit::{AuditEntry, AuditQuery};
+use crate::auth::User;
+use crate::canvas::{CanvasMeta, CanvasRegistry};
+use crate::migrate;
+use crate::moderation::{Action, Ban, Lock};
+use crate::pixel::Pixel;
+use crate::postgres;
+use crate::snapshot::{self, CanvasHistory, Snapshot, SnapshotInfo};
+use async_trait::async_trait;
+use deadpool_postgres::Pool;
+
+/// Canvas stored in a Postgres database, configured with `DATABASE_URL` and the `PG_*`
+/// variables, see `postgres`.
+#[derive(Debug, Clone)]
+pub struct PgStore {
+    pool: Pool,
+}
+
+impl PgStore {
+    /// Connect, waiting for the database to come up, and bring the schema up to date, see
+    /// `migrate::migrate_on_startup`
+    pub async fn connect() -> Result<PgStore, StoreError> {
+        let pool = postgres::create_pool()?;
+        postgres::wait_for_database(&pool).await?;
+        migrate::migrate_on_startup(&pool).await?;
+        Ok(PgStore { pool })
+    }
+}
+
+#[async_trait]
+impl CanvasStore for PgStore {
+    async fn canvases(&self) -> Result<Vec<CanvasMeta>, StoreError> {
+        let client = self.pool.get().await?;
+        Ok(CanvasMeta::all(&**client).await?)
+    }
+
+    async fn set_canvas(&self, meta: &CanvasMeta) -> Result<(), StoreError> {
+        let mut client = self.pool.get().await?;
+        let mut tx = client.transaction().await?;
+        let stmt = tx
+            .prepare("SELECT indexed FROM canvas_meta WHERE canvas_id = $1")
+            .await?;
+        let was_indexed = tx
+            .query_opt(&stmt, &[&meta.id])
+            .await?
+            .is_some_and(|row| row.get(0));
+
+        // Indices have to be turned back into colours while the old palette is still stored
+        if was_indexed && !meta.indexed {
+            Pixel::reindex(&tx, &meta.id, false).await?;
+        }
+        CanvasMeta::set(&mut *tx, meta).await?;
+        if meta.indexed && !was_indexed {
+            Pixel::reindex(&tx, &meta.id, true).await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn pixels(&self) -> Result<Vec<Pixel>, StoreError> {
+        let client = self.pool.get().await?;
+        Ok(Pixel::all(&client).await?)
+    }
+
+    async fn canvas_pixels(&self, canvas_id: &str) -> Result<Vec<Pixel>, StoreError> {
+        let client = self.pool.get().await?;
+        Ok(Pixel::all_in(&client, canvas_id).await?)
+    }
+
+    async fn pixel(&self, canvas_id: &str, x: i32, y: i32) -> Result<Option<Pixel>, StoreError> {
+        let client = self.pool.get().await?;
+        Ok(Pixel::get(&client, canvas_id, x, y).await?)
+    }
+
+    async fn insert_pixel(
+        &self,
+        pixel: &Pixel,
+        palette_index: Option<i16>,
+    ) -> Result<u64, StoreError> {
+        let client = self.pool.get().await?;
+        Ok(Pixel::insert_pixel(&client, pixel, palette_index).await?)
+    }
+
+    async fn insert_pixels(
+        &self,
+        pixels: &[Pixel],
+        canvases: &CanvasRegistry,
+    ) -> Result<u64, StoreError> {
+        let client = self.pool.get().await?;
+        Ok(Pixel::insert_pixels(&client, pixels, canvases).await?)
+    }
+
+    async fn history(
+        &self,
+        canvas_id: &str,
+        since: Option<i32>,
+        until: Option<i32>,
+    ) -> Result<Vec<Pixel>, StoreError> {
+        let client = self.pool.get().await?;
+        let snapshots = Snapshot::all(&**client, canvas_id).await?;
+        let base = match snapshot::base_snapshot(&snapshots, until) {
+            Some(base) => Snapshot::get(&**client, canvas_id, base.id).await?,
+            None => None,
+        };
+        let after = base.as_ref().map_or(0, |base| base.history_id);
+        let writes = Pixel::history(&client, canvas_id, after, since, until).await?;
+        Ok(snapshot::history_from(base, writes, since, until))
+    }
+
+    async fn take_snapshot(&self, canvas_id: &str, taken: i32) -> Result<SnapshotInfo, StoreError> {
+        let mut client = self.pool.get().await?;
+        Ok(Snapshot::take(&mut client, canvas_id, taken).await?)
+    }
+
+    async fn snapshots(&self, canvas_id: &str) -> Result<Vec<SnapshotInfo>, StoreError> {
+        let client = self.pool.get().await?;
+        Ok(Snapshot::all(&**client, canvas_id).await?)
+    }
+
+    async fn snapshot(&self, canvas_id: &str, id: i64) -> Result<Option<Snapshot>, StoreError> {
+        let client = self.pool.get().await?;
+        Ok(Snapshot::get(&**client, canvas_id, id).await?)
+    }
+
+    async fn prune_history(&self, canvas_id: &str, keep: usize) -> Result<u64, StoreError> {
+        let mut client = self.pool.get().await?;
+        Ok(Snapshot::prune(&mut client, canvas_id, keep).await?)
+    }
+
+    async fn catch_up(&self, canvas_id: &str) -> Result<CanvasHistory, StoreError> {
+        let client = self.pool.get().await?;
+        let snapshot = match Snapshot::all(&**client, canvas_id).await?.first() {
+            Some(newest) => Snapshot::get(&**client, canvas_id, newest.id).await?,
+            None => None,
+        };
+        let after = snapshot.as_ref().map_or(0, |snapshot| snapshot.history_id);
+        let writes = Pixel::history(&client, canvas_id, after, None, None).await?;
+        Ok(CanvasHistory {
+            canvas_id: canvas_id.to_string(),
+            snapshot,
+            writes,
+        })
+    }
+
+    async fn restore_history(&self, history: &CanvasHistory) -> Result<(), StoreError> {
+        let mut client = self.pool.get().await?;
+        Ok(Snapshot::restore(&mut client, history).await?)
+    }
+
+    async fn replace_pixels(
+        &self,
+        pixels: &[Pixel],
+        canvases: &CanvasRegistry,
+    ) -> Result<u64, StoreError> {
+        let mut client = self.pool.get().await?;
+        Ok(Pixel::update_all_vec(&mut client, pixels, canvases).await?)
+    }
+
+    async fn users(&self) -> Result<Vec<User>, StoreError> {
+        let client = self.pool.get().await?;
+        Ok(User::all(&client).await?)
+    }
+
+    async fn user(&self, name: &str) -> Result<Option<User>, StoreError> {
+        let client = self.pool.get().await?;
+        Ok(User::get(&client, name).await?)
+    }
+
+    async fn add_user(&self, user: &User) -> Result<bool, StoreError> {
+        let client = self.pool.get().await?;
+        Ok(User::add(&client, user).await?)
+    }
+
+    async fn locks(&self) -> Result<Vec<Lock>, StoreError> {
+        let client = self.pool.get().await?;
+        Ok(Lock::all(&client).await?)
+    }
+
+    async fn bans(&self) -> Result<Vec<Ban>, StoreError> {
+        let client = self.pool.get().await?;
+        Ok(Ban::all(&client).await?)
+    }
+
+    async fn apply_moderation(&self, action: &Action) -> Result<(), StoreError> {
+        let client = self.pool.get().await?;
+        action.apply(&client).await?;
+        Ok(())
+    }
+
+    async fn record_audit(&self, entry: &AuditEntry) -> Result<(), StoreError> {
+        let client = self.pool.get().await?;
+        AuditEntry::record(&client, entry).await?;
+        Ok(())
+    }
+
+    async fn audit_log(&self, query: &AuditQuery) -> Result<Vec<AuditEntry>, StoreError> {
+        let client = self.pool.get().await?;
+        Ok(AuditEntry::query(&client, query).await?)
+    }
+}
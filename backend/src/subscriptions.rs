@@ -0,0 +1,319 @@
+//! Regions of a canvas WebSocket sessions asked to be sent the updates of.
+//!
+//! Regions are indexed by the `TILE_SIZE` tiles they overlap, so finding who to send a pixel to
+//! only looks at the sessions subscribed around it. Sessions that never subscribed get every
+//! update.
+
+use crate::canvas::Region;
+use crate::pixel::Pixel;
+use crate::ConnId;
+use std::collections::{HashMap, HashSet};
+
+/// Width and height of a tile, also what `{"tile": [x, y]}` subscribes to
+pub const TILE_SIZE: i32 = 64;
+
+/// Most regions one session can subscribe to
+pub const MAX_SUBSCRIPTIONS: usize = 64;
+
+/// Most tiles one region can overlap. Sessions that want more of the canvas can leave out the
+/// subscriptions and get every update
+pub const MAX_REGION_TILES: i64 = 1024;
+
+/// Region covered by a tile
+pub fn tile(x: i32, y: i32) -> Region {
+    Region {
+        x: x.saturating_mul(TILE_SIZE),
+        y: y.saturating_mul(TILE_SIZE),
+        width: TILE_SIZE,
+        height: TILE_SIZE,
+    }
+}
+
+/// Why a subscription wasn't added
+#[derive(Debug, PartialEq, Eq)]
+pub enum SubscribeError {
+    Empty,
+    TooLarge,
+    TooMany,
+}
+
+impl std::fmt::Display for SubscribeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SubscribeError::Empty => write!(f, "width and height must be positive"),
+            SubscribeError::TooLarge => write!(
+                f,
+                "a region can overlap at most {} tiles of {}x{}",
+                MAX_REGION_TILES, TILE_SIZE, TILE_SIZE
+            ),
+            SubscribeError::TooMany => write!(
+                f,
+                "a connection can subscribe to at most {} regions",
+                MAX_SUBSCRIPTIONS
+            ),
+        }
+    }
+}
+
+/// Subscriptions to one canvas
+#[derive(Debug, Default)]
+pub struct Subscriptions {
+    /// Regions of each session that subscribed to any
+    regions: HashMap<ConnId, Vec<Region>>,
+    /// Sessions with a region overlapping each tile
+    tiles: HashMap<(i32, i32), HashSet<ConnId>>,
+}
+
+impl Subscriptions {
+    /// Send `conn` the updates in `region`
+    pub fn subscribe(&mut self, conn: ConnId, region: Region) -> Result<(), SubscribeError> {
+        if region.width <= 0 || region.height <= 0 {
+            return Err(SubscribeError::Empty);
+        }
+        let overflows = region.x.checked_add(region.width).is_none()
+            || region.y.checked_add(region.height).is_none();
+        if overflows || tile_count(&region) > MAX_REGION_TILES {
+            return Err(SubscribeError::TooLarge);
+        }
+        let regions = self.regions.entry(conn).or_default();
+        if regions.contains(&region) {
+            return Ok(());
+        }
+        if regions.len() >= MAX_SUBSCRIPTIONS {
+            return Err(SubscribeError::TooMany);
+        }
+        regions.push(region);
+        for tile in tiles(&region) {
+            self.tiles.entry(tile).or_default().insert(conn);
+        }
+        Ok(())
+    }
+
+    /// Stop sending `conn` the updates in `region`. Once it has no region left it gets every
+    /// update again. False if it wasn't subscribed to the region
+    pub fn unsubscribe(&mut self, conn: ConnId, region: &Region) -> bool {
+        let Some(regions) = self.regions.get_mut(&conn) else {
+            return false;
+        };
+        let Some(idx) = regions.iter().position(|r| r == region) else {
+            return false;
+        };
+        regions.remove(idx);
+        let regions = regions.clone();
+        if regions.is_empty() {
+            self.regions.remove(&conn);
+        }
+
+        // The session stays on the tiles its other regions overlap
+        for tile in tiles(region) {
+            let still_covered = regions.iter().any(|other| tiles(other).any(|t| t == tile));
+            if still_covered {
+                continue;
+            }
+            if let Some(conns) = self.tiles.get_mut(&tile) {
+                conns.remove(&conn);
+                if conns.is_empty() {
+                    self.tiles.remove(&tile);
+                }
+            }
+        }
+        true
+    }
+
+    /// Forget a session that disconnected
+    pub fn remove(&mut self, conn: ConnId) {
+        let Some(regions) = self.regions.remove(&conn) else {
+            return;
+        };
+        for tile in regions.iter().flat_map(tiles) {
+            if let Some(conns) = self.tiles.get_mut(&tile) {
+                conns.remove(&conn);
+                if conns.is_empty() {
+                    self.tiles.remove(&tile);
+                }
+            }
+        }
+    }
+
+    /// Whether the session asked for specific regions
+    pub fn is_subscribed(&self, conn: ConnId) -> bool {
+        self.regions.contains_key(&conn)
+    }
+
+    /// Whether the session is sent the pixel, for checking a few without the index
+    pub fn wants(&self, conn: ConnId, pixel: &Pixel) -> bool {
+        self.regions
+            .get(&conn)
+            .is_none_or(|regions| regions.iter().any(|r| r.contains(pixel)))
+    }
+
+    /// Sessions subscribed to a region containing the pixel
+    pub fn subscribers<'a>(&'a self, pixel: &'a Pixel) -> impl Iterator<Item = ConnId> + 'a {
+        let tile = (pixel.x.div_euclid(TILE_SIZE), pixel.y.div_euclid(TILE_SIZE));
+        self.tiles
+            .get(&tile)
+            .into_iter()
+            .flatten()
+            .copied()
+            .filter(move |conn| {
+                self.regions
+                    .get(conn)
+                    .is_some_and(|regions| regions.iter().any(|r| r.contains(pixel)))
+            })
+    }
+}
+
+/// First and last tile overlapped by a region
+fn tile_range(region: &Region) -> ((i32, i32), (i32, i32)) {
+    let x1 = region.x + (region.width - 1);
+    let y1 = region.y + (region.height - 1);
+    (
+        (
+            region.x.div_euclid(TILE_SIZE),
+            region.y.div_euclid(TILE_SIZE),
+        ),
+        (x1.div_euclid(TILE_SIZE), y1.div_euclid(TILE_SIZE)),
+    )
+}
+
+fn tile_count(region: &Region) -> i64 {
+    let ((x0, y0), (x1, y1)) = tile_range(region);
+    (x1 as i64 - x0 as i64 + 1) * (y1 as i64 - y0 as i64 + 1)
+}
+
+/// Tiles overlapped by a region
+fn tiles(region: &Region) -> impl Iterator<Item = (i32, i32)> {
+    let ((x0, y0), (x1, y1)) = tile_range(region);
+    (x0..=x1).flat_map(move |x| (y0..=y1).map(move |y| (x, y)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn region(x: i32, y: i32, width: i32, height: i32) -> Region {
+        Region {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    fn subscribers(subs: &Subscriptions, x: i32, y: i32) -> Vec<ConnId> {
+        let pixel = Pixel::test(x, y, 0);
+        let mut conns: Vec<_> = subs.subscribers(&pixel).collect();
+        conns.sort();
+        conns
+    }
+
+    #[test]
+    fn tiles_cover_their_edges() {
+        let mut subs = Subscriptions::default();
+        subs.subscribe(1, tile(1, 0)).unwrap();
+        assert!(subscribers(&subs, TILE_SIZE - 1, 0).is_empty());
+        assert_eq!(subscribers(&subs, TILE_SIZE, 0), vec![1]);
+        assert_eq!(
+            subscribers(&subs, 2 * TILE_SIZE - 1, TILE_SIZE - 1),
+            vec![1]
+        );
+        assert!(subscribers(&subs, 2 * TILE_SIZE, 0).is_empty());
+        assert!(subscribers(&subs, TILE_SIZE, TILE_SIZE).is_empty());
+    }
+
+    #[test]
+    fn regions_across_tiles() {
+        let mut subs = Subscriptions::default();
+        let across = region(TILE_SIZE - 2, TILE_SIZE - 2, 4, 4);
+        subs.subscribe(1, across).unwrap();
+        assert_eq!(tiles(&across).count(), 4);
+        assert_eq!(subscribers(&subs, TILE_SIZE - 2, TILE_SIZE - 2), vec![1]);
+        assert_eq!(subscribers(&subs, TILE_SIZE + 1, TILE_SIZE + 1), vec![1]);
+        // In a tile the region overlaps, outside the region
+        assert!(subscribers(&subs, TILE_SIZE + 2, TILE_SIZE).is_empty());
+        assert!(subscribers(&subs, TILE_SIZE - 3, TILE_SIZE - 2).is_empty());
+    }
+
+    #[test]
+    fn negative_tiles() {
+        let mut subs = Subscriptions::default();
+        subs.subscribe(1, tile(-1, -1)).unwrap();
+        assert_eq!(subscribers(&subs, -1, -1), vec![1]);
+        assert_eq!(subscribers(&subs, -TILE_SIZE, -TILE_SIZE), vec![1]);
+        assert!(subscribers(&subs, 0, 0).is_empty());
+        assert!(subscribers(&subs, -TILE_SIZE - 1, -1).is_empty());
+    }
+
+    #[test]
+    fn unsubscribing_keeps_tiles_of_other_regions() {
+        let mut subs = Subscriptions::default();
+        subs.subscribe(1, region(0, 0, 10, 10)).unwrap();
+        subs.subscribe(1, region(20, 20, 10, 10)).unwrap();
+        subs.subscribe(2, region(0, 0, 10, 10)).unwrap();
+        assert!(subs.unsubscribe(1, &region(0, 0, 10, 10)));
+        assert!(!subs.unsubscribe(1, &region(0, 0, 10, 10)));
+        assert_eq!(subscribers(&subs, 5, 5), vec![2]);
+        assert_eq!(subscribers(&subs, 25, 25), vec![1]);
+
+        // With no region left a session gets every update again
+        assert!(subs.unsubscribe(1, &region(20, 20, 10, 10)));
+        assert!(!subs.is_subscribed(1));
+        assert!(subs.wants(1, &Pixel::test(25, 25, 0)));
+        subs.remove(2);
+        assert!(subs.tiles.is_empty());
+    }
+
+    #[test]
+    fn empty_regions_are_refused() {
+        let mut subs = Subscriptions::default();
+        assert_eq!(
+            subs.subscribe(1, region(0, 0, 0, 10)),
+            Err(SubscribeError::Empty)
+        );
+        assert_eq!(
+            subs.subscribe(1, region(0, 0, 10, -1)),
+            Err(SubscribeError::Empty)
+        );
+        assert!(!subs.is_subscribed(1));
+    }
+
+    #[test]
+    fn region_tile_cap() {
+        let mut subs = Subscriptions::default();
+        let side = 32 * TILE_SIZE;
+        assert_eq!(subs.subscribe(1, region(0, 0, side, side)), Ok(()));
+        // Off the tile grid the same size overlaps another row and column
+        assert_eq!(
+            subs.subscribe(2, region(1, 1, side, side)),
+            Err(SubscribeError::TooLarge)
+        );
+        assert_eq!(
+            subs.subscribe(2, region(0, 0, side + 1, side)),
+            Err(SubscribeError::TooLarge)
+        );
+        assert_eq!(
+            subs.subscribe(2, region(i32::MAX, 0, 2, 1)),
+            Err(SubscribeError::TooLarge)
+        );
+        assert_eq!(
+            subs.subscribe(2, region(0, 0, i32::MAX, i32::MAX)),
+            Err(SubscribeError::TooLarge)
+        );
+        assert!(!subs.is_subscribed(2));
+    }
+
+    #[test]
+    fn subscription_cap() {
+        let mut subs = Subscriptions::default();
+        for i in 0..MAX_SUBSCRIPTIONS as i32 {
+            subs.subscribe(1, region(i, 0, 1, 1)).unwrap();
+        }
+        // Subscribing again to a region already there is fine
+        assert_eq!(subs.subscribe(1, region(0, 0, 1, 1)), Ok(()));
+        assert_eq!(
+            subs.subscribe(1, region(0, 1, 1, 1)),
+            Err(SubscribeError::TooMany)
+        );
+        assert_eq!(subs.subscribe(2, region(0, 1, 1, 1)), Ok(()));
+    }
+}
@@ -11,10 +11,13 @@ import Collapse from "@mui/material/Collapse";
 const WS_URL = `ws://${process.env.REACT_APP_HTTP_HOST}:${process.env.REACT_APP_HTTP_PORT}/ws`;
 const MAX_RETRY_ATTEMPTS = 100; // Maximum number of retry attempts
 const RETRY_INTERVAL = 1000; // Retry interval in milliseconds
+// Used until the backend tells us the real canvas size
+const DEFAULT_CANVAS = { width: 501, height: 501, background: 0xffffff };
 
 function App() {
   //const { sendJsonMessage, lastJsonMessage, readyState } = useWebSocket(WS_URL);
   const [pixels, setPixels] = useState();
+  const [canvasMeta, setCanvasMeta] = useState(DEFAULT_CANVAS);
   const [openSuccess, setOpenSuccess] = useState(false);
   const [openError, setOpenError] = useState(false);
   const [primaryId, setPrimaryId] = useState(null);
@@ -91,10 +94,16 @@ function App() {
       //console.log(lastJsonMessage)
       switch (lastJsonMessage.command) {
         case "get_pixels":
+          const meta = lastJsonMessage.canvas || DEFAULT_CANVAS;
+          setCanvasMeta(meta);
+          let background = meta.background.toString(16);
+          while (background.length < 6) {
+            background = "0" + background;
+          }
           const newPixels = [];
-          for (var i = 0; i < 51; i++) {
-            for (var j = 0; j < 51; j++) {
-              newPixels.push(new Pixel(i * 10, j * 10, "white"));
+          for (var i = 0; i < Math.min(51, meta.width); i++) {
+            for (var j = 0; j < Math.min(51, meta.height); j++) {
+              newPixels.push(new Pixel(i * 10, j * 10, `#${background}`));
             }
           }
           for (let pixel of lastJsonMessage.payload) {
@@ -133,6 +142,34 @@ function App() {
             })
           );
           break;
+        case "error":
+        case "cooldown":
+          // Our write was rejected, e.g. placed before the cooldown was over
+          setOpenError(true);
+          setTimeout(() => {
+            setOpenError(false);
+          }, 2000);
+          break;
+        case "unreplicated":
+          const current = lastJsonMessage.payload.current;
+          setOpenError(true);
+          setTimeout(() => {
+            setOpenError(false);
+          }, 2000);
+          setPixels((pixels) =>
+            pixels.map((pixel) => {
+              if (pixel.x / 10 === current.x && pixel.y / 10 === current.y) {
+                let new_color = current.colour.toString(16);
+                while (new_color.length < 6) {
+                  new_color = "0" + new_color;
+                }
+                return new Pixel(pixel.x, pixel.y, `#${new_color}`);
+              } else {
+                return pixel;
+              }
+            })
+          );
+          break;
         case "primary_id":
           //console.log("app" +lastJsonMessage.payload);
           //setPrimaryId(lastJsonMessage.payload);
@@ -166,8 +203,8 @@ function App() {
         setPixel={setPixel}
         isError={isError}
         pixels={pixels}
-        width={5010}
-        height={5010}
+        width={canvasMeta.width * 10}
+        height={canvasMeta.height * 10}
         primary={primaryId}
       />
       
@@ -79,7 +79,9 @@ async function checkPrimaryServerHealth() {
 
       // TODO: reload page when trying to connect to backup proxy server
 
-      clientServer.on("connection", (clientSocket) => {
+      clientServer.on("connection", (clientSocket, req) => {
+        // Sent with each write so the backend can hold every client to its own cooldown
+        clientSocket.clientAddress = req.socket.remoteAddress;
         console.log("Client connected to proxy 1");
 
         // stop health checks after proxy 1 is now primary
@@ -106,6 +108,7 @@ async function checkPrimaryServerHealth() {
                   JSON.stringify({
                     ...parsedMessage.payload,
                     updated: Math.floor(+new Date() / 1000),
+                    client: clientSocket.clientAddress,
                   })
                 );
                 break;
@@ -77,7 +77,9 @@ async function checkPrimaryServerHealth() {
 
       // TODO: reload page when trying to connect to backup proxy server
 
-      clientServer.on("connection", (clientSocket) => {
+      clientServer.on("connection", (clientSocket, req) => {
+        // Sent with each write so the backend can hold every client to its own cooldown
+        clientSocket.clientAddress = req.socket.remoteAddress;
         console.log("Client connected to proxy 2");
 
         // stop health checks after proxy 2 is now primary
@@ -104,6 +106,7 @@ async function checkPrimaryServerHealth() {
                   JSON.stringify({
                     ...parsedMessage.payload,
                     updated: Math.floor(+new Date() / 1000),
+                    client: clientSocket.clientAddress,
                   })
                 );
                 break;
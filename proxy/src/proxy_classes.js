@@ -12,6 +12,8 @@ class BackendInstance {
     this.backoffTime = 5000;
     this.reconnectAttempts = 0;
     this.clientServer = clientServer;
+    // Number of the newest write we've passed on, to resume from after reconnecting
+    this.lastSeq = null;
 
     this.connect();
   }
@@ -43,6 +45,43 @@ class BackendInstance {
           `BACKEND ${this.id}::Received parsed message from backend:`,
           parsedMessage
         );
+        if (
+          parsedMessage.command === "error" ||
+          parsedMessage.command === "cooldown"
+        ) {
+          this.onRejected(parsedMessage);
+          return;
+        }
+        if (parsedMessage.command === "replicated") {
+          // One of our writes went around the ring, our clients see it like any other
+          this.onSetPixel(parsedMessage.payload);
+          return;
+        }
+        if (parsedMessage.command === "resync_required") {
+          // We fell behind, the backend closes the connection and we resume once reconnected
+          console.error(`BACKEND ${this.id}::Fell behind, reconnecting`);
+          return;
+        }
+        if (parsedMessage.command === "resumed") {
+          console.log(
+            `BACKEND ${this.id}::Resumed, replaying missed writes:`,
+            parsedMessage.payload
+          );
+          return;
+        }
+        if (parsedMessage.command === "get_pixels") {
+          // Too much was missed to replay, clients redraw the whole canvas
+          this.lastSeq = parsedMessage.seq;
+          this.clientServer.clients.forEach((clientSocket) => {
+            clientSocket.send(message.toString());
+          });
+          return;
+        }
+        if (parsedMessage.command === "unreplicated") {
+          // Write didn't make it around the ring, clients repaint the current colour
+          this.onUnreplicated(parsedMessage);
+          return;
+        }
         this.onSetPixel(parsedMessage);
       }
     } catch (error) {
@@ -64,7 +103,28 @@ class BackendInstance {
     });
   }
 
+  // A write was rejected, tell the client that made it
+  onRejected(message) {
+    const { client, ...reply } = message;
+    console.error(
+      `BACKEND ${this.id}::Write from ${client} rejected by backend:`,
+      reply.payload
+    );
+    if (client === undefined) {
+      return;
+    }
+    const json_message = JSON.stringify(reply);
+    this.clientServer.clients.forEach((clientSocket) => {
+      if (clientSocket.clientAddress === client) {
+        clientSocket.send(json_message);
+      }
+    });
+  }
+
   onSetPixel(message) {
+    if (message.seq !== undefined) {
+      this.lastSeq = message.seq;
+    }
     let json_message = JSON.stringify({
       command: "set_pixel",
       payload: message,
@@ -74,12 +134,24 @@ class BackendInstance {
     });
   }
 
+  onUnreplicated(message) {
+    // All clients share our connection, so every one of them is told
+    let json_message = JSON.stringify(message);
+    this.clientServer.clients.forEach((clientSocket) => {
+      clientSocket.send(json_message);
+    });
+  }
+
   connect() {
     this.ws_connection = new WebSocket(`ws://${this.address}:${this.port}/ws`);
 
     this.ws_connection.on("open", () => {
       console.log(`BACKEND ${this.id}::Connected to backend`);
       this.reconnectAttempts = 0;
+      if (this.lastSeq !== null) {
+        // Get the writes made while we were disconnected
+        this.ws_connection.send(`resume since=${this.lastSeq}`);
+      }
     });
 
     this.ws_connection.on("close", () => {